@@ -0,0 +1,116 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+use llm_rs::prepro::{preprocess, PreproOptions};
+use llm_rs::tokenizer::Tokenizer;
+
+/// Replaces the `\n`, `\t` and `\\` escapes of a command line argument.
+///
+/// # Arguments
+///
+/// * `text` - The argument.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Tokenizes local text or JSONL files into train and validation token files, without Python.
+///
+/// Usage: `prepro <output prefix> <file or directory>...`
+/// With `--tokenizer <tokenizer.bin>`, another tokenizer than `gpt2_tokenizer.bin` is used.
+/// With `--delimiter <text>`, text files hold several documents separated by it (`\n` for a
+/// newline, e.g. `--delimiter '\n\n'` for paragraphs).
+/// With `--text-field <name>`, the text of the JSONL lines is read from that field.
+/// With `--val-fraction <f>`, that fraction of the tokens goes to validation (0.1 by default).
+/// With `--shard-size <n>`, the train split is written in shards of n tokens.
+pub fn main() {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut options = PreproOptions::default();
+    let mut tokenizer_path = PathBuf::from("gpt2_tokenizer.bin");
+    while let Some(arg) = args.next() {
+        let mut value = |option: &str| {
+            args.next().unwrap_or_else(|| {
+                eprintln!("Missing value for {}", option);
+                process::exit(1);
+            })
+        };
+        let invalid = |option: &str, value: &str| -> ! {
+            eprintln!("Invalid value '{}' for {}", value, option);
+            process::exit(1);
+        };
+        match arg.as_str() {
+            "--tokenizer" => tokenizer_path = PathBuf::from(value(&arg)),
+            "--delimiter" => options.delimiter = Some(unescape(&value(&arg))),
+            "--text-field" => options.text_field = value(&arg),
+            "--val-fraction" => {
+                let fraction = value(&arg);
+                options.val_fraction = match fraction.parse() {
+                    Ok(fraction) if (0.0..=1.0).contains(&fraction) => fraction,
+                    _ => invalid(&arg, &fraction),
+                };
+            }
+            "--shard-size" => {
+                let size = value(&arg);
+                options.shard_size = match size.parse() {
+                    Ok(size) if size > 0 => Some(size),
+                    _ => invalid(&arg, &size),
+                };
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 {
+        eprintln!("Usage: prepro <output prefix> <file or directory>... [--tokenizer <tokenizer.bin>] [--delimiter <text>] [--text-field <name>] [--val-fraction <f>] [--shard-size <n>]");
+        process::exit(1);
+    }
+    let output_prefix = Path::new(&positional[0]);
+    let inputs: Vec<PathBuf> = positional[1..].iter().map(PathBuf::from).collect();
+
+    let tokenizer = Tokenizer::new(&tokenizer_path).unwrap_or_else(|err| {
+        eprintln!("Error loading the tokenizer from {}: {}", tokenizer_path.display(), err);
+        process::exit(1);
+    });
+    if !tokenizer.can_encode() || tokenizer.eot_token().is_none() {
+        eprintln!("The tokenizer {} cannot encode, it needs merges and an EOT token", tokenizer_path.display());
+        eprintln!("Re-run `python train_gpt2.py` to write it");
+        process::exit(1);
+    }
+    if let Some(parent) = output_prefix.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Err(err) = fs::create_dir_all(parent) {
+            eprintln!("Error creating {}: {}", parent.display(), err);
+            process::exit(1);
+        }
+    }
+
+    let start = Instant::now();
+    let stats = preprocess(&inputs, &tokenizer, output_prefix, &options).unwrap_or_else(|err| {
+        eprintln!("Error preprocessing the documents: {}", err);
+        process::exit(1);
+    });
+    println!("Tokenized {} documents in {:.2} s", stats.num_documents, start.elapsed().as_secs_f64());
+    println!("Saved {} val tokens and {} train tokens to:", stats.val_tokens, stats.train_tokens);
+    for file in &stats.files {
+        println!("{}", file.display());
+    }
+}
@@ -0,0 +1,193 @@
+#![allow(non_snake_case)]
+
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use llm_rs::debug_state::DebugState;
+use llm_rs::gpt2::*;
+use llm_rs::send_ptr::SendPtr;
+
+/// Tolerance on the logits and on the loss.
+const LOGITS_TOLERANCE: f32 = 1e-2;
+
+/// Tolerance on each element of the parameter gradients.
+const GRADS_TOLERANCE: f32 = 2e-2;
+
+/// Number of optimization steps to check the loss of.
+const NUM_STEPS: usize = 10;
+
+/// Losses PyTorch reaches when overfitting the reference batch with Adam at lr=1e-4.
+const EXPECTED_LOSSES: [f32; NUM_STEPS] = [
+    5.270007,
+    4.0597067,
+    3.375123,
+    2.8007827,
+    2.3153822,
+    1.8490286,
+    1.3946564,
+    0.9991465,
+    0.6240804,
+    0.37651098,
+];
+
+/// Compares a computed tensor against its reference and reports the maximum absolute difference.
+///
+/// # Arguments
+///
+/// * `a` - Computed tensor.
+/// * `b` - Reference tensor.
+/// * `n` - Number of elements to compare.
+/// * `label` - Name of the tensor, used in the report.
+/// * `tolerance` - Maximum absolute difference allowed for any element.
+///
+/// # Returns
+///
+/// `true` if every element is within the tolerance.
+unsafe fn check_tensor(a: SendPtr<f32>, b: SendPtr<f32>, n: usize, label: &str, tolerance: f32) -> bool {
+    let print_upto = 5;
+    let mut ok = true;
+    let mut max_diff: f32 = 0.0;
+    println!("{}", label);
+    for i in 0..n {
+        let (a_i, b_i) = (*a.ptr.add(i), *b.ptr.add(i));
+        let diff = (a_i - b_i).abs();
+        // NaNs never compare as within tolerance
+        ok = ok && diff <= tolerance;
+        max_diff = max_diff.max(diff);
+        if i < print_upto {
+            let status = if diff <= tolerance { "OK" } else { "NOT OK" };
+            println!("{} {} {}", status, a_i, b_i);
+        }
+    }
+    if ok {
+        println!("TENSOR OK, max_diff = {:e}", max_diff);
+    } else {
+        println!("TENSOR NOT OK, max_diff = {:e}", max_diff);
+    }
+    ok
+}
+
+pub fn main() {
+    // Build the GPT-2 model from a checkpoint
+    let checkpoint_path = Path::new("gpt2_124M.bin");
+    let mut model = GPT2::new(checkpoint_path).unwrap_or_else(|err| {
+        eprintln!("Error loading model from {}: {}", checkpoint_path.display(), err);
+        process::exit(1);
+    });
+
+    let C = model.config.channels;
+    let V = model.config.vocab_size;
+    let Vp = model.config.padded_vocab_size;
+    let maxT = model.config.max_seq_len;
+    let L = model.config.num_layers;
+
+    // Load the reference batch and expected outputs
+    let state_path = Path::new("gpt2_124M_debug_state.bin");
+    let mut state = DebugState::new(state_path, &model).unwrap_or_else(|err| {
+        eprintln!("Error loading debug state from {}: {}", state_path.display(), err);
+        process::exit(1);
+    });
+    let B = state.B;
+    let T = state.T;
+    let inputs = SendPtr::new(state.inputs.as_mut_ptr());
+    let targets = SendPtr::new(state.targets.as_mut_ptr());
+
+    let mut all_ok = true;
+
+    unsafe {
+        for (step, &expected_loss) in EXPECTED_LOSSES.iter().enumerate() {
+            let start = Instant::now();
+            model.forward(inputs, targets, B, T);
+            model.zero_grad();
+            model.backward();
+            let duration = start.elapsed();
+
+            if step == 0 {
+                // Check the logits, only up to V (the padding is not part of the reference)
+                let mut logits_ok = true;
+                let mut max_diff: f32 = 0.0;
+                for bt in 0..B * T {
+                    for v in 0..V {
+                        let expected = state.expected_logits[bt * V + v];
+                        let calculated = *model.acts.logits.ptr.add(bt * Vp + v);
+                        if bt * V + v < 10 {
+                            println!("{} {}", expected, calculated);
+                        }
+                        let diff = (expected - calculated).abs();
+                        max_diff = max_diff.max(diff);
+                        if diff.is_nan() || diff >= LOGITS_TOLERANCE {
+                            logits_ok = false;
+                        }
+                    }
+                }
+                if logits_ok {
+                    println!("OK (LOGITS), max_diff = {:e}", max_diff);
+                } else {
+                    println!("NOT OK (LOGITS), max_diff = {:e}", max_diff);
+                }
+                all_ok = all_ok && logits_ok;
+
+                // Check the loss
+                let loss_diff = (model.mean_loss - state.expected_loss).abs();
+                if loss_diff < LOGITS_TOLERANCE {
+                    println!("LOSS OK: {} {}", model.mean_loss, state.expected_loss);
+                } else {
+                    println!("LOSS MISMATCH: {} {}", model.mean_loss, state.expected_loss);
+                    all_ok = false;
+                }
+
+                // Check all the parameter gradients, in `param_sizes` order
+                let grads = model.grads;
+                let expected = state.expected_grads;
+                let checks = [
+                    (grads.wte, expected.wte, V * C, "dwte"),
+                    (grads.wpe, expected.wpe, maxT * C, "dwpe"),
+                    (grads.ln1w, expected.ln1w, L * C, "dln1w"),
+                    (grads.ln1b, expected.ln1b, L * C, "dln1b"),
+                    (grads.qkvw, expected.qkvw, L * 3 * C * C, "dqkvw"),
+                    (grads.qkvb, expected.qkvb, L * 3 * C, "dqkvb"),
+                    (grads.attprojw, expected.attprojw, L * C * C, "dattprojw"),
+                    (grads.attprojb, expected.attprojb, L * C, "dattprojb"),
+                    (grads.ln2w, expected.ln2w, L * C, "dln2w"),
+                    (grads.ln2b, expected.ln2b, L * C, "dln2b"),
+                    (grads.fcw, expected.fcw, L * 4 * C * C, "dfcw"),
+                    (grads.fcb, expected.fcb, L * 4 * C, "dfcb"),
+                    (grads.fcprojw, expected.fcprojw, L * C * 4 * C, "dfcprojw"),
+                    (grads.fcprojb, expected.fcprojb, L * C, "dfcprojb"),
+                    (grads.lnfw, expected.lnfw, C, "dlnfw"),
+                    (grads.lnfb, expected.lnfb, C, "dlnfb"),
+                ];
+                for (calculated, expected, n, label) in checks {
+                    all_ok = check_tensor(calculated, expected, n, label, GRADS_TOLERANCE) && all_ok;
+                }
+            }
+
+            // No weight decay, to mirror the `torch.optim.Adam` run of train_gpt2.py
+            if let Err(err) = model.update(1e-4, 0.9, 0.999, 1e-8, 0.0, step + 1) {
+                eprintln!("Error updating the model: {}", err);
+                process::exit(1);
+            }
+
+            // Compare the loss of this step against PyTorch
+            let step_loss_ok = (expected_loss - model.mean_loss).abs() < LOGITS_TOLERANCE;
+            all_ok = all_ok && step_loss_ok;
+            println!(
+                "step {}: loss {:.6} (took {:.2} ms) OK = {}",
+                step,
+                model.mean_loss,
+                duration.as_secs_f64() * 1000.0,
+                step_loss_ok
+            );
+        }
+
+        println!("overall okay: {}", all_ok);
+
+        state.free();
+        model.free();
+    }
+
+    if !all_ok {
+        process::exit(1);
+    }
+}
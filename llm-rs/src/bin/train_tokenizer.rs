@@ -0,0 +1,95 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+use llm_rs::bpe_trainer::BpeTrainer;
+use llm_rs::prepro::collect_files;
+
+/// Trains a byte-level BPE tokenizer on local text files and writes it for `Tokenizer::new`.
+///
+/// Usage: `train_tokenizer <vocab_size> <tokenizer.bin> <file or directory>...`
+/// With `--min-frequency <n>`, pairs occurring fewer than n times are never merged.
+/// With `--save-hf <directory>`, the tokenizer is also written as `vocab.json` and `merges.txt`.
+pub fn main() {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut min_frequency = None;
+    let mut hf_dir = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--min-frequency" => min_frequency = args.next(),
+            "--save-hf" => hf_dir = args.next().map(PathBuf::from),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 3 {
+        eprintln!("Usage: train_tokenizer <vocab_size> <tokenizer.bin> <file or directory>... [--min-frequency <n>] [--save-hf <directory>]");
+        process::exit(1);
+    }
+    let vocab_size: usize = positional[0].parse().unwrap_or_else(|_| {
+        eprintln!("Invalid vocabulary size '{}'", positional[0]);
+        process::exit(1);
+    });
+    if vocab_size <= 256 {
+        eprintln!("The vocabulary needs more than the 256 byte tokens, got {}", vocab_size);
+        process::exit(1);
+    }
+    let output_path = Path::new(&positional[1]);
+
+    let mut files = Vec::new();
+    for input in &positional[2..] {
+        collect_files(Path::new(input), &mut files).unwrap_or_else(|err| {
+            eprintln!("Error listing {}: {}", input, err);
+            process::exit(1);
+        });
+    }
+
+    let mut trainer = BpeTrainer::new(vocab_size);
+    if let Some(min_frequency) = min_frequency {
+        trainer.min_frequency = min_frequency.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value '{}' for --min-frequency", min_frequency);
+            process::exit(1);
+        });
+    }
+    for file in &files {
+        trainer.feed_file(file).unwrap_or_else(|err| {
+            eprintln!("Error reading {}: {}", file.display(), err);
+            process::exit(1);
+        });
+    }
+    println!("read {} files", files.len());
+
+    let start = Instant::now();
+    let tokenizer = trainer.train().unwrap_or_else(|err| {
+        eprintln!("Error training the tokenizer: {}", err);
+        process::exit(1);
+    });
+    println!(
+        "trained {} tokens in {:.2} s",
+        tokenizer.vocab_size(),
+        start.elapsed().as_secs_f64()
+    );
+    if (tokenizer.vocab_size() as usize) < vocab_size {
+        println!("the corpus has no more pairs to merge, the vocabulary is smaller than requested");
+    }
+
+    if let Err(err) = tokenizer.save(output_path) {
+        eprintln!("Error saving the tokenizer to {}: {}", output_path.display(), err);
+        process::exit(1);
+    }
+    println!("wrote {}", output_path.display());
+    if let Some(hf_dir) = hf_dir {
+        let saved = fs::create_dir_all(&hf_dir)
+            .map_err(Into::into)
+            .and_then(|()| {
+                tokenizer.save_vocab_merges(&hf_dir.join("vocab.json"), &hf_dir.join("merges.txt"))
+            });
+        if let Err(err) = saved {
+            eprintln!("Error saving the tokenizer to {}: {}", hf_dir.display(), err);
+            process::exit(1);
+        }
+        println!("wrote {}", hf_dir.display());
+    }
+}
@@ -0,0 +1,353 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::tokenizer::{pre_tokenize, Tokenizer};
+
+/// Text of the special token separating documents, the last token of a trained vocabulary.
+const EOT_TEXT: &str = "<|endoftext|>";
+
+/// Learns byte-level BPE merges from a corpus, like the ones of GPT-2.
+///
+/// The texts are split with the GPT-2 pre-tokenization pattern and only the number of
+/// occurrences of each piece is kept, so a large corpus can be fed in several calls. Tokens 0 to
+/// 255 are the bytes, each merge adds the next token and `<|endoftext|>` comes last.
+pub struct BpeTrainer {
+    /// Number of tokens of the trained vocabulary, including the 256 bytes and EOT.
+    pub vocab_size: usize,
+
+    /// Pairs occurring fewer times are never merged, so the vocabulary can end up smaller.
+    pub min_frequency: u64,
+
+    /// Number of occurrences of each piece of the texts fed so far.
+    piece_counts: HashMap<Vec<u8>, u64>,
+}
+
+impl BpeTrainer {
+    /// Creates a trainer with no text.
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab_size` - Number of tokens of the trained vocabulary, at least 257.
+    ///
+    /// # Returns
+    ///
+    /// A new `BpeTrainer` merging the pairs occurring at least twice.
+    pub fn new(vocab_size: usize) -> Self {
+        assert!(
+            vocab_size > 256,
+            "the vocabulary needs more than the 256 byte tokens, got {}",
+            vocab_size
+        );
+        BpeTrainer {
+            vocab_size,
+            min_frequency: 2,
+            piece_counts: HashMap::new(),
+        }
+    }
+
+    /// Adds a text to the corpus. Occurrences of `<|endoftext|>` separate documents and are not
+    /// learned from.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to learn from.
+    pub fn feed(&mut self, text: &str) {
+        for document in text.split(EOT_TEXT) {
+            for piece in pre_tokenize(document) {
+                *self.piece_counts.entry(piece.as_bytes().to_vec()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Adds the content of a file to the corpus.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the text file. Bytes that are not valid UTF-8 are read as U+FFFD.
+    ///
+    /// # Returns
+    ///
+    /// An error if the file cannot be read.
+    pub fn feed_file(&mut self, path: &Path) -> Result<()> {
+        let data = fs::read(path)?;
+        self.feed(&String::from_utf8_lossy(&data));
+        Ok(())
+    }
+
+    /// Learns the merges from the texts fed so far.
+    ///
+    /// # Returns
+    ///
+    /// The trained tokenizer, with `vocab_size` tokens unless the corpus runs out of pairs
+    /// occurring `min_frequency` times first.
+    ///
+    /// # Note
+    ///
+    /// The most frequent pair is merged first, the one with the lowest tokens on ties, so
+    /// training is deterministic. Pairs producing more than 255 bytes are skipped since the
+    /// tokenizer file cannot store them.
+    pub fn train(&self) -> Result<Tokenizer> {
+        let mut token_table: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        let mut token_ids: HashMap<Vec<u8>, u32> = token_table
+            .iter()
+            .enumerate()
+            .map(|(id, bytes)| (bytes.clone(), id as u32))
+            .collect();
+        let mut merges = Vec::new();
+
+        let mut words: Vec<Vec<u32>> = Vec::with_capacity(self.piece_counts.len());
+        let mut counts: Vec<u64> = Vec::with_capacity(self.piece_counts.len());
+        for (piece, &count) in &self.piece_counts {
+            words.push(piece.iter().map(|&byte| byte as u32).collect());
+            counts.push(count);
+        }
+
+        // Occurrences of each pair, and the words it may be found in
+        let mut pair_counts: HashMap<(u32, u32), u64> = HashMap::new();
+        let mut pair_words: HashMap<(u32, u32), HashSet<usize>> = HashMap::new();
+        for (w, word) in words.iter().enumerate() {
+            for pair in word.windows(2) {
+                let pair = (pair[0], pair[1]);
+                *pair_counts.entry(pair).or_insert(0) += counts[w];
+                pair_words.entry(pair).or_default().insert(w);
+            }
+        }
+        // Most frequent pair first, lowest tokens on ties. Entries are outdated when their count
+        // differs from `pair_counts`, a pair is pushed again every time its count changes.
+        let mut heap: BinaryHeap<(u64, Reverse<(u32, u32)>)> = pair_counts
+            .iter()
+            .map(|(&pair, &count)| (count, Reverse(pair)))
+            .collect();
+        // Pairs that would make a token too long, they are not counted anymore
+        let mut skipped: HashSet<(u32, u32)> = HashSet::new();
+
+        while token_table.len() + 1 < self.vocab_size {
+            let Some((count, Reverse(pair))) = heap.pop() else {
+                break;
+            };
+            if pair_counts.get(&pair) != Some(&count) {
+                continue;
+            }
+            if count < self.min_frequency.max(1) {
+                break;
+            }
+            let bytes = [
+                token_table[pair.0 as usize].as_slice(),
+                token_table[pair.1 as usize].as_slice(),
+            ]
+            .concat();
+            if bytes.len() > 255 {
+                pair_counts.remove(&pair);
+                pair_words.remove(&pair);
+                skipped.insert(pair);
+                continue;
+            }
+            // Different pairs can make the same bytes, they all produce the same token
+            let merged = match token_ids.get(&bytes) {
+                Some(&id) => id,
+                None => {
+                    let id = token_table.len() as u32;
+                    token_ids.insert(bytes.clone(), id);
+                    token_table.push(bytes);
+                    merges.push(pair);
+                    id
+                }
+            };
+
+            let mut changed = HashSet::new();
+            for w in pair_words.remove(&pair).unwrap_or_default() {
+                let word = &mut words[w];
+                let count = counts[w];
+                for old in word.windows(2) {
+                    let old = (old[0], old[1]);
+                    if let Some(old_count) = pair_counts.get_mut(&old) {
+                        *old_count -= count;
+                        changed.insert(old);
+                    }
+                }
+                let mut merged_word = Vec::with_capacity(word.len());
+                let mut i = 0;
+                while i < word.len() {
+                    if i + 1 < word.len() && (word[i], word[i + 1]) == pair {
+                        merged_word.push(merged);
+                        i += 2;
+                    } else {
+                        merged_word.push(word[i]);
+                        i += 1;
+                    }
+                }
+                *word = merged_word;
+                for new in word.windows(2) {
+                    let new = (new[0], new[1]);
+                    if skipped.contains(&new) {
+                        continue;
+                    }
+                    *pair_counts.entry(new).or_insert(0) += count;
+                    pair_words.entry(new).or_default().insert(w);
+                    changed.insert(new);
+                }
+            }
+            pair_counts.remove(&pair);
+            for changed_pair in changed {
+                match pair_counts.get(&changed_pair) {
+                    Some(&0) => {
+                        pair_counts.remove(&changed_pair);
+                    }
+                    Some(&count) => heap.push((count, Reverse(changed_pair))),
+                    None => {}
+                }
+            }
+        }
+
+        let eot_token = token_table.len() as u32;
+        token_table.push(EOT_TEXT.as_bytes().to_vec());
+        Tokenizer::from_tokens(token_table, merges, Some(eot_token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A corpus with frequent words, runs of the same byte and pieces too long to be a token.
+    fn corpus() -> Vec<String> {
+        vec![
+            "the cat sat on the mat, the cat ate the rat. ".repeat(3),
+            "aaaaaaa aaa aa a aaaab    indented\n\n\n".repeat(2),
+            "café déjà vu, ééé!<|endoftext|>the end".to_string(),
+            "b".repeat(300),
+            "b".repeat(300),
+        ]
+    }
+
+    /// Trains like `BpeTrainer::train`, recounting every pair of every word before each merge.
+    fn train_naively(texts: &[String], vocab_size: usize, min_frequency: u64) -> Tokenizer {
+        let mut piece_counts: HashMap<Vec<u8>, u64> = HashMap::new();
+        for text in texts {
+            for document in text.split(EOT_TEXT) {
+                for piece in pre_tokenize(document) {
+                    *piece_counts.entry(piece.as_bytes().to_vec()).or_insert(0) += 1;
+                }
+            }
+        }
+        let mut words: Vec<(Vec<u32>, u64)> = piece_counts
+            .into_iter()
+            .map(|(piece, count)| (piece.iter().map(|&byte| byte as u32).collect(), count))
+            .collect();
+        let mut token_table: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        let mut merges = Vec::new();
+
+        while token_table.len() + 1 < vocab_size {
+            let mut pair_counts: HashMap<(u32, u32), u64> = HashMap::new();
+            for (word, count) in &words {
+                for pair in word.windows(2) {
+                    let length = token_table[pair[0] as usize].len() + token_table[pair[1] as usize].len();
+                    if length <= 255 {
+                        *pair_counts.entry((pair[0], pair[1])).or_insert(0) += count;
+                    }
+                }
+            }
+            let Some((pair, count)) = pair_counts.into_iter().max_by_key(|&(pair, count)| (count, Reverse(pair)))
+            else {
+                break;
+            };
+            if count < min_frequency.max(1) {
+                break;
+            }
+            let bytes = [token_table[pair.0 as usize].as_slice(), &token_table[pair.1 as usize]].concat();
+            let merged = match token_table.iter().position(|token| *token == bytes) {
+                Some(id) => id as u32,
+                None => {
+                    token_table.push(bytes);
+                    merges.push(pair);
+                    token_table.len() as u32 - 1
+                }
+            };
+            for (word, _) in &mut words {
+                let mut merged_word = Vec::with_capacity(word.len());
+                let mut i = 0;
+                while i < word.len() {
+                    if i + 1 < word.len() && (word[i], word[i + 1]) == pair {
+                        merged_word.push(merged);
+                        i += 2;
+                    } else {
+                        merged_word.push(word[i]);
+                        i += 1;
+                    }
+                }
+                *word = merged_word;
+            }
+        }
+
+        let eot_token = token_table.len() as u32;
+        token_table.push(EOT_TEXT.as_bytes().to_vec());
+        Tokenizer::from_tokens(token_table, merges, Some(eot_token)).unwrap()
+    }
+
+    fn trainer(texts: &[String], vocab_size: usize, min_frequency: u64) -> BpeTrainer {
+        let mut trainer = BpeTrainer::new(vocab_size);
+        trainer.min_frequency = min_frequency;
+        texts.iter().for_each(|text| trainer.feed(text));
+        trainer
+    }
+
+    /// Checks that two tokenizers have the same tokens and encode the texts the same way.
+    fn assert_same_tokenizer(tokenizer: &Tokenizer, expected: &Tokenizer, texts: &[String]) {
+        assert_eq!(tokenizer.vocab_size(), expected.vocab_size());
+        assert_eq!(tokenizer.eot_token(), expected.eot_token());
+        for token in 0..expected.vocab_size() {
+            assert_eq!(tokenizer.decode_bytes(token), expected.decode_bytes(token), "token {}", token);
+        }
+        for text in texts {
+            assert_eq!(tokenizer.encode(text), expected.encode(text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn train_matches_a_naive_trainer() {
+        let texts = corpus();
+        for (vocab_size, min_frequency) in [(257, 2), (280, 2), (400, 2), (1000, 2), (1000, 1), (1000, 5)] {
+            let tokenizer = trainer(&texts, vocab_size, min_frequency).train().unwrap();
+            let expected = train_naively(&texts, vocab_size, min_frequency);
+            assert_same_tokenizer(&tokenizer, &expected, &texts);
+            assert!(tokenizer.vocab_size() as usize <= vocab_size);
+        }
+    }
+
+    #[test]
+    fn runs_of_the_same_byte_are_counted_once_per_merge() {
+        // `aaaa` holds the pair `aa` 3 times, but once merged it only holds `aa aa` once
+        let texts = [vec!["aaaa".to_string(); 3], vec!["bc".to_string(); 7]].concat();
+        let tokenizer = trainer(&texts, 260, 2).train().unwrap();
+        assert_eq!(tokenizer.decode_bytes(256), b"aa");
+        assert_eq!(tokenizer.decode_bytes(257), b"bc");
+        assert_eq!(tokenizer.decode_bytes(258), b"aaaa");
+        assert_eq!(tokenizer.eot_token(), Some(259));
+        assert_eq!(tokenizer.encode("aaaaaaa"), [258, 256, b'a' as u32]);
+        assert_same_tokenizer(&tokenizer, &train_naively(&texts, 260, 2), &texts);
+
+        // Tokens longer than 255 bytes are never made
+        let texts = vec!["b".repeat(1000); 2];
+        let tokenizer = trainer(&texts, 1000, 2).train().unwrap();
+        assert!((0..tokenizer.vocab_size()).all(|token| tokenizer.decode_bytes(token).len() <= 255));
+        assert_same_tokenizer(&tokenizer, &train_naively(&texts, 1000, 2), &texts);
+    }
+
+    #[test]
+    fn saved_tokenizer_loads_back() {
+        let texts = corpus();
+        let tokenizer = trainer(&texts, 400, 2).train().unwrap();
+        let dir = std::env::temp_dir().join(format!("llm-rs-bpe-trainer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tokenizer.bin");
+
+        tokenizer.save(&path).unwrap();
+        let loaded = Tokenizer::new(&path).unwrap();
+        assert!(loaded.can_encode());
+        assert_same_tokenizer(&loaded, &tokenizer, &texts);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
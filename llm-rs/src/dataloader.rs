@@ -1,12 +1,241 @@
 use std::alloc::{alloc, Layout};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::mem;
+use std::panic;
 use std::ptr::null_mut;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
+use crate::error::{Error, Result};
+use crate::random;
 use crate::send_ptr::SendPtr;
 
+/// Magic number of the token shards with a header.
+const SHARD_MAGIC: i32 = 20240520;
+
+/// Version of the token shards with a header and uint16 tokens.
+const SHARD_VERSION: i32 = 1;
+
+/// Size of the header of the token shards: 256 int32.
+const SHARD_HEADER_BYTES: u64 = 256 * 4;
+
+/// A token file of a dataset.
+struct Shard {
+    /// Path to the file
+    path: PathBuf,
+
+    /// Size of the header before the tokens, 0 for the legacy headerless files
+    header_size: u64,
+
+    /// Size of a token in the file: 2 for uint16 shards, 4 for the legacy int32 files
+    token_size: usize,
+
+    /// Number of tokens in the file
+    num_tokens: u64,
+
+    /// Number of batches of B*T+1 tokens in the file, starting every B*T tokens
+    num_samples: usize,
+}
+
+/// Position of a `DataLoader` in its dataset: epoch, position of the shard in the order of the
+/// epoch and position of the next batch in the order of the shard.
+type Position = (usize, usize, usize);
+
+/// Reads the batches of the shards in the order of each epoch.
+struct Cursor {
+    /// Batch size
+    B: usize,
+
+    /// Sequence length
+    T: usize,
+
+    /// Shards of the dataset, in name order
+    shards: Arc<Vec<Shard>>,
+
+    /// Seed of the shuffling, `None` to read the shards and their batches in order
+    shuffle_seed: Option<u64>,
+
+    /// Order of the shards in the current epoch
+    shard_order: Vec<usize>,
+
+    /// Order of the batches in the current shard
+    sample_order: Vec<usize>,
+
+    /// Position of the next batch
+    position: Position,
+
+    /// File of the shard being read, and its index
+    open_shard: Option<(usize, File)>,
+
+    /// Bytes of the last batch read
+    buffer: Vec<u8>,
+}
+
+impl Cursor {
+    /// Moves to a position, which must be inside of the dataset.
+    fn seek(&mut self, (epoch, shard_index, sample_index): Position) {
+        self.shard_order = shard_order(&self.shards, self.shuffle_seed, epoch);
+        let shard = self.shard_order[shard_index];
+        self.sample_order = (0..self.shards[shard].num_samples).collect();
+        if let Some(seed) = self.shuffle_seed {
+            let mut state = permutation_state(seed, epoch, shard as u64 + 1);
+            random::shuffle(&mut self.sample_order, &mut state);
+        }
+        self.position = (epoch, shard_index, sample_index);
+    }
+
+    /// Creates a cursor at the same position, with its own file.
+    fn fork(&self) -> Cursor {
+        Cursor {
+            B: self.B,
+            T: self.T,
+            shards: Arc::clone(&self.shards),
+            shuffle_seed: self.shuffle_seed,
+            shard_order: self.shard_order.clone(),
+            sample_order: self.sample_order.clone(),
+            position: self.position,
+            open_shard: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Reads the next batch of B*T+1 tokens and moves to the next batch, shard or epoch.
+    ///
+    /// # Returns
+    ///
+    /// An error if the shard cannot be read, the position does not move then.
+    fn read_batch(&mut self, batch: &mut [i32]) -> Result<()> {
+        let (epoch, shard_index, sample_index) = self.position;
+        let shard_id = self.shard_order[shard_index];
+        let shard = &self.shards[shard_id];
+        let token_size = shard.token_size;
+
+        if self.open_shard.as_ref().map(|(id, _)| *id) != Some(shard_id) {
+            self.open_shard = Some((shard_id, File::open(&shard.path)?));
+        }
+        let Some((_, tokens_file)) = &mut self.open_shard else {
+            panic!("File is not open");
+        };
+
+        // seek to the batch, batches start every B*T tokens
+        let sample = self.sample_order[sample_index];
+        let position = shard.header_size + (sample * self.B * self.T * token_size) as u64;
+        tokens_file.seek(SeekFrom::Start(position))?;
+
+        // read B*T+1 integers from the file into the buffer, then decode them into batch
+        self.buffer.resize(batch.len() * token_size, 0);
+        tokens_file.read_exact(&mut self.buffer)?;
+        if token_size == mem::size_of::<u16>() {
+            for (token, chunk) in batch.iter_mut().zip(self.buffer.chunks_exact(token_size)) {
+                *token = u16::from_le_bytes([chunk[0], chunk[1]]) as i32;
+            }
+        } else {
+            for (token, chunk) in batch.iter_mut().zip(self.buffer.chunks_exact(token_size)) {
+                *token = i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            }
+        }
+
+        // Move to the next batch, the next shard or the next epoch
+        if sample_index + 1 < self.sample_order.len() {
+            self.position.2 += 1;
+        } else if shard_index + 1 < self.shards.len() {
+            self.seek((epoch, shard_index + 1, 0));
+        } else {
+            self.seek((epoch + 1, 0, 0));
+        }
+        Ok(())
+    }
+}
+
+/// Reads batches ahead on a background thread.
+///
+/// Buffers of B*T+1 tokens go around a ring: the thread fills the free ones in order and sends
+/// them back with the position after them, `next_batch` hands the oldest one to the model and
+/// frees the previous one. There is one more buffer than batches read ahead, for the batch the
+/// model is using. The thread stops after sending the first read error.
+struct Prefetcher {
+    /// Filled buffers, in order, with the position of the `DataLoader` after each, or the error
+    /// reading the next one
+    filled: Receiver<Result<(Vec<i32>, Position)>>,
+
+    /// Buffers for the thread to fill, closed to stop it
+    free: Option<Sender<Vec<i32>>>,
+
+    /// Buffer of the current batch, freed by the next call to `next_batch`
+    current: Option<Vec<i32>>,
+
+    /// The background thread
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Prefetcher {
+    /// Starts reading batches on a background thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor` - Reads the batches from its position on.
+    /// * `num_batches` - Number of batches read ahead.
+    fn start(mut cursor: Cursor, num_batches: usize) -> Self {
+        let (free_sender, free_receiver) = mpsc::channel::<Vec<i32>>();
+        let (filled_sender, filled_receiver) = mpsc::channel();
+        let batch_size = cursor.B * cursor.T + 1;
+        for _ in 0..num_batches + 1 {
+            free_sender.send(vec![0; batch_size]).unwrap();
+        }
+        let worker = thread::spawn(move || {
+            // Stops when `free` is closed or the receiving end is gone
+            while let Ok(mut batch) = free_receiver.recv() {
+                let filled = cursor.read_batch(&mut batch).map(|()| (batch, cursor.position));
+                let failed = filled.is_err();
+                if filled_sender.send(filled).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Prefetcher {
+            filled: filled_receiver,
+            free: Some(free_sender),
+            current: None,
+            worker: Some(worker),
+        }
+    }
+
+    /// Frees the current buffer and waits for the next filled one.
+    ///
+    /// # Returns
+    ///
+    /// A pointer to the B*T+1 tokens of the batch, valid until the next call, and the position
+    /// of the `DataLoader` after it, or the error of the thread reading it.
+    fn next_batch(&mut self) -> Result<(*mut i32, Position)> {
+        if let (Some(batch), Some(free)) = (self.current.take(), &self.free) {
+            // The thread only stops once `free` is closed, it still receives
+            let _ = free.send(batch);
+        }
+        let Ok(filled) = self.filled.recv() else {
+            // The thread only stops on its own after an error or by panicking, pass its panic on
+            self.stop();
+            panic!("The prefetching thread stopped");
+        };
+        let (mut batch, position) = filled?;
+        let ptr = batch.as_mut_ptr();
+        self.current = Some(batch);
+        Ok((ptr, position))
+    }
+
+    /// Stops the background thread and waits for it.
+    fn stop(&mut self) {
+        self.free = None;
+        if let Some(worker) = self.worker.take() {
+            if let Err(panic) = worker.join() {
+                panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
 pub struct DataLoader {
     // ----------------------------------------------------------------------------
     // Hyperparameters
@@ -20,14 +249,34 @@ pub struct DataLoader {
     // ----------------------------------------------------------------------------
     // Input handling and its state
     // ----------------------------------------------------------------------------
-    /// File for tokens
-    pub tokens_file: Option<File>,
+    /// Reads the batches in the synchronous mode, positions the prefetching thread otherwise
+    cursor: Cursor,
+
+    /// Reads the batches ahead in the prefetching mode
+    prefetcher: Option<Prefetcher>,
 
-    /// File size
-    pub file_size: u64,
+    /// Number of batches read ahead, 0 in the synchronous mode
+    prefetch_batches: usize,
 
-    /// Current position in the file
-    pub current_position: u64,
+    /// Number of complete passes over the dataset
+    pub epoch: usize,
+
+    /// Position of the current shard in the order of the epoch
+    pub shard_index: usize,
+
+    /// Position of the next batch in the order of the current shard
+    pub sample_index: usize,
+
+    /// Token starting each document, e.g. EOT, to fill `doc_starts`. `None` makes each row a
+    /// single document.
+    pub eot_token: Option<i32>,
+
+    /// Whether `next_batch` goes on with the next epoch after the last batch of the current one.
+    /// Without it, every batch is read exactly once until the next `reset`, e.g. to evaluate.
+    pub wrap: bool,
+
+    /// Whether the last batch of the epoch was read without `wrap`
+    exhausted: bool,
 
     // ----------------------------------------------------------------------------
     // Output memory
@@ -41,138 +290,801 @@ pub struct DataLoader {
     /// Pointer to target tokens
     pub targets: SendPtr<i32>,
 
+    /// Pointer to the start of the document of each input token, in its row
+    pub doc_starts: SendPtr<i32>,
+
     // ----------------------------------------------------------------------------
     // Convenience variables
     // ----------------------------------------------------------------------------
-    /// Number of batches
+    /// Number of tokens in all the shards
+    pub num_tokens: u64,
+
+    /// Number of batches in an epoch
     pub num_batches: usize,
 }
 
 impl DataLoader {
-    /// Creates a new DataLoader instance.
+    /// Creates a new DataLoader instance, reading the shards and their batches in order.
     ///
     /// # Arguments
     ///
-    /// * `filename` - Path to the tokens file.
+    /// * `pattern` - Path to the tokens file, or a glob pattern matching several shards, e.g.
+    ///   `data/fineweb_train_*.bin`. The shards are read in name order.
     /// * `B` - Batch size.
     /// * `T` - Sequence length.
     ///
     /// # Returns
     ///
-    /// A new `DataLoader` instance.
-    pub fn new(filename: &Path, B: usize, T: usize) -> Self {
+    /// A new `DataLoader` instance, or an error if a file cannot be read or they are all too small.
+    ///
+    /// # Note
+    ///
+    /// Each file is either a shard of llm.c, with a header of 256 int32 (magic 20240520, version 1
+    /// and the number of tokens) followed by uint16 tokens, or a legacy headerless stream of int32
+    /// tokens. Both are little-endian. Batches never span two files, and shards with fewer than
+    /// B*T+1 tokens are skipped.
+    pub fn new(pattern: &Path, B: usize, T: usize) -> Result<Self> {
+        let pattern_text = pattern.to_string_lossy();
+        let mut paths = glob::glob(&pattern_text)
+            .map_err(|err| Error::InvalidInput(format!("bad pattern {}: {}", pattern_text, err)))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| Error::Io(err.into()))?;
+        if paths.is_empty() {
+            // Report a missing file like `File::open` would
+            File::open(pattern)?;
+            paths.push(pattern.to_path_buf());
+        }
+        paths.sort();
+
+        let mut shards = Vec::with_capacity(paths.len());
+        let mut first_error = None;
+        for path in paths {
+            let shard = read_shard(path, B, T)?;
+            if shard.num_samples > 0 {
+                shards.push(shard);
+            } else if first_error.is_none() {
+                first_error = Some(Error::Truncated {
+                    expected: shard.header_size + ((B * T + 1) * shard.token_size) as u64,
+                    actual: shard.header_size + shard.num_tokens * shard.token_size as u64,
+                });
+            }
+        }
+        if shards.is_empty() {
+            return Err(first_error.unwrap());
+        }
+
         let mut loader = DataLoader {
             B,
             T,
-            tokens_file: None,
-            file_size: 0,
-            current_position: 0,
+            num_tokens: shards.iter().map(|shard| shard.num_tokens).sum(),
+            num_batches: shards.iter().map(|shard| shard.num_samples).sum(),
+            cursor: Cursor {
+                B,
+                T,
+                shards: Arc::new(shards),
+                shuffle_seed: None,
+                shard_order: Vec::new(),
+                sample_order: Vec::new(),
+                position: (0, 0, 0),
+                open_shard: None,
+                buffer: Vec::new(),
+            },
+            prefetcher: None,
+            prefetch_batches: 0,
+            epoch: 0,
+            shard_index: 0,
+            sample_index: 0,
+            eot_token: None,
+            wrap: true,
+            exhausted: false,
             batch: SendPtr::new(null_mut()),
             inputs: SendPtr::new(null_mut()),
             targets: SendPtr::new(null_mut()),
-            num_batches: 0,
+            doc_starts: SendPtr::new(null_mut()),
         };
-
-        loader.tokens_file = match File::open(filename) {
-            Ok(file) => Some(file),
-            Err(_) => {
-                panic!("Error opening tokens file");
-            }
-        };
-
-        // Determine the file size
-        if let Some(file) = &mut loader.tokens_file {
-            if let Err(_) = file.seek(SeekFrom::End(0)) {
-                panic!("Error seeking to end of tokens file");
-            }
-
-            loader.file_size = match file.metadata() {
-                Ok(metadata) => metadata.len(),
-                Err(_) => {
-                    panic!("Error getting file size");
-                }
-            };
-
-            if let Err(_) = file.seek(SeekFrom::Start(0)) {
-                panic!("Error seeking to start of tokens file");
-            }
-        }
-
-        if loader.file_size < ((B * T + 1) * std::mem::size_of::<i32>()) as u64 {
-            panic!("Error: file size is too small for the batch size and sequence length");
-        }
-        loader.current_position = 0; // Start at the beginning
+        loader.reset();
 
         // Allocate space for B*T + 1 integers to store the inputs and targets
         unsafe {
-            let layout =
-                Layout::array::<i32>((B * T + 1) * mem::size_of::<i32>()).expect("Layout error");
+            let layout = Layout::array::<i32>(B * T + 1).expect("Layout error");
             loader.batch.ptr = alloc(layout) as *mut i32;
             loader.inputs = loader.batch;
             loader.targets.ptr = loader.batch.ptr.add(1); // Targets are shifted by one
-            loader.num_batches = (loader.file_size as usize) / (B * T * std::mem::size_of::<i32>());
+            let layout = Layout::array::<i32>(B * T).expect("Layout error");
+            loader.doc_starts.ptr = alloc(layout) as *mut i32;
         }
 
-        loader
+        Ok(loader)
+    }
+
+    /// Shuffles the order of the shards and of the batches in each shard, with a new permutation
+    /// every epoch, and starts over from the first epoch.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - Seed of the permutations. The same seed gives the same batches in the same order.
+    pub fn shuffle(&mut self, seed: u64) {
+        self.cursor.shuffle_seed = Some(seed);
+        self.reset();
+    }
+
+    /// Reads the next batches on a background thread, so that `next_batch` does not wait for the
+    /// file while they keep up with the training.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_batches` - Number of batches read ahead, 0 to go back to reading them in
+    ///   `next_batch`.
+    ///
+    /// # Note
+    ///
+    /// The batches and their order are the same in both modes. In the prefetching mode, `inputs`
+    /// and `targets` point to a buffer of the thread, valid until the next call to `next_batch`.
+    pub fn prefetch(&mut self, num_batches: usize) {
+        self.prefetch_batches = num_batches;
+        self.seek((self.epoch, self.shard_index, self.sample_index));
+    }
+
+    /// Number of shards the batches are read from.
+    pub fn num_shards(&self) -> usize {
+        self.cursor.shards.len()
+    }
+
+    /// Fraction of the batches of the current epoch already read, in [0, 1).
+    pub fn epoch_progress(&self) -> f64 {
+        let shards = &self.cursor.shards;
+        let read: usize = shard_order(shards, self.cursor.shuffle_seed, self.epoch)
+            [..self.shard_index]
+            .iter()
+            .map(|&shard| shards[shard].num_samples)
+            .sum::<usize>()
+            + self.sample_index;
+        read as f64 / self.num_batches as f64
     }
 
-    /// Resets the DataLoader to start from the beginning of the file.
+    /// Resets the DataLoader to start from the beginning of the first epoch.
     pub fn reset(&mut self) {
-        self.current_position = 0;
+        self.seek((0, 0, 0));
+    }
+
+    /// Moves the DataLoader to a position saved from its counters, e.g. to resume training.
+    ///
+    /// # Arguments
+    ///
+    /// * `epoch` - Number of complete passes over the dataset.
+    /// * `shard_index` - Position of the shard in the order of the epoch.
+    /// * `sample_index` - Position of the next batch in the order of the shard.
+    ///
+    /// # Returns
+    ///
+    /// An error if the position is outside of the dataset, e.g. it was saved with other shards.
+    pub fn resume(&mut self, epoch: usize, shard_index: usize, sample_index: usize) -> Result<()> {
+        let shards = &self.cursor.shards;
+        // The shard order of the epoch fixes which shard is at `shard_index`
+        let in_range = shard_index < shards.len() && {
+            let shard = shard_order(shards, self.cursor.shuffle_seed, epoch)[shard_index];
+            sample_index < shards[shard].num_samples
+        };
+        if !in_range {
+            return Err(Error::InvalidInput(format!(
+                "no batch {} in shard {} of the dataset",
+                sample_index, shard_index
+            )));
+        }
+        self.seek((epoch, shard_index, sample_index));
+        Ok(())
     }
 
     /// Loads the next batch of data into the DataLoader's memory.
-    pub fn next_batch(&mut self) {
-        let B = self.B;
-        let T = self.T;
-
-        // If we are at the end of the file, loop back to the beginning
-        if self.current_position + ((B * T + 1) * std::mem::size_of::<i32>()) as u64
-            > self.file_size
-        {
-            self.current_position = 0;
-        }
-
-        // Read the B*T+1 integers from the file into batch
-        if let Some(tokens_file) = &mut self.tokens_file {
-            // seek to the current position in the file
-            tokens_file
-                .seek(SeekFrom::Start(self.current_position))
-                .expect("Seek Failed");
-
-            // read B*T+1 integers from the file into batch
-            let mut buffer = vec![0; (B * T + 1) * std::mem::size_of::<i32>()];
-            tokens_file.read_exact(&mut buffer).expect("Read Failed");
-
-            // copy the buffer into the batch pointer
-            unsafe {
-                let batch_slice = std::slice::from_raw_parts_mut(self.batch.ptr, B * T + 1);
-                for (i, chunk) in buffer.chunks_exact(std::mem::size_of::<i32>()).enumerate() {
-                    batch_slice[i] = i32::from_ne_bytes(chunk.try_into().unwrap());
+    ///
+    /// # Note
+    ///
+    /// With an `eot_token`, `doc_starts` gets the position of the last `eot_token` up to each
+    /// input token of its row, or 0 before the first one, so that a document starting with it
+    /// never attends to the previous ones. Otherwise it is all 0.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(true)` if a batch was loaded, `Ok(false)` without `wrap` once the last batch of the
+    /// epoch was read before, or an error if a shard cannot be read. After an error the position
+    /// does not move, the next call reads the same batch again.
+    pub fn next_batch(&mut self) -> Result<bool> {
+        if self.exhausted && !self.wrap {
+            return Ok(false);
+        }
+        if self.prefetch_batches > 0 && self.prefetcher.is_none() {
+            // The thread stopped on an error, start it over from the batch it could not read
+            self.seek((self.epoch, self.shard_index, self.sample_index));
+        }
+        let position = if let Some(prefetcher) = &mut self.prefetcher {
+            match prefetcher.next_batch() {
+                Ok((ptr, position)) => {
+                    self.inputs = SendPtr::new(ptr);
+                    position
+                }
+                Err(err) => {
+                    if let Some(mut prefetcher) = self.prefetcher.take() {
+                        prefetcher.stop();
+                    }
+                    return Err(err);
                 }
             }
-
-            // advance the current position by B*T integers
-            self.current_position += (B * T) as u64 * std::mem::size_of::<i32>() as u64;
         } else {
-            panic!("File is not open");
+            let batch =
+                unsafe { std::slice::from_raw_parts_mut(self.batch.ptr, self.B * self.T + 1) };
+            self.cursor.read_batch(batch)?;
+            self.inputs = self.batch;
+            self.cursor.position
+        };
+        unsafe {
+            self.targets.ptr = self.inputs.ptr.add(1); // Targets are shifted by one
+
+            let inputs = std::slice::from_raw_parts(self.inputs.ptr, self.B * self.T);
+            let doc_starts = std::slice::from_raw_parts_mut(self.doc_starts.ptr, self.B * self.T);
+            for (row, starts) in inputs.chunks_exact(self.T).zip(doc_starts.chunks_exact_mut(self.T)) {
+                let mut start = 0;
+                for (t, (&token, doc_start)) in row.iter().zip(starts.iter_mut()).enumerate() {
+                    if Some(token) == self.eot_token {
+                        start = t as i32;
+                    }
+                    *doc_start = start;
+                }
+            }
+        }
+        self.exhausted = !self.wrap && position.0 != self.epoch;
+        (self.epoch, self.shard_index, self.sample_index) = position;
+        Ok(true)
+    }
+
+    /// Moves the DataLoader to a position inside of the dataset, restarting the prefetching
+    /// thread from there.
+    fn seek(&mut self, position: Position) {
+        if let Some(mut prefetcher) = self.prefetcher.take() {
+            prefetcher.stop();
+        }
+        self.cursor.seek(position);
+        (self.epoch, self.shard_index, self.sample_index) = position;
+        self.exhausted = false;
+        if self.prefetch_batches > 0 {
+            self.prefetcher = Some(Prefetcher::start(self.cursor.fork(), self.prefetch_batches));
         }
     }
 
     /// Frees the memory allocated by the DataLoader.
     pub fn free(&mut self) {
-        if let Some(file) = self.tokens_file.take() {
-            drop(file); // Close the file by dropping it
+        if let Some(mut prefetcher) = self.prefetcher.take() {
+            prefetcher.stop();
         }
+        self.cursor.open_shard = None; // Close the file by dropping it
         unsafe {
             if !self.batch.ptr.is_null() {
                 let layout =
                     std::alloc::Layout::array::<i32>(self.B * self.T + 1).expect("Layout error");
                 std::alloc::dealloc(self.batch.ptr as *mut u8, layout);
             }
+            if !self.doc_starts.ptr.is_null() {
+                let layout = std::alloc::Layout::array::<i32>(self.B * self.T).expect("Layout error");
+                std::alloc::dealloc(self.doc_starts.ptr as *mut u8, layout);
+            }
         }
         self.batch = SendPtr::new(null_mut());
         self.inputs = SendPtr::new(null_mut());
         self.targets = SendPtr::new(null_mut());
+        self.doc_starts = SendPtr::new(null_mut());
+    }
+}
+
+/// Reads and validates the header of a token file.
+///
+/// # Arguments
+///
+/// * `path` - Path to the tokens file.
+/// * `B` - Batch size.
+/// * `T` - Sequence length.
+///
+/// # Returns
+///
+/// The layout of the file and its number of batches, or an error if its header is invalid.
+fn read_shard(path: PathBuf, B: usize, T: usize) -> Result<Shard> {
+    let mut file = File::open(&path)?;
+    let file_size = file.metadata()?.len();
+    let mut shard = Shard {
+        path,
+        header_size: 0,
+        token_size: mem::size_of::<i32>(),
+        num_tokens: 0,
+        num_samples: 0,
+    };
+
+    // Tell the shards from the legacy files by their magic number
+    let mut first_bytes = [0u8; 4];
+    let has_header = file_size >= SHARD_HEADER_BYTES
+        && file.read_exact(&mut first_bytes).is_ok()
+        && i32::from_le_bytes(first_bytes) == SHARD_MAGIC;
+    if has_header {
+        let mut header_bytes = [0u8; SHARD_HEADER_BYTES as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header_bytes)?;
+        let header: Vec<i32> = header_bytes
+            .chunks_exact(mem::size_of::<i32>())
+            .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        if header[1] != SHARD_VERSION {
+            return Err(Error::BadVersion(header[1]));
+        }
+        if header[2] < 0 {
+            return Err(Error::InconsistentHeader(format!(
+                "negative number of tokens {}",
+                header[2]
+            )));
+        }
+        shard.header_size = SHARD_HEADER_BYTES;
+        shard.token_size = mem::size_of::<u16>();
+        shard.num_tokens = header[2] as u64;
+        let expected_size = SHARD_HEADER_BYTES + shard.num_tokens * shard.token_size as u64;
+        if file_size != expected_size {
+            return Err(if file_size < expected_size {
+                Error::Truncated {
+                    expected: expected_size,
+                    actual: file_size,
+                }
+            } else {
+                Error::InconsistentHeader(format!(
+                    "the header has {} tokens but the file has room for {}",
+                    shard.num_tokens,
+                    (file_size - SHARD_HEADER_BYTES) / shard.token_size as u64
+                ))
+            });
+        }
+    } else {
+        shard.num_tokens = file_size / shard.token_size as u64;
+    }
+
+    // The last token of a batch is the first of the next one, only as a target
+    shard.num_samples = (shard.num_tokens.saturating_sub(1) / (B * T) as u64) as usize;
+    Ok(shard)
+}
+
+/// Orders the shards of an epoch.
+///
+/// # Arguments
+///
+/// * `shards` - Shards of the dataset.
+/// * `shuffle_seed` - Seed of the shuffling, `None` to keep the name order.
+/// * `epoch` - The epoch.
+///
+/// # Returns
+///
+/// The indices of the shards in the order they are read.
+fn shard_order(shards: &[Shard], shuffle_seed: Option<u64>, epoch: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..shards.len()).collect();
+    if let Some(seed) = shuffle_seed {
+        let mut state = permutation_state(seed, epoch, 0);
+        random::shuffle(&mut order, &mut state);
+    }
+    order
+}
+
+/// Derives the RNG state of a permutation of a shuffled data loader.
+///
+/// # Arguments
+///
+/// * `seed` - Seed of the shuffling.
+/// * `epoch` - Epoch of the permutation.
+/// * `key` - 0 for the order of the shards, 1 + the shard for the order of its batches.
+///
+/// # Returns
+///
+/// A non-zero RNG state, so that the permutations only depend on where they are used.
+pub(crate) fn permutation_state(seed: u64, epoch: usize, key: u64) -> u64 {
+    // splitmix64 finalizer, so that close seeds give unrelated permutations
+    let mut state = seed
+        ^ (epoch as u64).wrapping_mul(0x9E3779B97F4A7C15)
+        ^ key.wrapping_mul(0xBF58476D1CE4E5B9);
+    state = (state ^ (state >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    state = (state ^ (state >> 27)).wrapping_mul(0x94D049BB133111EB);
+    state ^= state >> 31;
+    state.max(1)
+}
+
+/// Writes tokens to a file `DataLoader` reads.
+///
+/// # Arguments
+///
+/// * `filename` - Path to the tokens file.
+/// * `tokens` - The tokens to write.
+///
+/// # Returns
+///
+/// An error if the file cannot be written.
+///
+/// # Note
+///
+/// Tokens that all fit in uint16, like the ones of GPT-2, are written as a shard with a header,
+/// which takes half the space. Larger ones are written as a legacy int32 file.
+pub fn write_tokens(filename: &Path, tokens: &[u32]) -> Result<()> {
+    let uint16 = tokens.iter().all(|&token| token <= u16::MAX as u32) && tokens.len() <= i32::MAX as usize;
+    let mut writer = TokenWriter::create(filename, uint16)?;
+    writer.write(tokens)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes a tokens file `DataLoader` reads a few tokens at a time, without holding them all.
+pub struct TokenWriter {
+    /// The file being written.
+    file: BufWriter<File>,
+
+    /// Whether the file is a shard of uint16 tokens with a header, or a legacy int32 file.
+    uint16: bool,
+
+    /// Number of tokens written so far.
+    num_tokens: usize,
+}
+
+impl TokenWriter {
+    /// Creates an empty tokens file.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path to the tokens file.
+    /// * `uint16` - Write a shard of uint16 tokens with a header, instead of a legacy int32 file.
+    ///
+    /// # Returns
+    ///
+    /// A new `TokenWriter`, or an error if the file cannot be created.
+    pub fn create(filename: &Path, uint16: bool) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(filename)?);
+        if uint16 {
+            // The number of tokens is filled in by `finish`
+            file.write_all(&[0u8; SHARD_HEADER_BYTES as usize])?;
+        }
+        Ok(TokenWriter {
+            file,
+            uint16,
+            num_tokens: 0,
+        })
+    }
+
+    /// Appends tokens to the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The tokens to write.
+    ///
+    /// # Returns
+    ///
+    /// An error if the file cannot be written, or if a token does not fit in uint16 in a shard.
+    pub fn write(&mut self, tokens: &[u32]) -> Result<()> {
+        for &token in tokens {
+            if !self.uint16 {
+                self.file.write_all(&(token as i32).to_le_bytes())?;
+            } else if let Ok(token) = u16::try_from(token) {
+                self.file.write_all(&token.to_le_bytes())?;
+            } else {
+                return Err(Error::InvalidInput(format!(
+                    "token {} does not fit in a uint16 shard",
+                    token
+                )));
+            }
+        }
+        self.num_tokens += tokens.len();
+        Ok(())
+    }
+
+    /// Completes the file.
+    ///
+    /// # Returns
+    ///
+    /// The number of tokens written, or an error if the file cannot be written or if a shard has
+    /// more tokens than its header can count.
+    pub fn finish(mut self) -> Result<usize> {
+        if self.uint16 {
+            let Ok(num_tokens) = i32::try_from(self.num_tokens) else {
+                return Err(Error::InvalidInput(format!(
+                    "{} tokens do not fit in a shard",
+                    self.num_tokens
+                )));
+            };
+            let mut header = [0i32; 256];
+            header[0] = SHARD_MAGIC;
+            header[1] = SHARD_VERSION;
+            header[2] = num_tokens;
+            self.file.seek(SeekFrom::Start(0))?;
+            for value in header {
+                self.file.write_all(&value.to_le_bytes())?;
+            }
+        }
+        self.file.flush()?;
+        Ok(self.num_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A temporary directory for the token files of a test, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("llm-rs-dataloader-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes a shard with the given header values and raw bytes after the header.
+    fn write_raw_shard(path: &Path, magic: i32, version: i32, num_tokens: i32, tokens: &[u8]) {
+        let mut header = [0i32; 256];
+        header[..3].copy_from_slice(&[magic, version, num_tokens]);
+        let bytes: Vec<u8> = header.iter().flat_map(|value| value.to_le_bytes()).chain(tokens.iter().copied()).collect();
+        fs::write(path, bytes).unwrap();
+    }
+
+    /// The B*T+1 tokens of the batch loaded last.
+    fn batch(loader: &DataLoader) -> Vec<i32> {
+        unsafe { std::slice::from_raw_parts(loader.inputs.ptr, loader.B * loader.T + 1).to_vec() }
+    }
+
+    #[test]
+    fn write_tokens_picks_the_smallest_format() {
+        let dir = TestDir::new("write");
+
+        // Tokens fitting in uint16 get a header, up to 65535 which must not come back as -1
+        let small: Vec<u32> = vec![0, 1, 50256, 65535, 7];
+        write_tokens(&dir.path("small.bin"), &small).unwrap();
+        assert_eq!(fs::metadata(dir.path("small.bin")).unwrap().len(), 1024 + 2 * 5);
+        let mut loader = DataLoader::new(&dir.path("small.bin"), 1, 4).unwrap();
+        assert_eq!(loader.num_tokens, 5);
+        assert!(loader.next_batch().unwrap());
+        assert_eq!(batch(&loader), [0, 1, 50256, 65535, 7]);
+        loader.free();
+
+        // A single larger token makes it a legacy int32 file
+        let large: Vec<u32> = vec![3, 65536, 100000, 2, 1];
+        write_tokens(&dir.path("large.bin"), &large).unwrap();
+        assert_eq!(fs::metadata(dir.path("large.bin")).unwrap().len(), 4 * 5);
+        let mut loader = DataLoader::new(&dir.path("large.bin"), 1, 4).unwrap();
+        assert!(loader.next_batch().unwrap());
+        assert_eq!(batch(&loader), [3, 65536, 100000, 2, 1]);
+        loader.free();
+
+        // The streaming writer writes the same bytes
+        let mut writer = TokenWriter::create(&dir.path("streamed.bin"), true).unwrap();
+        writer.write(&small[..2]).unwrap();
+        writer.write(&small[2..]).unwrap();
+        assert_eq!(writer.finish().unwrap(), 5);
+        assert_eq!(fs::read(dir.path("streamed.bin")).unwrap(), fs::read(dir.path("small.bin")).unwrap());
+        let mut writer = TokenWriter::create(&dir.path("streamed.bin"), true).unwrap();
+        assert!(matches!(writer.write(&[65536]), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn headers_are_detected_and_validated() {
+        let dir = TestDir::new("headers");
+        let tokens: Vec<u8> = (0..9u16).flat_map(|token| token.to_le_bytes()).collect();
+
+        write_raw_shard(&dir.path("ok.bin"), SHARD_MAGIC, 1, 9, &tokens);
+        let mut loader = DataLoader::new(&dir.path("ok.bin"), 2, 4).unwrap();
+        assert_eq!((loader.num_tokens, loader.num_batches), (9, 1));
+        loader.next_batch().unwrap();
+        assert_eq!(batch(&loader), (0..9).collect::<Vec<_>>());
+        loader.free();
+
+        let load = |file: &str| DataLoader::new(&dir.path(file), 2, 4).map(|mut loader| loader.free());
+        write_raw_shard(&dir.path("version.bin"), SHARD_MAGIC, 2, 9, &tokens);
+        assert!(matches!(load("version.bin"), Err(Error::BadVersion(2))));
+        write_raw_shard(&dir.path("negative.bin"), SHARD_MAGIC, 1, -1, &tokens);
+        assert!(matches!(load("negative.bin"), Err(Error::InconsistentHeader(_))));
+        write_raw_shard(&dir.path("truncated.bin"), SHARD_MAGIC, 1, 10, &tokens);
+        assert!(matches!(
+            load("truncated.bin"),
+            Err(Error::Truncated { expected: 1044, actual: 1042 })
+        ));
+        write_raw_shard(&dir.path("long.bin"), SHARD_MAGIC, 1, 8, &tokens);
+        assert!(matches!(load("long.bin"), Err(Error::InconsistentHeader(_))));
+
+        // Without the magic number, the whole file is legacy int32 tokens, header included
+        write_raw_shard(&dir.path("legacy.bin"), 7, 1, 9, &tokens);
+        let mut loader = DataLoader::new(&dir.path("legacy.bin"), 2, 4).unwrap();
+        assert_eq!(loader.num_tokens, (1024 + 18) / 4);
+        loader.next_batch().unwrap();
+        assert_eq!(batch(&loader)[..4], [7, 1, 9, 0]);
+        loader.free();
+
+        // Too few tokens for a batch
+        assert!(matches!(
+            DataLoader::new(&dir.path("ok.bin"), 2, 8).map(|mut loader| loader.free()),
+            Err(Error::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn read_errors_are_returned() {
+        let dir = TestDir::new("errors");
+        let tokens: Vec<u32> = (0..33).collect();
+        write_tokens(&dir.path("tokens.bin"), &tokens).unwrap();
+
+        for prefetch in [0, 2] {
+            let mut loader = DataLoader::new(&dir.path("tokens.bin"), 2, 4).unwrap();
+            loader.prefetch(prefetch);
+            loader.next_batch().unwrap();
+            assert_eq!(batch(&loader)[0], 0);
+
+            // Truncate the file under the loader, which keeps it open
+            let bytes = fs::read(dir.path("tokens.bin")).unwrap();
+            fs::write(dir.path("tokens.bin"), &bytes[..1024 + 8]).unwrap();
+            loader.reset();
+            assert!(matches!(loader.next_batch(), Err(Error::Io(_))));
+            assert_eq!((loader.epoch, loader.shard_index, loader.sample_index), (0, 0, 0));
+
+            // The same batch is read again once the file is back
+            fs::write(dir.path("tokens.bin"), &bytes).unwrap();
+            loader.next_batch().unwrap();
+            assert_eq!(batch(&loader)[0], 0);
+            assert_eq!(loader.sample_index, 1);
+            loader.free();
+        }
+    }
+
+    /// Writes 3 shards of 5, 3 and 4 batches of B=1, T=4, in which token `1000 * shard + i` is at
+    /// position i, so that the first token of a batch tells where it comes from.
+    fn write_dataset(dir: &TestDir) -> PathBuf {
+        for (shard, num_batches) in [5, 3, 4].into_iter().enumerate() {
+            let tokens: Vec<u32> = (0..4 * num_batches + 1).map(|i| 1000 * shard as u32 + i).collect();
+            write_tokens(&dir.path(&format!("shard_{}.bin", shard)), &tokens).unwrap();
+        }
+        dir.path("shard_*.bin")
+    }
+
+    /// The first tokens of the next batches.
+    fn read_batches(loader: &mut DataLoader, num_batches: usize) -> Vec<i32> {
+        (0..num_batches)
+            .map(|_| {
+                assert!(loader.next_batch().unwrap());
+                batch(loader)[0]
+            })
+            .collect()
+    }
+
+    /// The first tokens of all the batches, in order.
+    fn all_batches() -> Vec<i32> {
+        [(0, 5), (1, 3), (2, 4)]
+            .into_iter()
+            .flat_map(|(shard, num_batches)| (0..num_batches).map(move |sample| 1000 * shard + 4 * sample))
+            .collect()
+    }
+
+    #[test]
+    fn shuffled_epochs_visit_every_batch_once() {
+        let dir = TestDir::new("shuffle");
+        let pattern = write_dataset(&dir);
+        let mut loader = DataLoader::new(&pattern, 1, 4).unwrap();
+        assert_eq!((loader.num_shards(), loader.num_batches), (3, 12));
+        assert_eq!(read_batches(&mut loader, 12), all_batches());
+
+        loader.shuffle(42);
+        let epochs: Vec<Vec<i32>> = (0..3).map(|_| read_batches(&mut loader, 12)).collect();
+        for epoch in &epochs {
+            let mut sorted = epoch.clone();
+            sorted.sort();
+            assert_eq!(sorted, all_batches());
+            // The batches of a shard stay together
+            let shards: Vec<i32> = epoch.iter().map(|token| token / 1000).collect();
+            assert_eq!(shards.windows(2).filter(|pair| pair[0] != pair[1]).count(), 2, "{:?}", epoch);
+        }
+        assert!(epochs[0] != all_batches() && epochs[0] != epochs[1] && epochs[1] != epochs[2]);
+        assert_eq!(loader.epoch, 3);
+
+        // The same seed gives the same order, another seed another one
+        loader.shuffle(42);
+        assert_eq!(read_batches(&mut loader, 36), epochs.concat());
+        loader.shuffle(43);
+        assert_ne!(read_batches(&mut loader, 12), epochs[0]);
+        loader.free();
+    }
+
+    #[test]
+    fn resume_continues_the_order() {
+        let dir = TestDir::new("resume");
+        let pattern = write_dataset(&dir);
+        let mut loader = DataLoader::new(&pattern, 1, 4).unwrap();
+        loader.shuffle(7);
+        let expected = read_batches(&mut loader, 30);
+
+        for stop in [0, 1, 4, 5, 11, 12, 13, 20] {
+            loader.shuffle(7);
+            read_batches(&mut loader, stop);
+            let position = (loader.epoch, loader.shard_index, loader.sample_index);
+
+            let mut resumed = DataLoader::new(&pattern, 1, 4).unwrap();
+            resumed.shuffle(7);
+            resumed.resume(position.0, position.1, position.2).unwrap();
+            assert_eq!(resumed.epoch_progress(), loader.epoch_progress());
+            assert_eq!(read_batches(&mut resumed, 30 - stop), expected[stop..], "stopped after {}", stop);
+            resumed.free();
+        }
+
+        // A position outside of the dataset is refused
+        assert!(loader.resume(0, 3, 0).is_err());
+        let last_shard = shard_order(&loader.cursor.shards, Some(7), 0)[2];
+        let num_samples = loader.cursor.shards[last_shard].num_samples;
+        assert!(loader.resume(0, 2, num_samples).is_err());
+        assert!(loader.resume(0, 2, num_samples - 1).is_ok());
+        loader.free();
+    }
+
+    /// The tokens, document starts and position after each of the next batches.
+    fn read_states(loader: &mut DataLoader, num_batches: usize) -> Vec<(Vec<i32>, Vec<i32>, Position)> {
+        (0..num_batches)
+            .map(|_| {
+                assert!(loader.next_batch().unwrap());
+                let doc_starts = unsafe { std::slice::from_raw_parts(loader.doc_starts.ptr, loader.B * loader.T) };
+                (batch(loader), doc_starts.to_vec(), (loader.epoch, loader.shard_index, loader.sample_index))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prefetched_batches_match_the_synchronous_ones() {
+        let dir = TestDir::new("prefetch");
+        let pattern = write_dataset(&dir);
+        let mut loader = DataLoader::new(&pattern, 1, 4).unwrap();
+        // Token 1002 is in the middle of a batch, which gets a document start
+        loader.eot_token = Some(1002);
+
+        for seed in [None, Some(42)] {
+            let restart = |loader: &mut DataLoader| match seed {
+                Some(seed) => loader.shuffle(seed),
+                None => loader.reset(),
+            };
+            restart(&mut loader);
+            loader.prefetch(0);
+            // Over two and a half epochs
+            let expected = read_states(&mut loader, 30);
+            let (_, _, (epoch, _, _)) = &expected[11];
+            assert_eq!(*epoch, 1);
+            assert!(expected.iter().any(|(_, doc_starts, _)| doc_starts == &[0, 0, 2, 2]));
+
+            for num_batches in [1, 3, 40] {
+                restart(&mut loader);
+                loader.prefetch(num_batches);
+                assert_eq!(read_states(&mut loader, 30), expected, "prefetching {}", num_batches);
+
+                // Switching modes in the middle keeps the order
+                restart(&mut loader);
+                let mut states = read_states(&mut loader, 7);
+                loader.prefetch(0);
+                states.extend(read_states(&mut loader, 7));
+                loader.prefetch(num_batches);
+                states.extend(read_states(&mut loader, 16));
+                assert_eq!(states, expected);
+            }
+        }
+
+        // Without wrapping, both modes stop after the last batch of the epoch
+        loader.wrap = false;
+        for num_batches in [0, 2] {
+            loader.prefetch(num_batches);
+            loader.reset();
+            let mut batches = read_batches(&mut loader, 12);
+            batches.sort();
+            assert_eq!(batches, all_batches());
+            assert!(!loader.next_batch().unwrap());
+        }
+        loader.free();
     }
 }
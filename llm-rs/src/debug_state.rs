@@ -0,0 +1,163 @@
+use core::slice;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::gpt2::{ParameterTensors, GPT2};
+use crate::send_ptr::SendPtr;
+
+/// Reference state dumped by `train_gpt2.py::write_state` for a single batch.
+///
+/// It contains the inputs and targets of the batch, the logits and loss PyTorch computed for
+/// them and the gradients of every parameter tensor after one backward pass.
+pub struct DebugState {
+    /// Batch size of the reference batch.
+    pub B: usize,
+
+    /// Sequence length of the reference batch.
+    pub T: usize,
+
+    /// Input tokens (B, T).
+    pub inputs: Vec<i32>,
+
+    /// Target tokens (B, T).
+    pub targets: Vec<i32>,
+
+    /// Expected logits (B, T, V), without the vocabulary padding.
+    pub expected_logits: Vec<f32>,
+
+    /// Expected mean loss.
+    pub expected_loss: f32,
+
+    /// Expected gradients, pointing into `expected_grads_memory`.
+    pub expected_grads: ParameterTensors,
+
+    /// Memory block containing all expected gradients, laid out like `GPT2::grads_memory`.
+    pub expected_grads_memory: SendPtr<f32>,
+
+    /// Total number of expected gradients.
+    pub num_parameters: usize,
+}
+
+impl DebugState {
+    /// Reads a debug state file written for the given model.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path to the debug state file.
+    /// * `model` - The model the state was produced with, used for its configuration and parameter sizes.
+    ///
+    /// # Returns
+    ///
+    /// A new `DebugState` instance, or an error if the file cannot be read or does not match the model.
+    pub fn new(filename: &Path, model: &GPT2) -> Result<Self> {
+        let mut state_file = File::open(filename)?;
+        let file_size = state_file.metadata()?.len();
+
+        // Read state header
+        let mut state_header = [0i32; 256];
+        let header_bytes = mem::size_of_val(&state_header) as u64;
+        if file_size < header_bytes {
+            return Err(Error::Truncated {
+                expected: header_bytes,
+                actual: file_size,
+            });
+        }
+        read_into(&mut state_file, &mut state_header)?;
+
+        // Check magic number and version
+        if state_header[0] != 20240327 {
+            return Err(Error::BadMagic {
+                expected: 20240327,
+                found: state_header[0],
+            });
+        }
+        if state_header[1] != 2 {
+            return Err(Error::BadVersion(state_header[1]));
+        }
+        if state_header[2] <= 0 || state_header[3] <= 0 {
+            return Err(Error::InconsistentHeader(format!(
+                "batch size {} and sequence length {} must be positive",
+                state_header[2], state_header[3]
+            )));
+        }
+
+        let B = state_header[2] as usize;
+        let T = state_header[3] as usize;
+        let V = model.config.vocab_size;
+
+        // Inputs, targets, logits, loss and gradients
+        let expected_size = header_bytes
+            + (2 * B * T * mem::size_of::<i32>()
+                + (B * T * V + 1 + model.num_parameters) * mem::size_of::<f32>()) as u64;
+        if file_size < expected_size {
+            return Err(Error::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
+        println!("[State]");
+        println!("batch_size: {}", B);
+        println!("seq_len: {}", T);
+
+        let mut state = DebugState {
+            B,
+            T,
+            inputs: vec![0; B * T],
+            targets: vec![0; B * T],
+            expected_logits: vec![0.0; B * T * V],
+            expected_loss: 0.0,
+            expected_grads: ParameterTensors::new(),
+            expected_grads_memory: SendPtr::new(std::ptr::null_mut()),
+            num_parameters: model.num_parameters,
+        };
+
+        // Read in the batch, the reference outputs and the reference gradients
+        read_into(&mut state_file, &mut state.inputs)?;
+        read_into(&mut state_file, &mut state.targets)?;
+        read_into(&mut state_file, &mut state.expected_logits)?;
+        let mut expected_loss = [0.0f32];
+        read_into(&mut state_file, &mut expected_loss)?;
+        state.expected_loss = expected_loss[0];
+        unsafe {
+            state.expected_grads_memory = state
+                .expected_grads
+                .alloc_and_point_parameters(&model.param_sizes);
+            if let Err(err) = read_into(
+                &mut state_file,
+                slice::from_raw_parts_mut(state.expected_grads_memory.ptr, state.num_parameters),
+            ) {
+                state.free();
+                return Err(err.into());
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Frees the memory allocated for the expected gradients.
+    pub fn free(&mut self) {
+        if !self.expected_grads_memory.ptr.is_null() {
+            unsafe {
+                let layout = std::alloc::Layout::array::<f32>(self.num_parameters).expect("Layout error");
+                std::alloc::dealloc(self.expected_grads_memory.ptr as *mut u8, layout);
+            }
+        }
+        self.expected_grads = ParameterTensors::new();
+        self.expected_grads_memory = SendPtr::new(std::ptr::null_mut());
+    }
+}
+
+/// Fills `buffer` with the raw native-endian bytes read from `file`.
+///
+/// # Arguments
+///
+/// * `file` - File to read from.
+/// * `buffer` - Destination slice of plain numeric values.
+fn read_into<T: Copy>(file: &mut File, buffer: &mut [T]) -> std::io::Result<()> {
+    file.read_exact(unsafe {
+        slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, mem::size_of_val(buffer))
+    })
+}
@@ -0,0 +1,525 @@
+use crate::gpt2::{InferenceSession, GPT2};
+
+/// A finished (or cut off) sequence of a beam search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// Generated tokens, without the prompt. Ends with the EOS token if the beam finished.
+    pub tokens: Vec<i32>,
+
+    /// Sum of the log-probabilities of the tokens, divided by `tokens.len()^length_penalty`.
+    pub score: f32,
+}
+
+/// Beam search decoding: keeps the `num_beams` most likely sequences at every step.
+///
+/// Each beam occupies one row of the B dimension of an `InferenceSession`, so all the beams go
+/// through the model together and a step costs one cached forward pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearch {
+    /// Number of sequences kept at every step.
+    pub num_beams: usize,
+
+    /// Exponent of the length normalization of the scores. Above 0 favors longer sequences,
+    /// below 0 shorter ones.
+    pub length_penalty: f32,
+
+    /// Stop as soon as `num_beams` sequences are finished. Otherwise stop when no running beam
+    /// can beat the finished ones anymore.
+    pub early_stopping: bool,
+
+    /// Token that finishes a sequence, if any.
+    pub eos_token: Option<i32>,
+}
+
+impl BeamSearch {
+    /// Creates a beam search with the usual defaults: no length penalty, no early stopping.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_beams` - Number of sequences kept at every step.
+    /// * `eos_token` - Token that finishes a sequence, if any.
+    ///
+    /// # Returns
+    ///
+    /// A new `BeamSearch`.
+    pub fn new(num_beams: usize, eos_token: Option<i32>) -> Self {
+        BeamSearch {
+            num_beams,
+            length_penalty: 1.0,
+            early_stopping: false,
+            eos_token,
+        }
+    }
+
+    /// Decodes a continuation of the prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to decode with.
+    /// * `prompt` - Tokens of the prompt, at least one and at most `max_seq_len` of the model.
+    /// * `max_new_tokens` - Maximum number of generated tokens. The hypotheses also stop when they
+    ///   reach `max_seq_len`, like `generate` does.
+    ///
+    /// # Returns
+    ///
+    /// The best `num_beams` hypotheses, best first. Empty when no token can be generated.
+    pub fn generate(&self, model: &GPT2, prompt: &[i32], max_new_tokens: usize) -> Vec<Hypothesis> {
+        let K = self.num_beams;
+        let V = model.config.vocab_size;
+        let Vp = model.config.padded_vocab_size;
+        let maxT = model.config.max_seq_len;
+        assert!(
+            K > 0 && 2 * K <= V,
+            "expected between 1 and V/2 beams, got {}",
+            K
+        );
+        assert!(
+            !prompt.is_empty() && prompt.len() <= maxT,
+            "the prompt must have between 1 and {} tokens, got {}",
+            maxT,
+            prompt.len()
+        );
+        let max_new_tokens = max_new_tokens.min(maxT - prompt.len());
+        // An empty hypothesis has no score
+        if max_new_tokens == 0 {
+            return Vec::new();
+        }
+
+        let mut session = InferenceSession::new(model, K, prompt.len() + max_new_tokens);
+        session.forward(&prompt.repeat(K));
+
+        // All the beams start with the same prompt, only follow the first one at first
+        let mut beams: Vec<Vec<i32>> = vec![Vec::new(); K];
+        let mut scores = vec![f32::NEG_INFINITY; K];
+        scores[0] = 0.0;
+        let mut finished: Vec<Hypothesis> = Vec::new();
+        let mut done = false;
+
+        for step in 0..max_new_tokens {
+            // The 2K best continuations of each beam are enough: at most K of them end a beam
+            let mut candidates = Vec::with_capacity(K * 2 * K);
+            for (b, &score) in scores.iter().enumerate() {
+                let log_probs = log_softmax(&session.logits()[b * Vp..b * Vp + V]);
+                for token in top_indices(&log_probs, 2 * K) {
+                    candidates.push((score + log_probs[token], b, token as i32));
+                }
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            let mut rows = Vec::with_capacity(K);
+            let mut next_tokens = Vec::with_capacity(K);
+            let mut next_scores = Vec::with_capacity(K);
+            for (rank, &(score, b, token)) in candidates.iter().enumerate() {
+                if Some(token) == self.eos_token {
+                    // Only an EOS among the K best candidates finishes a beam
+                    if rank < K {
+                        let mut tokens = beams[b].clone();
+                        tokens.push(token);
+                        self.add_hypothesis(&mut finished, tokens, score);
+                    }
+                    continue;
+                }
+                rows.push(b);
+                next_tokens.push(token);
+                next_scores.push(score);
+                if rows.len() == K {
+                    break;
+                }
+            }
+
+            beams = rows
+                .iter()
+                .zip(&next_tokens)
+                .map(|(&b, &token)| {
+                    let mut tokens = beams[b].clone();
+                    tokens.push(token);
+                    tokens
+                })
+                .collect();
+            scores = next_scores;
+
+            done = self.is_done(&finished, scores[0], step + 1);
+            if done {
+                break;
+            }
+            if step + 1 < max_new_tokens {
+                session.reorder(&rows);
+                session.forward(&next_tokens);
+            }
+        }
+
+        // The beams still running when the budget runs out are candidates too
+        if !done {
+            for (tokens, score) in beams.into_iter().zip(scores) {
+                if score > f32::NEG_INFINITY {
+                    self.add_hypothesis(&mut finished, tokens, score);
+                }
+            }
+        }
+        finished
+    }
+
+    /// Adds a hypothesis to the best ones found so far, keeping the `num_beams` best, best first.
+    ///
+    /// # Arguments
+    ///
+    /// * `finished` - The best hypotheses so far.
+    /// * `tokens` - Generated tokens of the new hypothesis.
+    /// * `log_prob` - Sum of the log-probabilities of the tokens.
+    fn add_hypothesis(&self, finished: &mut Vec<Hypothesis>, tokens: Vec<i32>, log_prob: f32) {
+        let score = log_prob / (tokens.len() as f32).powf(self.length_penalty);
+        let index = finished.partition_point(|hypothesis| hypothesis.score >= score);
+        if index < self.num_beams {
+            finished.insert(index, Hypothesis { tokens, score });
+            finished.truncate(self.num_beams);
+        }
+    }
+
+    /// Checks whether the search can stop before the token budget runs out.
+    ///
+    /// # Arguments
+    ///
+    /// * `finished` - The best finished hypotheses so far, best first.
+    /// * `best_running` - Log-probability of the best running beam.
+    /// * `length` - Number of tokens of the running beams.
+    fn is_done(&self, finished: &[Hypothesis], best_running: f32, length: usize) -> bool {
+        if finished.len() < self.num_beams {
+            return false;
+        }
+        if self.early_stopping {
+            return true;
+        }
+        // Like transformers, assume the best running beam only gets worse from here
+        let best_possible = best_running / (length as f32).powf(self.length_penalty);
+        finished[finished.len() - 1].score >= best_possible
+    }
+}
+
+/// Contrastive search decoding: picks, among the `top_k` most likely tokens, the one trading off
+/// its probability against how similar its hidden state is to the ones of the previous tokens.
+///
+/// This is deterministic like greedy decoding, without its tendency to repeat itself. The `top_k`
+/// candidates of a step are evaluated together on the rows of an `InferenceSession`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContrastiveSearch {
+    /// Number of candidate tokens at every step.
+    pub top_k: usize,
+
+    /// Weight of the degeneration penalty, 0 is greedy decoding.
+    pub alpha: f32,
+
+    /// Token that stops the generation, if any.
+    pub eos_token: Option<i32>,
+}
+
+impl ContrastiveSearch {
+    /// Creates a contrastive search with the settings recommended by its paper.
+    ///
+    /// # Arguments
+    ///
+    /// * `eos_token` - Token that stops the generation, if any.
+    ///
+    /// # Returns
+    ///
+    /// A new `ContrastiveSearch` with `top_k` 4 and `alpha` 0.6.
+    pub fn new(eos_token: Option<i32>) -> Self {
+        ContrastiveSearch {
+            top_k: 4,
+            alpha: 0.6,
+            eos_token,
+        }
+    }
+
+    /// Decodes a continuation of the prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to decode with.
+    /// * `prompt` - Tokens of the prompt, at least one and at most `max_seq_len` of the model.
+    /// * `max_new_tokens` - Maximum number of generated tokens. The generation also stops when the
+    ///   sequence reaches `max_seq_len`, like `generate` does.
+    ///
+    /// # Returns
+    ///
+    /// The generated tokens, without the prompt. They end with the EOS token if it was generated.
+    pub fn generate(&self, model: &GPT2, prompt: &[i32], max_new_tokens: usize) -> Vec<i32> {
+        let K = self.top_k;
+        let V = model.config.vocab_size;
+        let C = model.config.channels;
+        let maxT = model.config.max_seq_len;
+        assert!(
+            K > 0 && K <= V,
+            "expected between 1 and V candidates, got {}",
+            K
+        );
+        assert!(
+            !prompt.is_empty() && prompt.len() <= maxT,
+            "the prompt must have between 1 and {} tokens, got {}",
+            maxT,
+            prompt.len()
+        );
+        let max_new_tokens = max_new_tokens.min(maxT - prompt.len());
+
+        let mut session = InferenceSession::new(model, K, prompt.len() + max_new_tokens);
+        session.forward(&prompt.repeat(K));
+        // Hidden states of all the tokens so far, for the degeneration penalty
+        let mut context: Vec<f32> = session.hidden_states()[..prompt.len() * C].to_vec();
+        let mut tokens = Vec::new();
+
+        for _ in 0..max_new_tokens {
+            // All the rows hold the same sequence, every candidate goes to its own row
+            let probs = softmax(&session.logits()[..V]);
+            let candidates: Vec<i32> = top_indices(&probs, K)
+                .into_iter()
+                .map(|token| token as i32)
+                .collect();
+            session.forward(&candidates);
+
+            let hidden = session.hidden_states();
+            let mut best = (f32::NEG_INFINITY, 0);
+            for (b, &token) in candidates.iter().enumerate() {
+                let h = &hidden[b * C..(b + 1) * C];
+                let max_similarity = context
+                    .chunks_exact(C)
+                    .map(|previous| cosine_similarity(h, previous))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                let score =
+                    (1.0 - self.alpha) * probs[token as usize] - self.alpha * max_similarity;
+                if score > best.0 {
+                    best = (score, b);
+                }
+            }
+
+            let (_, b) = best;
+            tokens.push(candidates[b]);
+            context.extend_from_slice(&hidden[b * C..(b + 1) * C]);
+            if Some(candidates[b]) == self.eos_token {
+                break;
+            }
+            session.reorder(&vec![b; K]);
+        }
+        tokens
+    }
+}
+
+/// Computes the log-softmax of the logits.
+///
+/// # Arguments
+///
+/// * `logits` - The logits, without padding.
+///
+/// # Returns
+///
+/// The log-probabilities.
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let maxval = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&logit| (logit - maxval).exp()).sum();
+    let log_sum = maxval + sum.ln();
+    logits.iter().map(|&logit| logit - log_sum).collect()
+}
+
+/// Computes the softmax of the logits.
+///
+/// # Arguments
+///
+/// * `logits` - The logits, without padding.
+///
+/// # Returns
+///
+/// The probabilities.
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    log_softmax(logits).into_iter().map(f32::exp).collect()
+}
+
+/// Returns the indices of the `k` largest values, largest first, ties in index order.
+///
+/// # Arguments
+///
+/// * `values` - The values to rank.
+/// * `k` - Number of indices to return, at most `values.len()`.
+fn top_indices(values: &[f32], k: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..values.len()).collect();
+    let k = k.min(values.len());
+    if k < values.len() {
+        indices.select_nth_unstable_by(k, |&a, &b| values[b].total_cmp(&values[a]).then(a.cmp(&b)));
+        indices.truncate(k);
+    }
+    indices.sort_by(|&a, &b| values[b].total_cmp(&values[a]).then(a.cmp(&b)));
+    indices
+}
+
+/// Computes the cosine similarity of two vectors.
+///
+/// # Arguments
+///
+/// * `a` - First vector.
+/// * `b` - Second vector, of the same length.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let mut dot = 0.0;
+    let mut norm_a = 0.0;
+    let mut norm_b = 0.0;
+    for (&x, &y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt()).max(1e-8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt2::GPT2Config;
+
+    const PROMPT: [i32; 3] = [1, 2, 3];
+
+    /// A tiny model with 16 tokens, small enough to enumerate the continuations.
+    fn small_model() -> GPT2 {
+        GPT2::from_config(GPT2Config::tiny().with_vocab_size(16), 42).unwrap()
+    }
+
+    /// Log-probabilities of the token following `tokens` after the prompt.
+    fn next_log_probs(model: &GPT2, tokens: &[i32]) -> Vec<f32> {
+        let sequence = [&PROMPT[..], tokens].concat();
+        let mut session = InferenceSession::new(model, 1, sequence.len());
+        session.forward(&sequence);
+        log_softmax(&session.logits()[..model.config.vocab_size])
+    }
+
+    /// Sum of the log-probabilities of `tokens` after the prompt.
+    fn log_prob(model: &GPT2, tokens: &[i32]) -> f32 {
+        (0..tokens.len())
+            .map(|i| next_log_probs(model, &tokens[..i])[tokens[i] as usize])
+            .sum()
+    }
+
+    /// Checks that the hypotheses are best first and scored from their log-probabilities.
+    fn assert_scored(model: &GPT2, search: &BeamSearch, hypotheses: &[Hypothesis]) {
+        for hypothesis in hypotheses {
+            let length = hypothesis.tokens.len() as f32;
+            let expected = log_prob(model, &hypothesis.tokens) / length.powf(search.length_penalty);
+            assert!((hypothesis.score - expected).abs() < 1e-4, "{:?} vs {}", hypothesis, expected);
+        }
+        assert!(hypotheses.windows(2).all(|pair| pair[0].score >= pair[1].score));
+    }
+
+    #[test]
+    fn beam_search_keeps_the_best_sequences() {
+        let mut model = small_model();
+        let K = 3;
+
+        // After 2 steps, the beams are the best continuations of the K best first tokens
+        let first = next_log_probs(&model, &[]);
+        let mut expected = Vec::new();
+        for first_token in top_indices(&first, K) {
+            let second = next_log_probs(&model, &[first_token as i32]);
+            for (second_token, second_log_prob) in second.iter().enumerate() {
+                let tokens = vec![first_token as i32, second_token as i32];
+                expected.push((first[first_token] + second_log_prob, tokens));
+            }
+        }
+        expected.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let search = BeamSearch::new(K, None);
+        let hypotheses = search.generate(&model, &PROMPT, 2);
+        assert_eq!(hypotheses.len(), K);
+        for (hypothesis, (log_prob, tokens)) in hypotheses.iter().zip(&expected) {
+            assert_eq!(&hypothesis.tokens, tokens);
+            assert!((hypothesis.score - log_prob / 2.0).abs() < 1e-4);
+        }
+        assert_scored(&model, &search, &hypotheses);
+
+        // A single beam is greedy decoding
+        let greedy = BeamSearch::new(1, None).generate(&model, &PROMPT, 1);
+        assert_eq!(greedy[0].tokens, [top_indices(&first, 1)[0] as i32]);
+        unsafe { model.free() };
+    }
+
+    #[test]
+    fn eos_finishes_a_hypothesis() {
+        let mut model = small_model();
+        let ranked = top_indices(&next_log_probs(&model, &[]), 2);
+
+        // The most likely first token is EOS: nothing can beat it
+        let eos = ranked[0] as i32;
+        for early_stopping in [false, true] {
+            let search = BeamSearch {
+                early_stopping,
+                ..BeamSearch::new(1, Some(eos))
+            };
+            let hypotheses = search.generate(&model, &PROMPT, 5);
+            assert_eq!(hypotheses.len(), 1);
+            assert_eq!(hypotheses[0].tokens, [eos]);
+            assert_scored(&model, &search, &hypotheses);
+        }
+
+        // The second most likely one is EOS: hypotheses of several lengths compete
+        for length_penalty in [-1.0, 0.0, 1.0, 2.0] {
+            let search = BeamSearch {
+                length_penalty,
+                ..BeamSearch::new(3, Some(ranked[1] as i32))
+            };
+            let hypotheses = search.generate(&model, &PROMPT, 4);
+            assert_eq!(hypotheses.len(), 3);
+            for hypothesis in &hypotheses {
+                let ends_with_eos = hypothesis.tokens.last() == Some(&(ranked[1] as i32));
+                assert!(ends_with_eos || hypothesis.tokens.len() == 4, "{:?}", hypothesis);
+                assert!(!hypothesis.tokens[..hypothesis.tokens.len() - 1].contains(&(ranked[1] as i32)));
+            }
+            assert_scored(&model, &search, &hypotheses);
+        }
+        unsafe { model.free() };
+    }
+
+    #[test]
+    fn early_stopping_does_not_wait_for_better_beams() {
+        let finished = [
+            Hypothesis { tokens: vec![1], score: -2.0 },
+            Hypothesis { tokens: vec![1, 2], score: -3.0 },
+        ];
+        let search = BeamSearch::new(2, Some(0));
+        // Fewer finished hypotheses than beams
+        assert!(!search.is_done(&finished[..1], -8.0, 2));
+        // The best running beam could still reach -4 / 2 and beat the worst finished one
+        assert!(!search.is_done(&finished, -4.0, 2));
+        assert!(search.is_done(&finished, -8.0, 2));
+        let early = BeamSearch {
+            early_stopping: true,
+            ..search
+        };
+        assert!(early.is_done(&finished, -4.0, 2));
+
+        // Only the best `num_beams` hypotheses are kept, scored with the length penalty
+        let mut kept = Vec::new();
+        let search = BeamSearch {
+            length_penalty: 2.0,
+            ..search
+        };
+        search.add_hypothesis(&mut kept, vec![1, 2], -4.0);
+        search.add_hypothesis(&mut kept, vec![3], -2.0);
+        search.add_hypothesis(&mut kept, vec![4, 5], -2.0);
+        assert_eq!(
+            kept,
+            [
+                Hypothesis { tokens: vec![4, 5], score: -0.5 },
+                Hypothesis { tokens: vec![1, 2], score: -1.0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn decoders_stop_at_the_context_length() {
+        let mut model = small_model();
+        let maxT = model.config.max_seq_len;
+        let prompt: Vec<i32> = (0..maxT - 1).map(|t| (t % 16) as i32).collect();
+
+        let hypotheses = BeamSearch::new(2, None).generate(&model, &prompt, 10);
+        assert!(hypotheses.iter().all(|hypothesis| hypothesis.tokens.len() == 1));
+        assert_eq!(ContrastiveSearch::new(None).generate(&model, &prompt, 10).len(), 1);
+
+        let full: Vec<i32> = (0..maxT).map(|t| (t % 16) as i32).collect();
+        assert!(BeamSearch::new(2, None).generate(&model, &full, 10).is_empty());
+        assert!(ContrastiveSearch::new(None).generate(&model, &full, 10).is_empty());
+        unsafe { model.free() };
+    }
+}
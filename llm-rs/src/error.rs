@@ -0,0 +1,118 @@
+use std::fmt;
+use std::io;
+
+/// Errors returned while loading checkpoints, token files, tokenizers and datasets, or decoding
+/// tokens.
+#[derive(Debug)]
+pub enum Error {
+    /// The file could not be opened or read.
+    Io(io::Error),
+
+    /// The file does not start with the expected magic number.
+    BadMagic {
+        /// Magic number of the expected file format.
+        expected: i32,
+        /// Magic number found in the file.
+        found: i32,
+    },
+
+    /// The file format version is not supported.
+    BadVersion(i32),
+
+    /// The header values contradict each other or cannot describe a valid model.
+    InconsistentHeader(String),
+
+    /// The file is shorter than what its header (or the requested shapes) require.
+    Truncated {
+        /// Number of bytes needed.
+        expected: u64,
+        /// Number of bytes available.
+        actual: u64,
+    },
+
+    /// The safetensors file could not be parsed or written.
+    Safetensors(safetensors::SafeTensorError),
+
+    /// A tensor the model needs is not in the file.
+    MissingTensor(String),
+
+    /// A tensor does not have the shape the model expects.
+    BadShape {
+        /// Name of the tensor.
+        name: String,
+        /// Shape the model expects.
+        expected: Vec<usize>,
+        /// Shape found in the file.
+        found: Vec<usize>,
+    },
+
+    /// A tensor is stored with an element type that cannot be converted to fp32.
+    UnsupportedDtype(String),
+
+    /// Decoded tokens are not valid UTF-8.
+    InvalidUtf8(Vec<u8>),
+
+    /// A document of a dataset cannot be parsed.
+    InvalidInput(String),
+}
+
+/// Result type used by the loaders of this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::BadMagic { expected, found } => {
+                write!(f, "bad magic number: expected {}, found {}", expected, found)
+            }
+            Error::BadVersion(version) => write!(
+                f,
+                "unsupported version {}\n---> HINT: try to re-run `python train_gpt2.py`",
+                version
+            ),
+            Error::InconsistentHeader(reason) => write!(f, "inconsistent header: {}", reason),
+            Error::Truncated { expected, actual } => write!(
+                f,
+                "truncated file: expected {} bytes, found {}",
+                expected, actual
+            ),
+            Error::Safetensors(err) => write!(f, "safetensors error: {}", err),
+            Error::MissingTensor(name) => write!(f, "missing tensor {}", name),
+            Error::BadShape {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "tensor {} has shape {:?}, expected {:?}",
+                name, found, expected
+            ),
+            Error::UnsupportedDtype(dtype) => write!(f, "unsupported tensor dtype {}", dtype),
+            Error::InvalidUtf8(bytes) => write!(f, "invalid UTF-8 bytes {:02x?}", bytes),
+            Error::InvalidInput(reason) => write!(f, "invalid input: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Safetensors(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<safetensors::SafeTensorError> for Error {
+    fn from(err: safetensors::SafeTensorError) -> Self {
+        Error::Safetensors(err)
+    }
+}
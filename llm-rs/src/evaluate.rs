@@ -0,0 +1,110 @@
+use std::slice;
+
+use crate::dataloader::DataLoader;
+use crate::error::Result;
+use crate::gpt2::{GPT2, IGNORE_INDEX};
+use crate::tokenizer::Tokenizer;
+
+/// Losses of a model over a dataset, from `evaluate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    /// Number of batches evaluated.
+    pub num_batches: usize,
+
+    /// Number of target tokens evaluated, not counting the `IGNORE_INDEX` ones.
+    pub num_tokens: usize,
+
+    /// Mean cross-entropy loss of the target tokens, in nats. 0 when there is none.
+    pub mean_loss: f64,
+
+    /// Perplexity, the exponential of the mean loss.
+    pub perplexity: f64,
+
+    /// Total loss divided by the number of bytes of the target tokens, in bits. Only known with a
+    /// tokenizer.
+    pub bits_per_byte: Option<f64>,
+}
+
+/// Computes the loss of a model on every batch of a dataset, exactly once.
+///
+/// # Arguments
+///
+/// * `model` - The model to evaluate.
+/// * `loader` - The dataset. It is read from its first batch, without wrapping, and reset
+///   afterwards. Its `doc_starts` are used like in training.
+/// * `tokenizer` - Tokenizer to count the bytes of the target tokens for `bits_per_byte`. The EOT
+///   token counts as no bytes since it is not part of the text.
+/// * `max_batches` - Stops after this many batches, `None` evaluates all of them.
+///
+/// # Returns
+///
+/// The mean loss weighted by tokens, its perplexity and bits per byte, or an error if the dataset
+/// cannot be read.
+///
+/// # Note
+///
+/// Only full windows of B*T+1 tokens are evaluated: the last B*T tokens or less of each file are
+/// left out, like in training.
+pub fn evaluate(
+    model: &mut GPT2,
+    loader: &mut DataLoader,
+    tokenizer: Option<&Tokenizer>,
+    max_batches: Option<usize>,
+) -> Result<Evaluation> {
+    let B = loader.B;
+    let T = loader.T;
+    let wrap = loader.wrap;
+    loader.wrap = false;
+    loader.reset();
+
+    let mut num_batches = 0;
+    let mut num_tokens = 0;
+    let mut total_loss = 0.0f64;
+    let mut num_bytes = 0usize;
+    while max_batches.is_none_or(|max_batches| num_batches < max_batches) {
+        match loader.next_batch() {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                loader.wrap = wrap;
+                loader.reset();
+                return Err(err);
+            }
+        }
+        model.forward_with_documents(loader.inputs, loader.targets, loader.doc_starts, B, T);
+        unsafe {
+            // The loss of the ignored targets is 0
+            let losses = slice::from_raw_parts(model.acts.losses.ptr, B * T);
+            total_loss += losses.iter().map(|&loss| loss as f64).sum::<f64>();
+            let targets = slice::from_raw_parts(loader.targets.ptr, B * T);
+            let targets = targets.iter().filter(|&&token| token != IGNORE_INDEX);
+            num_tokens += targets.clone().count();
+            if let Some(tokenizer) = tokenizer {
+                num_bytes += targets
+                    .filter(|&&token| Some(token as u32) != tokenizer.eot_token())
+                    .map(|&token| tokenizer.decode_bytes(token as u32).len())
+                    .sum::<usize>();
+            }
+        }
+        num_batches += 1;
+    }
+
+    loader.wrap = wrap;
+    loader.reset();
+
+    // No batch read or every target ignored leaves nothing to average
+    let mean_loss = if num_tokens == 0 {
+        0.0
+    } else {
+        total_loss / num_tokens as f64
+    };
+    Ok(Evaluation {
+        num_batches,
+        num_tokens,
+        mean_loss,
+        perplexity: mean_loss.exp(),
+        bits_per_byte: tokenizer
+            .filter(|_| num_bytes > 0)
+            .map(|_| total_loss / (num_bytes as f64 * std::f64::consts::LN_2)),
+    })
+}
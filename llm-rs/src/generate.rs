@@ -0,0 +1,324 @@
+use crate::gpt2::{InferenceSession, GPT2};
+use crate::sampler::Sampler;
+use crate::tokenizer::{StreamDecoder, Tokenizer};
+
+/// When `generate` stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerateOptions {
+    /// Maximum number of generated tokens.
+    pub max_new_tokens: usize,
+
+    /// Tokens that end the generation, e.g. the EOT token. They are not yielded.
+    pub stop_tokens: Vec<i32>,
+
+    /// Strings that end the generation as soon as they appear in the generated text. The text of
+    /// the token completing one is cut right before it, but when a stop string spans several tokens
+    /// its start has already been yielded with the previous ones. They must not be empty.
+    pub stop_strings: Vec<String>,
+}
+
+impl GenerateOptions {
+    /// Creates options that only stop after `max_new_tokens` tokens.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_new_tokens` - Maximum number of generated tokens.
+    ///
+    /// # Returns
+    ///
+    /// New `GenerateOptions` without stop tokens or strings.
+    pub fn new(max_new_tokens: usize) -> Self {
+        GenerateOptions {
+            max_new_tokens,
+            stop_tokens: Vec::new(),
+            stop_strings: Vec::new(),
+        }
+    }
+}
+
+/// Iterator over the tokens generated by `generate`.
+pub struct Generation<'a> {
+    /// Cached state of the sequence.
+    session: InferenceSession<'a>,
+
+    /// Decodes the tokens, if there is a tokenizer.
+    decoder: Option<StreamDecoder<'a>>,
+
+    /// Picks the tokens.
+    sampler: &'a mut Sampler,
+
+    /// When to stop.
+    options: GenerateOptions,
+
+    /// The prompt followed by the tokens generated so far.
+    tokens: Vec<i32>,
+
+    /// Length of the prompt.
+    prompt_len: usize,
+
+    /// Text generated so far, to look for the stop strings.
+    text: String,
+
+    /// Whether a stop condition was met.
+    finished: bool,
+}
+
+/// Generates a continuation of a prompt, one token at a time.
+///
+/// # Arguments
+///
+/// * `model` - The model to generate with.
+/// * `tokenizer` - Tokenizer to decode the tokens with. Without one, the texts are empty and the
+///   stop strings never match. The text of a character split over several tokens comes with its
+///   last token, invalid UTF-8 becomes U+FFFD.
+/// * `sampler` - Picks each token from the logits. Its RNG state advances with every token.
+/// * `prompt` - Tokens of the prompt, at least one and at most `max_seq_len` of the model.
+/// * `options` - When to stop. A stop string must not be empty.
+///
+/// # Returns
+///
+/// An iterator yielding each generated token and its text as soon as it is computed. The model
+/// only runs when the next item is requested. Generation also stops when the sequence reaches
+/// `max_seq_len`, or when the sampler bans every token.
+pub fn generate<'a>(
+    model: &'a GPT2,
+    tokenizer: Option<&'a Tokenizer>,
+    sampler: &'a mut Sampler,
+    prompt: &[i32],
+    options: GenerateOptions,
+) -> Generation<'a> {
+    let maxT = model.config.max_seq_len;
+    assert!(
+        !prompt.is_empty() && prompt.len() <= maxT,
+        "the prompt must have between 1 and {} tokens, got {}",
+        maxT,
+        prompt.len()
+    );
+    assert!(
+        options.stop_strings.iter().all(|stop_string| !stop_string.is_empty()),
+        "the stop strings must not be empty"
+    );
+    let T = (prompt.len() + options.max_new_tokens).min(maxT);
+
+    Generation {
+        session: InferenceSession::new(model, 1, T),
+        decoder: tokenizer.map(|tokenizer| StreamDecoder::new(tokenizer, true)),
+        sampler,
+        options,
+        tokens: prompt.to_vec(),
+        prompt_len: prompt.len(),
+        text: String::new(),
+        finished: false,
+    }
+}
+
+impl Generation<'_> {
+    /// Returns the text generated so far.
+    ///
+    /// # Returns
+    ///
+    /// The text of the tokens yielded so far. Once the generation has stopped on a stop token or
+    /// because every token is banned, it ends with U+FFFD if the last tokens left a character
+    /// incomplete, which no item carries.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Stops the generation before the sampled token, flushing the incomplete character if any.
+    fn stop(&mut self) {
+        self.finished = true;
+        if let Some(decoder) = &mut self.decoder {
+            // Lossy decoding never fails
+            self.text.push_str(&decoder.finish().unwrap_or_default());
+        }
+    }
+}
+
+impl Iterator for Generation<'_> {
+    type Item = (i32, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let num_generated = self.tokens.len() - self.prompt_len;
+        if self.finished
+            || num_generated == self.options.max_new_tokens
+            || self.tokens.len() == self.session.max_seq_len
+        {
+            return None;
+        }
+
+        // Feed the prompt the first time, then the token yielded last
+        self.session.forward(&self.tokens[self.session.pos..]);
+        let V = self.session.model.config.vocab_size;
+        let token = self.sampler.sample(self.session.logits(), V, &self.tokens);
+        let Some(token) = token.map(|token| token as i32) else {
+            // Every token is banned
+            self.stop();
+            return None;
+        };
+        if self.options.stop_tokens.contains(&token) {
+            self.stop();
+            return None;
+        }
+        self.tokens.push(token);
+
+        let start = self.text.len();
+        if let Some(decoder) = &mut self.decoder {
+            // Lossy decoding never fails
+            self.text.push_str(&decoder.push(token as u32).unwrap_or_default());
+            let last = self.tokens.len() - self.prompt_len == self.options.max_new_tokens
+                || self.tokens.len() == self.session.max_seq_len;
+            if last {
+                self.text.push_str(&decoder.finish().unwrap_or_default());
+            }
+        }
+
+        // Only look where a stop string could end in the new text
+        let mut stop = None;
+        for stop_string in &self.options.stop_strings {
+            let mut search_start = start.saturating_sub(stop_string.len().saturating_sub(1));
+            while !self.text.is_char_boundary(search_start) {
+                search_start -= 1;
+            }
+            if let Some(index) = self.text[search_start..].find(stop_string.as_str()) {
+                let index = search_start + index;
+                stop = Some(stop.map_or(index, |stop: usize| stop.min(index)));
+            }
+        }
+        if let Some(index) = stop {
+            self.finished = true;
+            // The start of the stop string may have been yielded with the previous tokens already
+            self.text.truncate(index.max(start));
+        }
+
+        Some((token, self.text[start..].to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt2::GPT2Config;
+
+    const EOT: i32 = 256;
+
+    /// A prompt the scripted samplers never pick from, so that it does not count as EOT.
+    const PROMPT: &[i32] = &[b' ' as i32];
+
+    /// A tokenizer with the 256 bytes and EOT, and a model with the same vocabulary.
+    fn byte_model() -> (GPT2, Tokenizer) {
+        let mut token_table: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        token_table.push(b"<|endoftext|>".to_vec());
+        let tokenizer = Tokenizer::from_tokens(token_table, Vec::new(), Some(EOT as u32)).unwrap();
+        let model = GPT2::from_config(GPT2Config::tiny().with_vocab_size(257), 42).unwrap();
+        (model, tokenizer)
+    }
+
+    /// A greedy sampler picking each of `tokens` once, in order, and then EOT.
+    fn scripted_sampler(tokens: &[u8]) -> Sampler {
+        let mut sampler = Sampler::new(42);
+        sampler.temperature = 0.0;
+        sampler.frequency_penalty = 1000.0;
+        for token in 0..EOT as usize {
+            sampler.ban(token);
+        }
+        for (i, &token) in tokens.iter().enumerate() {
+            sampler.logit_bias.insert(token as usize, 100.0 * (tokens.len() - i) as f32);
+        }
+        sampler
+    }
+
+    /// Runs a generation, returning the items and the final text.
+    fn run(options: GenerateOptions, tokens: &[u8]) -> (Vec<(i32, String)>, String) {
+        let (mut model, tokenizer) = byte_model();
+        let mut sampler = scripted_sampler(tokens);
+        let mut generation = generate(&model, Some(&tokenizer), &mut sampler, PROMPT, options);
+        let items: Vec<_> = generation.by_ref().collect();
+        let text = generation.text().to_string();
+        drop(generation);
+        unsafe { model.free() };
+        (items, text)
+    }
+
+    fn item(token: u8, text: &str) -> (i32, String) {
+        (token as i32, text.to_string())
+    }
+
+    #[test]
+    fn stop_tokens_end_the_generation() {
+        let (items, text) = run(GenerateOptions::new(5), b"abcd");
+        assert_eq!(items.len(), 5);
+        assert_eq!(items[4], (EOT, "<|endoftext|>".to_string()));
+        assert_eq!(text, "abcd<|endoftext|>");
+
+        // The stop token is not yielded
+        let options = GenerateOptions {
+            stop_tokens: vec![EOT, b'c' as i32],
+            ..GenerateOptions::new(10)
+        };
+        let (items, text) = run(options, b"abcd");
+        assert_eq!(items, [item(b'a', "a"), item(b'b', "b")]);
+        assert_eq!(text, "ab");
+
+        let (items, _) = run(GenerateOptions::new(3), b"abcd");
+        assert_eq!(items.len(), 3);
+    }
+
+    #[test]
+    fn stop_token_flushes_an_incomplete_character() {
+        // The first byte of an emoji, then EOT
+        let options = GenerateOptions {
+            stop_tokens: vec![EOT],
+            ..GenerateOptions::new(10)
+        };
+        let (items, text) = run(options, &[0xF0]);
+        assert_eq!(items, [item(0xF0, "")]);
+        assert_eq!(text, "\u{FFFD}");
+    }
+
+    #[test]
+    fn stop_strings_truncate_the_text() {
+        let with_stop_strings = |stop_strings: &[&str]| GenerateOptions {
+            stop_strings: stop_strings.iter().map(|stop_string| stop_string.to_string()).collect(),
+            ..GenerateOptions::new(5)
+        };
+
+        // The token completing the stop string is yielded without its text
+        let (items, text) = run(with_stop_strings(&["c"]), b"abcd");
+        assert_eq!(items, [item(b'a', "a"), item(b'b', "b"), item(b'c', "")]);
+        assert_eq!(text, "ab");
+
+        // The start of a stop string spanning several tokens was already yielded
+        let (items, text) = run(with_stop_strings(&["bc"]), b"abcd");
+        assert_eq!(items, [item(b'a', "a"), item(b'b', "b"), item(b'c', "")]);
+        assert_eq!(text, "ab");
+
+        // The first stop string to appear wins, and a stop string can end in the text of EOT
+        let (items, text) = run(with_stop_strings(&["d<|end", "bc"]), b"abcd");
+        assert_eq!(items.len(), 3);
+        assert_eq!(text, "ab");
+        let (items, text) = run(with_stop_strings(&["d<|end", "x"]), b"abcd");
+        assert_eq!(items.len(), 5);
+        assert_eq!(items[4], (EOT, String::new()));
+        assert_eq!(text, "abcd");
+
+        // Without a tokenizer, the texts are empty and the stop strings never match
+        let (mut model, _) = byte_model();
+        let mut sampler = scripted_sampler(b"abcd");
+        let items: Vec<_> = generate(&model, None, &mut sampler, PROMPT, with_stop_strings(&["a"])).collect();
+        assert_eq!(items.len(), 5);
+        assert!(items.iter().all(|(_, text)| text.is_empty()));
+        unsafe { model.free() };
+    }
+
+    #[test]
+    #[should_panic(expected = "the stop strings must not be empty")]
+    fn empty_stop_string_is_rejected() {
+        let (model, tokenizer) = byte_model();
+        let mut sampler = Sampler::new(42);
+        let options = GenerateOptions {
+            stop_strings: vec![String::new()],
+            ..GenerateOptions::new(10)
+        };
+        generate(&model, Some(&tokenizer), &mut sampler, PROMPT, options);
+    }
+}
@@ -77,6 +77,12 @@ pub struct ActivationTensors {
     pub losses: SendPtr<f32>,
 }
 
+impl Default for ActivationTensors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ActivationTensors {
     /// Creates a new ActivationTensors instance.
     ///
@@ -121,6 +127,11 @@ impl ActivationTensors {
     /// # Returns
     ///
     /// * Pointer to the allocated memory for activations.
+    ///
+    /// # Safety
+    ///
+    /// The returned memory is not freed when the tensors are dropped, it must be freed with the
+    /// layout of the sum of `act_sizes`.
     pub unsafe fn alloc_and_point_activations(
         &mut self,
         act_sizes: &[usize; NUM_ACTIVATION_TENSORS],
@@ -0,0 +1,467 @@
+use core::slice;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::path::Path;
+
+use memmap2::Mmap;
+use safetensors::tensor::{TensorInfo, TensorView};
+use safetensors::{Dtype, SafeTensors};
+
+use super::{GPT2Config, GPT2, NUM_PARAMETER_TENSORS};
+use crate::error::{Error, Result};
+use crate::train_state::{write_atomically, write_path_atomically};
+
+/// HuggingFace name of each parameter tensor, in `param_sizes` order, whether it is stored per
+/// layer (as `h.{layer}.<name>`) and whether it is a `Conv1D` weight, stored transposed.
+const HF_NAMES: [(&str, bool, bool); NUM_PARAMETER_TENSORS] = [
+    ("wte.weight", false, false),
+    ("wpe.weight", false, false),
+    ("ln_1.weight", true, false),
+    ("ln_1.bias", true, false),
+    ("attn.c_attn.weight", true, true),
+    ("attn.c_attn.bias", true, false),
+    ("attn.c_proj.weight", true, true),
+    ("attn.c_proj.bias", true, false),
+    ("ln_2.weight", true, false),
+    ("ln_2.bias", true, false),
+    ("mlp.c_fc.weight", true, true),
+    ("mlp.c_fc.bias", true, false),
+    ("mlp.c_proj.weight", true, true),
+    ("mlp.c_proj.bias", true, false),
+    ("ln_f.weight", false, false),
+    ("ln_f.bias", false, false),
+];
+
+/// Returns the HuggingFace shape of (one layer of) each parameter tensor, in `param_sizes` order.
+///
+/// The matrices of the transformer blocks are HF `Conv1D` weights, stored as (in, out), i.e.
+/// transposed compared to the (out, in) layout of `ParameterTensors`. `wte` has no padding.
+///
+/// # Arguments
+///
+/// * `config` - The model configuration.
+fn hf_shapes(config: &GPT2Config) -> [Vec<usize>; NUM_PARAMETER_TENSORS] {
+    let V = config.vocab_size;
+    let maxT = config.max_seq_len;
+    let C = config.channels;
+    [
+        vec![V, C],
+        vec![maxT, C],
+        vec![C],
+        vec![C],
+        vec![C, 3 * C],
+        vec![3 * C],
+        vec![C, C],
+        vec![C],
+        vec![C],
+        vec![C],
+        vec![C, 4 * C],
+        vec![4 * C],
+        vec![4 * C, C],
+        vec![C],
+        vec![C],
+        vec![C],
+    ]
+}
+
+/// Returns the full name of a parameter tensor, for the given layer if it is stored per layer.
+///
+/// # Arguments
+///
+/// * `prefix` - Prefix of all the names, e.g. `transformer.` for a `GPT2LMHeadModel`.
+/// * `index` - Index of the tensor in `param_sizes` order.
+/// * `layer` - Layer of the tensor, ignored for the tensors shared by all layers.
+fn hf_name(prefix: &str, index: usize, layer: usize) -> String {
+    match HF_NAMES[index] {
+        (name, true, _) => format!("{}h.{}.{}", prefix, layer, name),
+        (name, false, _) => format!("{}{}", prefix, name),
+    }
+}
+
+impl GPT2 {
+    /// Creates a new GPT-2 model instance from a HuggingFace `model.safetensors` file.
+    ///
+    /// # Arguments
+    ///
+    /// * `safetensors_path` - Path to the safetensors file of a `GPT2Model` or `GPT2LMHeadModel`.
+    ///
+    /// # Returns
+    ///
+    /// A new `GPT2` model instance, or an error if the file cannot be read or is not a GPT-2 model.
+    ///
+    /// # Note
+    ///
+    /// The configuration is inferred from the tensor shapes. The number of heads cannot be, so it is
+    /// read from the `config.json` next to the weights if there is one, and assumed to be
+    /// `channels / 64` (as in all the GPT-2 sizes) otherwise. The vocabulary is padded to a multiple
+    /// of 128 like `pad_vocab` in train_gpt2.py. The weights can be stored in fp32, fp16 or bf16.
+    /// The file is memory-mapped while the weights are converted, it must not be modified meanwhile.
+    pub fn from_safetensors(safetensors_path: &Path) -> Result<Self> {
+        // Map the file instead of reading it, so that only the fp32 copy ends up in memory
+        let file = File::open(safetensors_path)?;
+        let bytes = unsafe { Mmap::map(&file)? };
+        let (header_bytes, metadata) = SafeTensors::read_metadata(&bytes)?;
+        let data = &bytes[8 + header_bytes..];
+
+        // Fine-tuned `GPT2LMHeadModel`s prefix the names, the base checkpoints do not
+        let prefix = if metadata.info("transformer.wte.weight").is_some() {
+            "transformer."
+        } else {
+            ""
+        };
+        let info = |name: &str| {
+            metadata
+                .info(name)
+                .ok_or_else(|| Error::MissingTensor(name.to_string()))
+        };
+
+        // Infer the configuration from the shapes
+        let wte = info(&hf_name(prefix, 0, 0))?;
+        let wpe = info(&hf_name(prefix, 1, 0))?;
+        if wte.shape.len() != 2 || wpe.shape.len() != 2 {
+            return Err(Error::InconsistentHeader(
+                "wte and wpe must be 2-dimensional".to_string(),
+            ));
+        }
+        let V = wte.shape[0];
+        let C = wte.shape[1];
+        let maxT = wpe.shape[0];
+        let L = (0..)
+            .take_while(|&layer| metadata.info(&hf_name(prefix, 2, layer)).is_some())
+            .count();
+        let NH = match read_num_heads(safetensors_path)? {
+            Some(num_heads) => num_heads,
+            None if C % 64 == 0 => C / 64,
+            None => {
+                return Err(Error::InconsistentHeader(format!(
+                    "cannot infer the number of heads of {} channels without a config.json",
+                    C
+                )))
+            }
+        };
+        let config = GPT2Config {
+            max_seq_len: maxT,
+            vocab_size: V,
+            padded_vocab_size: V.div_ceil(128) * 128,
+            num_layers: L,
+            num_heads: NH,
+            channels: C,
+        };
+        config.validate()?;
+
+        // Check every tensor before allocating for them
+        let shapes = hf_shapes(&config);
+        let mut tensors = Vec::with_capacity(NUM_PARAMETER_TENSORS);
+        for (index, shape) in shapes.iter().enumerate() {
+            let num_layers = if HF_NAMES[index].1 { L } else { 1 };
+            let mut layers = Vec::with_capacity(num_layers);
+            for layer in 0..num_layers {
+                let name = hf_name(prefix, index, layer);
+                let tensor_info = info(&name)?;
+                if tensor_info.shape != *shape {
+                    return Err(Error::BadShape {
+                        name,
+                        expected: shape.clone(),
+                        found: tensor_info.shape.clone(),
+                    });
+                }
+                if !matches!(tensor_info.dtype, Dtype::F32 | Dtype::F16 | Dtype::BF16) {
+                    return Err(Error::UnsupportedDtype(format!("{:?}", tensor_info.dtype)));
+                }
+                layers.push(tensor_info);
+            }
+            tensors.push(layers);
+        }
+
+        let mut model = GPT2::with_config(config);
+        unsafe {
+            model.params_memory = model.params.alloc_and_point_parameters(&model.param_sizes);
+            let mut params =
+                slice::from_raw_parts_mut(model.params_memory.ptr, model.num_parameters);
+            for (index, layers) in tensors.iter().enumerate() {
+                let (tensor, rest) = params.split_at_mut(model.param_sizes[index]);
+                params = rest;
+                if index == 0 {
+                    // The padded rows of wte are never used, keep them zero like `pad_vocab`
+                    tensor[V * C..].fill(0.0);
+                }
+                let layer_size = shapes[index].iter().product::<usize>();
+                for (layer, tensor_info) in layers.iter().enumerate() {
+                    let out = &mut tensor[layer * layer_size..(layer + 1) * layer_size];
+                    copy_tensor(tensor_info, data, out, HF_NAMES[index].2);
+                }
+            }
+        }
+
+        Ok(model)
+    }
+
+    /// Saves the model parameters to a HuggingFace `model.safetensors` file, along with the
+    /// `config.json` transformers needs to load it as a `GPT2LMHeadModel`.
+    ///
+    /// # Arguments
+    ///
+    /// * `safetensors_path` - Path of the safetensors file to write, `config.json` is written next to it.
+    ///
+    /// # Note
+    ///
+    /// The tensors use the GPT-2 names and the `Conv1D` layout, in fp32 and without the vocabulary
+    /// padding. `lm_head.weight` is tied to `wte.weight`, so it is not written.
+    ///
+    /// Both files replace any existing ones, including a `config.json` of another model in the same
+    /// directory, since it would not describe the new weights. Each of them is written to a `.tmp`
+    /// file first and renamed, so an interrupted export never leaves a truncated file.
+    pub fn save_safetensors(&self, safetensors_path: &Path) -> Result<()> {
+        let V = self.config.vocab_size;
+        let C = self.config.channels;
+        let L = self.config.num_layers;
+        let shapes = hf_shapes(&self.config);
+
+        // Convert every tensor to its HF layout first, the views borrow these buffers
+        let mut tensors = Vec::new();
+        let mut params =
+            unsafe { slice::from_raw_parts(self.params_memory.ptr, self.num_parameters) };
+        for (index, shape) in shapes.iter().enumerate() {
+            let (tensor, rest) = params.split_at(self.param_sizes[index]);
+            params = rest;
+            let (per_layer, transpose) = (HF_NAMES[index].1, HF_NAMES[index].2);
+            // Drop the padded rows of wte
+            let tensor = if index == 0 { &tensor[..V * C] } else { tensor };
+            let layer_size = shape.iter().product::<usize>();
+            for layer in 0..if per_layer { L } else { 1 } {
+                let values = &tensor[layer * layer_size..(layer + 1) * layer_size];
+                let mut bytes = Vec::with_capacity(layer_size * mem::size_of::<f32>());
+                if transpose {
+                    // (out, in) to (in, out)
+                    let (rows, cols) = (shape[0], shape[1]);
+                    for i in 0..rows {
+                        for j in 0..cols {
+                            bytes.extend_from_slice(&values[j * rows + i].to_le_bytes());
+                        }
+                    }
+                } else {
+                    for value in values {
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                tensors.push((hf_name("", index, layer), shape.clone(), bytes));
+            }
+        }
+        let views = tensors
+            .iter()
+            .map(|(name, shape, bytes)| {
+                Ok((
+                    name.as_str(),
+                    TensorView::new(Dtype::F32, shape.clone(), bytes)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // transformers only loads safetensors files tagged with their framework
+        let metadata = Some(HashMap::from([("format".to_string(), "pt".to_string())]));
+        write_path_atomically(safetensors_path, |temp_path| {
+            Ok(safetensors::serialize_to_file(views, &metadata, temp_path)?)
+        })?;
+
+        let config = serde_json::json!({
+            "architectures": ["GPT2LMHeadModel"],
+            "model_type": "gpt2",
+            "vocab_size": V,
+            "n_positions": self.config.max_seq_len,
+            "n_ctx": self.config.max_seq_len,
+            "n_embd": C,
+            "n_layer": L,
+            "n_head": self.config.num_heads,
+            "n_inner": null,
+            // llm.c uses the tanh approximation of GELU
+            "activation_function": "gelu_new",
+            "layer_norm_epsilon": 1e-5,
+            "initializer_range": 0.02,
+            "resid_pdrop": 0.1,
+            "embd_pdrop": 0.1,
+            "attn_pdrop": 0.1,
+            // GPT-2 vocabularies end with <|endoftext|>
+            "bos_token_id": V - 1,
+            "eos_token_id": V - 1,
+            "tie_word_embeddings": true,
+            "torch_dtype": "float32",
+        });
+        write_atomically(&safetensors_path.with_file_name("config.json"), |config_file| {
+            serde_json::to_writer_pretty(config_file, &config).map_err(io::Error::from)?;
+            Ok(())
+        })
+    }
+}
+
+/// Reads the number of heads from the `config.json` next to a safetensors file.
+///
+/// # Arguments
+///
+/// * `safetensors_path` - Path to the safetensors file.
+///
+/// # Returns
+///
+/// The `n_head` of the config, or `None` if there is no `config.json`.
+fn read_num_heads(safetensors_path: &Path) -> Result<Option<usize>> {
+    let config_path = safetensors_path.with_file_name("config.json");
+    if !config_path.exists() {
+        return Ok(None);
+    }
+    let config: serde_json::Value = serde_json::from_slice(&fs::read(&config_path)?)
+        .map_err(|err| Error::InconsistentHeader(format!("invalid config.json: {}", err)))?;
+    match config.get("n_head").and_then(serde_json::Value::as_u64) {
+        Some(num_heads) => Ok(Some(num_heads as usize)),
+        None => Err(Error::InconsistentHeader(
+            "config.json has no n_head".to_string(),
+        )),
+    }
+}
+
+/// Converts a tensor to fp32 into `out`, transposing the 2D matrices of the transformer blocks
+/// from the HF `Conv1D` (in, out) layout to the (out, in) layout of `ParameterTensors`.
+///
+/// # Arguments
+///
+/// * `tensor_info` - Dtype, shape and offsets of the tensor, already validated.
+/// * `data` - Data section of the safetensors file.
+/// * `out` - Destination slice, with as many elements as the tensor.
+/// * `transpose` - Whether the tensor is a `Conv1D` weight.
+fn copy_tensor(tensor_info: &TensorInfo, data: &[u8], out: &mut [f32], transpose: bool) {
+    let (start, end) = tensor_info.data_offsets;
+    let bytes = &data[start..end];
+    let values =
+        bytes
+            .chunks_exact(tensor_info.dtype.size())
+            .map(|value| match tensor_info.dtype {
+                Dtype::F32 => f32::from_le_bytes([value[0], value[1], value[2], value[3]]),
+                Dtype::F16 => f16_to_f32(u16::from_le_bytes([value[0], value[1]])),
+                // bf16 is the upper half of an fp32
+                _ => f32::from_bits((u16::from_le_bytes([value[0], value[1]]) as u32) << 16),
+            });
+
+    if transpose {
+        let (rows, cols) = (tensor_info.shape[0], tensor_info.shape[1]);
+        for (i, value) in values.enumerate() {
+            out[(i % cols) * rows + i / cols] = value;
+        }
+    } else {
+        for (out_value, value) in out.iter_mut().zip(values) {
+            *out_value = value;
+        }
+    }
+}
+
+/// Converts an fp16 value to fp32.
+///
+/// # Arguments
+///
+/// * `bits` - The bits of the fp16 value.
+///
+/// # Returns
+///
+/// The same value as an fp32.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits & 0x8000) as u32) << 16;
+    let exponent = ((bits >> 10) & 0x1F) as u32;
+    let mantissa = (bits & 0x3FF) as u32;
+    let magnitude = match exponent {
+        // Zero and subnormals: mantissa * 2^-24, exact in fp32
+        0 => (mantissa as f32 * 2f32.powi(-24)).to_bits(),
+        // Infinities and NaNs
+        0x1F => 0x7F80_0000 | (mantissa << 13),
+        _ => ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(sign | magnitude)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new empty directory for the files of a test.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("llm-rs-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Parameters of a model, copied out of its memory.
+    fn params(model: &GPT2) -> Vec<f32> {
+        unsafe { slice::from_raw_parts(model.params_memory.ptr, model.num_parameters).to_vec() }
+    }
+
+    #[test]
+    fn exported_weights_import_back() {
+        let dir = test_dir("hf-round-trip-test");
+        let path = dir.join("model.safetensors");
+        // The GPT-2 vocabulary, padded from 50257 to 50304 rows
+        let mut model = GPT2::from_config(GPT2Config::tiny(), 42).unwrap();
+        model.save_safetensors(&path).unwrap();
+
+        let mut imported = GPT2::from_safetensors(&path).unwrap();
+        assert_eq!(imported.config, model.config);
+        assert!(params(&imported).iter().zip(&params(&model)).all(|(a, e)| a.to_bits() == e.to_bits()));
+        unsafe { imported.free() };
+
+        // Without config.json, the number of heads is inferred from 64 channels per head
+        fs::remove_file(dir.join("config.json")).unwrap();
+        let mut imported = GPT2::from_safetensors(&path).unwrap();
+        let expected_config = GPT2Config {
+            num_heads: model.config.channels / 64,
+            ..model.config
+        };
+        assert_eq!(imported.config, expected_config);
+        assert!(params(&imported).iter().zip(&params(&model)).all(|(a, e)| a.to_bits() == e.to_bits()));
+        unsafe { imported.free() };
+
+        unsafe { model.free() };
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exported_tensors_have_the_hf_names_and_layout() {
+        let dir = test_dir("hf-export-test");
+        let path = dir.join("model.safetensors");
+        fs::write(dir.join("config.json"), "{}").unwrap();
+        let mut model = GPT2::from_config(GPT2Config::tiny(), 42).unwrap();
+        model.save_safetensors(&path).unwrap();
+        let (V, C, L) = (model.config.vocab_size, model.config.channels, model.config.num_layers);
+
+        let bytes = fs::read(&path).unwrap();
+        let file = SafeTensors::deserialize(&bytes).unwrap();
+        let mut names: Vec<_> = file.names().into_iter().cloned().collect();
+        names.sort();
+        let mut expected_names: Vec<_> = (0..NUM_PARAMETER_TENSORS)
+            .flat_map(|index| (0..if HF_NAMES[index].1 { L } else { 1 }).map(move |layer| hf_name("", index, layer)))
+            .collect();
+        expected_names.sort();
+        assert_eq!(names, expected_names);
+        assert_eq!(file.tensor("wte.weight").unwrap().shape(), [V, C]);
+        assert_eq!(file.tensor("h.1.mlp.c_fc.weight").unwrap().shape(), [C, 4 * C]);
+        assert_eq!(file.tensor("h.1.mlp.c_proj.weight").unwrap().shape(), [4 * C, C]);
+
+        // Conv1D weights are (in, out): element (i, j) is qkvw[j][i]
+        let qkvw = file.tensor("h.0.attn.c_attn.weight").unwrap();
+        let value = |data: &[u8], index: usize| f32::from_le_bytes(data[4 * index..4 * index + 4].try_into().unwrap());
+        let expected = unsafe { slice::from_raw_parts(model.params.qkvw.ptr, 3 * C * C) };
+        for (i, j) in [(0, 1), (1, 0), (5, 3 * C - 1), (C - 1, 7)] {
+            assert_eq!(value(qkvw.data(), i * 3 * C + j), expected[j * C + i]);
+        }
+
+        // The previous config.json is replaced, and no temporary file is left
+        let config: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("config.json")).unwrap()).unwrap();
+        assert_eq!(config["vocab_size"], V);
+        assert_eq!(config["n_positions"], model.config.max_seq_len);
+        assert_eq!(config["n_embd"], C);
+        assert_eq!(config["n_layer"], L);
+        assert_eq!(config["n_head"], model.config.num_heads);
+        let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, ["config.json", "model.safetensors"]);
+
+        unsafe { model.free() };
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+use std::ptr::null_mut;
+
+use super::passes::*;
+use super::{LoraTarget, GPT2};
+use crate::send_ptr::SendPtr;
+
+/// Incremental decoding state for a GPT-2 model.
+///
+/// The session keeps the query, key and value vectors of every position seen so far, for every
+/// layer, laid out like the `qkv` activations. Feeding new tokens only computes their own
+/// positions and attends to the cached ones, so generating a token costs O(T) instead of a full
+/// forward pass over the window.
+pub struct InferenceSession<'a> {
+    /// The model the session runs.
+    pub model: &'a GPT2,
+
+    /// Number of sequences decoded in parallel (B).
+    pub batch_size: usize,
+
+    /// Maximum number of positions of each sequence (T).
+    pub max_seq_len: usize,
+
+    /// Number of positions already in the cache.
+    pub pos: usize,
+
+    /// Query, key and value vectors of the cached positions (L, B, T, 3*C).
+    qkv_cache: Vec<f32>,
+
+    /// Logits of the last position of each sequence (B, Vp).
+    logits: Vec<f32>,
+
+    /// Probabilities of the last position of each sequence (B, Vp).
+    probs: Vec<f32>,
+
+    /// Output of the final layer normalization for the positions of the last call (B, N, C).
+    hidden: Vec<f32>,
+
+    /// Scratch activations of the new positions, reused from one call to the next.
+    scratch: Scratch,
+}
+
+/// Activations of the positions being computed, for one layer at a time.
+#[derive(Default)]
+struct Scratch {
+    /// Residual stream (B, N, C).
+    residual: Vec<f32>,
+    /// Layer normalization output (B, N, C).
+    ln: Vec<f32>,
+    /// Layer normalization means (B, N).
+    ln_mean: Vec<f32>,
+    /// Layer normalization reciprocal standard deviations (B, N).
+    ln_rstd: Vec<f32>,
+    /// Query, key and value vectors (B, N, 3*C).
+    qkv: Vec<f32>,
+    /// Attention output (B, N, C).
+    atty: Vec<f32>,
+    /// Output of the attention or MLP projection (B, N, C).
+    proj: Vec<f32>,
+    /// Residual stream after the attention block (B, N, C).
+    residual2: Vec<f32>,
+    /// Fully connected layer output (B, N, 4*C).
+    fch: Vec<f32>,
+    /// GELU output (B, N, 4*C).
+    fch_gelu: Vec<f32>,
+    /// Down projection output of the low-rank adapters (B, N, R).
+    lora: Vec<f32>,
+}
+
+impl Scratch {
+    /// Makes room for `BN` positions.
+    ///
+    /// # Arguments
+    ///
+    /// * `BN` - Number of positions, over all the sequences.
+    /// * `C` - Number of channels.
+    /// * `R` - Rank of the low-rank adapters, 0 without them.
+    fn resize(&mut self, BN: usize, C: usize, R: usize) {
+        for (buffer, size) in [
+            (&mut self.residual, BN * C),
+            (&mut self.ln, BN * C),
+            (&mut self.ln_mean, BN),
+            (&mut self.ln_rstd, BN),
+            (&mut self.qkv, BN * 3 * C),
+            (&mut self.atty, BN * C),
+            (&mut self.proj, BN * C),
+            (&mut self.residual2, BN * C),
+            (&mut self.fch, BN * 4 * C),
+            (&mut self.fch_gelu, BN * 4 * C),
+            (&mut self.lora, BN * R),
+        ] {
+            buffer.resize(size, 0.0);
+        }
+    }
+}
+
+/// Wraps a buffer into a `SendPtr` for the passes.
+fn ptr(buffer: &mut [f32]) -> SendPtr<f32> {
+    SendPtr::new(buffer.as_mut_ptr())
+}
+
+impl<'a> InferenceSession<'a> {
+    /// Creates an empty inference session.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to run, with its parameters loaded.
+    /// * `B` - Number of sequences decoded in parallel.
+    /// * `T` - Maximum number of positions of each sequence, at most `max_seq_len` of the model.
+    ///
+    /// # Returns
+    ///
+    /// A new `InferenceSession` with an empty cache.
+    pub fn new(model: &'a GPT2, B: usize, T: usize) -> Self {
+        assert!(!model.params_memory.ptr.is_null(), "Error: model was not initialized properly.");
+        assert!(
+            T <= model.config.max_seq_len,
+            "sequence length {} exceeds the maximum of the model, {}",
+            T,
+            model.config.max_seq_len
+        );
+        let L = model.config.num_layers;
+        let C = model.config.channels;
+        let Vp = model.config.padded_vocab_size;
+
+        InferenceSession {
+            model,
+            batch_size: B,
+            max_seq_len: T,
+            pos: 0,
+            qkv_cache: vec![0.0; L * B * T * 3 * C],
+            logits: vec![0.0; B * Vp],
+            probs: vec![0.0; B * Vp],
+            hidden: Vec::new(),
+            scratch: Scratch::default(),
+        }
+    }
+
+    /// Empties the cache, to start new sequences.
+    pub fn reset(&mut self) {
+        self.pos = 0;
+    }
+
+    /// Logits of the last position fed to each sequence (B, Vp).
+    pub fn logits(&self) -> &[f32] {
+        &self.logits
+    }
+
+    /// Probabilities of the last position fed to each sequence (B, Vp). The padded entries are zero.
+    pub fn probs(&self) -> &[f32] {
+        &self.probs
+    }
+
+    /// Hidden states of the positions fed by the last call to `forward` (B, N, C), i.e. the output of
+    /// the final layer normalization the logits are computed from.
+    pub fn hidden_states(&self) -> &[f32] {
+        &self.hidden
+    }
+
+    /// Rearranges the sequences, e.g. to follow the beams kept by a beam search.
+    ///
+    /// # Arguments
+    ///
+    /// * `rows` - For each sequence, the sequence it continues from. The same one can be repeated.
+    pub fn reorder(&mut self, rows: &[usize]) {
+        let B = self.batch_size;
+        let T = self.max_seq_len;
+        let C = self.model.config.channels;
+        let Vp = self.model.config.padded_vocab_size;
+        assert_eq!(rows.len(), B, "expected one source row per sequence");
+        assert!(rows.iter().all(|&row| row < B));
+
+        /// Copies `row_size` elements at the start of each row of `stride` elements.
+        fn reorder_rows(buffer: &mut [f32], rows: &[usize], stride: usize, row_size: usize) {
+            let source = buffer.to_vec();
+            for (b, &row) in rows.iter().enumerate() {
+                buffer[b * stride..b * stride + row_size]
+                    .copy_from_slice(&source[row * stride..row * stride + row_size]);
+            }
+        }
+
+        for l_qkv_cache in self.qkv_cache.chunks_exact_mut(B * T * 3 * C) {
+            reorder_rows(l_qkv_cache, rows, T * 3 * C, self.pos * 3 * C);
+        }
+        reorder_rows(&mut self.logits, rows, Vp, Vp);
+        reorder_rows(&mut self.probs, rows, Vp, Vp);
+        let row_size = self.hidden.len() / B;
+        reorder_rows(&mut self.hidden, rows, row_size, row_size);
+    }
+
+    /// Appends tokens to every sequence and computes the next token distribution.
+    ///
+    /// The prompt is prefilled with a single call, then each generated token is fed back with
+    /// a call of one token per sequence.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The new tokens (B, N), the same number for every sequence.
+    ///
+    /// # Returns
+    ///
+    /// The logits of the last new position of each sequence (B, Vp), also available with `logits`.
+    pub fn forward(&mut self, tokens: &[i32]) -> &[f32] {
+        let B = self.batch_size;
+        let T = self.max_seq_len;
+        let V = self.model.config.vocab_size;
+        let Vp = self.model.config.padded_vocab_size;
+        let L = self.model.config.num_layers;
+        let NH = self.model.config.num_heads;
+        let C = self.model.config.channels;
+
+        assert!(
+            !tokens.is_empty() && tokens.len().is_multiple_of(B),
+            "expected N > 0 tokens for each of the {} sequences, got {} tokens",
+            B,
+            tokens.len()
+        );
+        let N = tokens.len() / B;
+        let pos = self.pos;
+        assert!(
+            pos + N <= T,
+            "the session is full: {} cached positions, {} new ones, room for {}",
+            pos,
+            N,
+            T
+        );
+        assert!(tokens.iter().all(|&token| token >= 0 && token < V as i32));
+
+        let params = &self.model.params;
+        let lora = self.model.lora.as_ref();
+        let scratch = &mut self.scratch;
+        scratch.resize(B * N, C, lora.map_or(0, |lora| lora.rank));
+
+        unsafe {
+            // Positions pos..pos+N of the position embeddings
+            encoder_forward(
+                ptr(&mut scratch.residual),
+                SendPtr::new(tokens.as_ptr() as *mut i32),
+                params.wte,
+                SendPtr::new(params.wpe.ptr.add(pos * C)),
+                SendPtr::new(null_mut()),
+                B,
+                N,
+                C,
+            );
+
+            for l in 0..L {
+                // Get the pointers of the weights for this layer
+                let l_ln1w = SendPtr::new(params.ln1w.ptr.add(l * C));
+                let l_ln1b = SendPtr::new(params.ln1b.ptr.add(l * C));
+                let l_qkvw = SendPtr::new(params.qkvw.ptr.add(l * 3 * C * C));
+                let l_qkvb = SendPtr::new(params.qkvb.ptr.add(l * 3 * C));
+                let l_attprojw = SendPtr::new(params.attprojw.ptr.add(l * C * C));
+                let l_attprojb = SendPtr::new(params.attprojb.ptr.add(l * C));
+                let l_ln2w = SendPtr::new(params.ln2w.ptr.add(l * C));
+                let l_ln2b = SendPtr::new(params.ln2b.ptr.add(l * C));
+                let l_fcw = SendPtr::new(params.fcw.ptr.add(l * 4 * C * C));
+                let l_fcb = SendPtr::new(params.fcb.ptr.add(l * 4 * C));
+                let l_fcprojw = SendPtr::new(params.fcprojw.ptr.add(l * C * 4 * C));
+                let l_fcprojb = SendPtr::new(params.fcprojb.ptr.add(l * C));
+                let l_qkv_cache = &mut self.qkv_cache[l * B * T * 3 * C..(l + 1) * B * T * 3 * C];
+
+                let residual = ptr(&mut scratch.residual);
+                let ln = ptr(&mut scratch.ln);
+                let ln_mean = ptr(&mut scratch.ln_mean);
+                let ln_rstd = ptr(&mut scratch.ln_rstd);
+                let qkv = ptr(&mut scratch.qkv);
+                let atty = ptr(&mut scratch.atty);
+                let proj = ptr(&mut scratch.proj);
+                let residual2 = ptr(&mut scratch.residual2);
+                let fch = ptr(&mut scratch.fch);
+                let fch_gelu = ptr(&mut scratch.fch_gelu);
+                let lora_h = ptr(&mut scratch.lora);
+
+                // Adds the output of the adapter of a weight to the output of its matmul
+                let adapt = |target: LoraTarget, out: SendPtr<f32>, inp: SendPtr<f32>| {
+                    if let Some(lora) = lora {
+                        lora.forward(target, l, out, inp, lora_h, B, N);
+                    }
+                };
+
+                // Attention block, the new query, key and value vectors go to the cache first
+                layernorm_forward(ln, ln_mean, ln_rstd, residual, l_ln1w, l_ln1b, B, N, C);
+                matmul_forward(qkv, ln, l_qkvw, l_qkvb, B, N, C, 3 * C);
+                adapt(LoraTarget::Qkvw, qkv, ln);
+                for b in 0..B {
+                    let cache_start = (b * T + pos) * 3 * C;
+                    l_qkv_cache[cache_start..cache_start + N * 3 * C]
+                        .copy_from_slice(&scratch.qkv[b * N * 3 * C..(b + 1) * N * 3 * C]);
+                }
+                attention_forward_cached(atty, ptr(l_qkv_cache), B, T, pos, N, C, NH);
+                matmul_forward(proj, atty, l_attprojw, l_attprojb, B, N, C, C);
+                adapt(LoraTarget::Attprojw, proj, atty);
+                residual_forward(residual2, residual, proj, B * N * C);
+
+                // MLP block, its output is the residual stream of the next layer
+                layernorm_forward(ln, ln_mean, ln_rstd, residual2, l_ln2w, l_ln2b, B, N, C);
+                matmul_forward(fch, ln, l_fcw, l_fcb, B, N, C, 4 * C);
+                adapt(LoraTarget::Fcw, fch, ln);
+                gelu_forward(fch_gelu, fch, B * N * 4 * C);
+                matmul_forward(proj, fch_gelu, l_fcprojw, l_fcprojb, B, N, 4 * C, C);
+                adapt(LoraTarget::Fcprojw, proj, fch_gelu);
+                residual_forward(residual, residual2, proj, B * N * C);
+            }
+
+            self.hidden.resize(B * N * C, 0.0);
+            layernorm_forward(
+                ptr(&mut self.hidden),
+                ptr(&mut scratch.ln_mean),
+                ptr(&mut scratch.ln_rstd),
+                ptr(&mut scratch.residual),
+                params.lnfw,
+                params.lnfb,
+                B,
+                N,
+                C,
+            );
+
+            // Only the last position of each sequence predicts the next token
+            for b in 0..B {
+                let last = (b * N + N - 1) * C;
+                scratch.ln[b * C..(b + 1) * C].copy_from_slice(&self.hidden[last..last + C]);
+            }
+            let lnf = ptr(&mut scratch.ln);
+            let logits = ptr(&mut self.logits);
+            matmul_forward(logits, lnf, params.wte, SendPtr::new(null_mut()), B, 1, C, Vp);
+            softmax_forward(ptr(&mut self.probs), logits, B, 1, V, Vp);
+        }
+
+        self.pos += N;
+        &self.logits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt2::GPT2Config;
+    use crate::random::random_u32;
+
+    const B: usize = 2;
+    const T: usize = 8;
+
+    /// Random tokens for `B` sequences of `T` positions.
+    fn random_tokens(V: usize, seed: u64) -> Vec<i32> {
+        let mut rng_state = seed;
+        (0..B * T).map(|_| (random_u32(&mut rng_state) as usize % V) as i32).collect()
+    }
+
+    /// Logits of every position of a full forward pass (B, T, Vp).
+    fn full_logits(model: &mut GPT2, tokens: &[i32]) -> Vec<f32> {
+        let Vp = model.config.padded_vocab_size;
+        model.forward(SendPtr::new(tokens.as_ptr() as *mut i32), SendPtr::new(null_mut()), B, T);
+        unsafe { std::slice::from_raw_parts(model.acts.logits.ptr, B * T * Vp).to_vec() }
+    }
+
+    /// Checks the logits of sequence `b` of the session against position `t` of `row` in the
+    /// full forward pass.
+    fn assert_logits_close(session: &InferenceSession, b: usize, full: &[f32], row: usize, t: usize) {
+        let Vp = session.model.config.padded_vocab_size;
+        let logits = &session.logits()[b * Vp..(b + 1) * Vp];
+        let expected = &full[(row * T + t) * Vp..(row * T + t + 1) * Vp];
+        for (i, (&logit, &expected)) in logits.iter().zip(expected).enumerate() {
+            assert!(
+                (logit - expected).abs() <= 1e-4 * expected.abs().max(1.0),
+                "sequence {} position {} logit {}: {} vs {}",
+                b,
+                t,
+                i,
+                logit,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn cached_forward_matches_full_forward() {
+        let mut model = GPT2::from_config(GPT2Config::tiny(), 42).unwrap();
+        let tokens = random_tokens(model.config.vocab_size, 7);
+        let full = full_logits(&mut model, &tokens);
+
+        let mut session = InferenceSession::new(&model, B, T);
+        // Prefill the first 3 positions, then one position at a time
+        let prefill = 3;
+        let prompt: Vec<i32> = (0..B).flat_map(|b| tokens[b * T..b * T + prefill].to_vec()).collect();
+        session.forward(&prompt);
+        for b in 0..B {
+            assert_logits_close(&session, b, &full, b, prefill - 1);
+        }
+        for t in prefill..T {
+            let step: Vec<i32> = (0..B).map(|b| tokens[b * T + t]).collect();
+            session.forward(&step);
+            for b in 0..B {
+                assert_logits_close(&session, b, &full, b, t);
+            }
+        }
+        assert_eq!(session.pos, T);
+        drop(session);
+        unsafe { model.free() };
+    }
+
+    #[test]
+    fn reorder_follows_the_rows() {
+        let mut model = GPT2::from_config(GPT2Config::tiny(), 42).unwrap();
+        let tokens = random_tokens(model.config.vocab_size, 11);
+        let full = full_logits(&mut model, &tokens);
+
+        let mut session = InferenceSession::new(&model, B, T);
+        let prefill = 4;
+        let prompt: Vec<i32> = (0..B).flat_map(|b| tokens[b * T..b * T + prefill].to_vec()).collect();
+        session.forward(&prompt);
+
+        // Swapped, the logits move along and each sequence continues the other one's cache
+        session.reorder(&[1, 0]);
+        assert_logits_close(&session, 0, &full, 1, prefill - 1);
+        assert_logits_close(&session, 1, &full, 0, prefill - 1);
+        session.forward(&[tokens[T + prefill], tokens[prefill]]);
+        assert_logits_close(&session, 0, &full, 1, prefill);
+        assert_logits_close(&session, 1, &full, 0, prefill);
+
+        // Duplicated, both sequences continue the first one, which holds row 1
+        session.reorder(&[0, 0]);
+        let next = tokens[T + prefill + 1];
+        session.forward(&[next, next]);
+        assert_logits_close(&session, 0, &full, 1, prefill + 1);
+        assert_logits_close(&session, 1, &full, 1, prefill + 1);
+        drop(session);
+        unsafe { model.free() };
+    }
+}
@@ -0,0 +1,661 @@
+use core::slice;
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::Path;
+use std::ptr::{self, null_mut};
+
+use super::passes::*;
+use super::{GPT2Config, ParameterTensors};
+use crate::error::{Error, Result};
+use crate::random::normal_fill;
+use crate::send_ptr::SendPtr;
+use crate::train_state::write_atomically;
+
+/// Magic number of the LoRA adapter files.
+const LORA_MAGIC: i32 = 20240612;
+
+/// Version of the LoRA adapter files.
+const LORA_VERSION: i32 = 1;
+
+/// Size of the header of the LoRA adapter files, 256 i32 values.
+const LORA_HEADER_BYTES: u64 = 256 * mem::size_of::<i32>() as u64;
+
+/// A weight matrix of every layer that gets a low-rank adapter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoraTarget {
+    /// Query, Key, Value weights (3*C, C).
+    Qkvw,
+
+    /// Attention projection weights (C, C).
+    Attprojw,
+
+    /// Fully connected weights (4*C, C).
+    Fcw,
+
+    /// Fully connected projection weights (C, 4*C).
+    Fcprojw,
+}
+
+impl LoraTarget {
+    /// All the targets, in the order of their tensors in the adapter memory and files.
+    pub const ALL: [LoraTarget; 4] = [
+        LoraTarget::Qkvw,
+        LoraTarget::Attprojw,
+        LoraTarget::Fcw,
+        LoraTarget::Fcprojw,
+    ];
+
+    /// Input and output features of the weight matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `C` - Number of channels of the model.
+    ///
+    /// # Returns
+    ///
+    /// The number of input and output features (C, OC).
+    pub fn shape(self, C: usize) -> (usize, usize) {
+        match self {
+            LoraTarget::Qkvw => (C, 3 * C),
+            LoraTarget::Attprojw => (C, C),
+            LoraTarget::Fcw => (C, 4 * C),
+            LoraTarget::Fcprojw => (4 * C, C),
+        }
+    }
+
+    /// The weights of every layer the target adapts, in the base model.
+    fn base_weight(self, params: &ParameterTensors) -> SendPtr<f32> {
+        match self {
+            LoraTarget::Qkvw => params.qkvw,
+            LoraTarget::Attprojw => params.attprojw,
+            LoraTarget::Fcw => params.fcw,
+            LoraTarget::Fcprojw => params.fcprojw,
+        }
+    }
+}
+
+/// Low-rank adapters (LoRA) for parameter-efficient fine-tuning of a GPT-2 model.
+///
+/// Each weight matrix W (OC, C) of `LoraTarget::ALL` gets a down projection A (R, C) and an up
+/// projection B (OC, R) in every layer, and the model computes x W^T + scale * (x A^T) B^T with
+/// scale = alpha / R. Once attached to `GPT2::lora`, the base weights are frozen: `GPT2::backward`
+/// only computes the gradients of the adapters and `GPT2::update` only trains them, with AdamW
+/// buffers of their size.
+///
+/// The parameters are laid out target by target, A (L, R, C) then B (L, OC, R).
+pub struct LoraAdapters {
+    /// Rank of the adapters (R).
+    pub rank: usize,
+
+    /// Scaling numerator, the adapter outputs are scaled by alpha / rank.
+    pub alpha: f32,
+
+    /// Number of layers of the model.
+    pub num_layers: usize,
+
+    /// Number of channels of the model.
+    pub channels: usize,
+
+    /// Memory block containing all the adapter parameters.
+    pub params_memory: SendPtr<f32>,
+
+    /// Total number of adapter parameters.
+    pub num_parameters: usize,
+
+    /// Memory block containing the gradients of the adapter parameters.
+    pub grads_memory: SendPtr<f32>,
+
+    /// Buffer for the AdamW optimizer.
+    pub m_memory: SendPtr<f32>,
+
+    /// Buffer for the AdamW optimizer.
+    pub v_memory: SendPtr<f32>,
+
+    /// Outputs of the down projections of the last forward pass (4, L, B, T, R).
+    pub acts_memory: SendPtr<f32>,
+
+    /// The batch size (B) `acts_memory` was allocated for
+    pub batch_size: usize,
+
+    /// The sequence length (T) `acts_memory` was allocated for
+    pub seq_len: usize,
+}
+
+impl LoraAdapters {
+    /// Creates adapters for a model, which do not change its outputs until they are trained.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Configuration of the model to adapt.
+    /// * `rank` - Rank of the adapters, e.g. 8.
+    /// * `alpha` - Scaling numerator, e.g. the rank for a scale of 1.
+    /// * `seed` - Seed of the random initialization.
+    ///
+    /// # Returns
+    ///
+    /// New `LoraAdapters`.
+    ///
+    /// # Note
+    ///
+    /// Like in the LoRA paper, A is drawn from N(0, 1/C) and B is zero, so that the adapted model
+    /// starts out as the base model.
+    pub fn new(config: &GPT2Config, rank: usize, alpha: f32, seed: u64) -> Self {
+        assert!(rank > 0, "the adapters need a rank of at least 1");
+        let lora = LoraAdapters::empty(config.num_layers, config.channels, rank, alpha);
+        // xorshift must not start from 0
+        let mut rng_state = seed.max(1);
+        unsafe {
+            let L = lora.num_layers;
+            let C = lora.channels;
+            let R = lora.rank;
+            for target in LoraTarget::ALL {
+                let (IC, OC) = target.shape(C);
+                let (a, b) = lora.tensors(lora.params_memory, target);
+                normal_fill(
+                    slice::from_raw_parts_mut(a.ptr, L * R * IC),
+                    0.0,
+                    1.0 / (IC as f32).sqrt(),
+                    &mut rng_state,
+                );
+                slice::from_raw_parts_mut(b.ptr, L * OC * R).fill(0.0);
+            }
+        }
+        lora
+    }
+
+    /// Reads adapters from a file written by `save`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the adapter file.
+    /// * `config` - Configuration of the model to adapt.
+    ///
+    /// # Returns
+    ///
+    /// The adapters, or an error if the file cannot be read or was saved for another model size.
+    pub fn load(path: &Path, config: &GPT2Config) -> Result<Self> {
+        let mut lora_file = File::open(path)?;
+        let file_size = lora_file.metadata()?.len();
+
+        let mut lora_header = [0i32; 256];
+        if file_size < LORA_HEADER_BYTES {
+            return Err(Error::Truncated {
+                expected: LORA_HEADER_BYTES,
+                actual: file_size,
+            });
+        }
+        lora_file.read_exact(unsafe {
+            slice::from_raw_parts_mut(
+                lora_header.as_mut_ptr() as *mut u8,
+                LORA_HEADER_BYTES as usize,
+            )
+        })?;
+
+        // Check magic number and version
+        if lora_header[0] != LORA_MAGIC {
+            return Err(Error::BadMagic {
+                expected: LORA_MAGIC,
+                found: lora_header[0],
+            });
+        }
+        if lora_header[1] != LORA_VERSION {
+            return Err(Error::BadVersion(lora_header[1]));
+        }
+        let (num_layers, channels, rank) = (lora_header[2], lora_header[3], lora_header[4]);
+        if num_layers as usize != config.num_layers || channels as usize != config.channels {
+            return Err(Error::InconsistentHeader(format!(
+                "adapters for {} layers of {} channels, the model has {} layers of {} channels",
+                num_layers, channels, config.num_layers, config.channels
+            )));
+        }
+        if rank <= 0 {
+            return Err(Error::InconsistentHeader(format!("invalid adapter rank {}", rank)));
+        }
+        let alpha = f32::from_bits(lora_header[5] as u32);
+
+        // Make sure the whole payload is there before allocating for it
+        let num_parameters = count_parameters(config.num_layers, config.channels, rank as usize);
+        let expected_size = LORA_HEADER_BYTES + (num_parameters * mem::size_of::<f32>()) as u64;
+        if file_size < expected_size {
+            return Err(Error::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
+        let mut lora = LoraAdapters::empty(config.num_layers, config.channels, rank as usize, alpha);
+        let result = lora_file.read_exact(unsafe {
+            slice::from_raw_parts_mut(
+                lora.params_memory.ptr as *mut u8,
+                lora.num_parameters * mem::size_of::<f32>(),
+            )
+        });
+        if let Err(err) = result {
+            unsafe { lora.free() };
+            return Err(err.into());
+        }
+
+        Ok(lora)
+    }
+
+    /// Saves the adapters to a file, without the base model.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the adapter file to write.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_atomically(path, |lora_file| self.write(lora_file))
+    }
+
+    /// Writes the header and the parameters of the adapters.
+    ///
+    /// # Arguments
+    ///
+    /// * `lora_file` - Where to write the adapters.
+    pub(crate) fn write(&self, lora_file: &mut impl Write) -> Result<()> {
+        let mut lora_header = [0i32; 256];
+        lora_header[0] = LORA_MAGIC;
+        lora_header[1] = LORA_VERSION;
+        lora_header[2] = self.num_layers as i32;
+        lora_header[3] = self.channels as i32;
+        lora_header[4] = self.rank as i32;
+        lora_header[5] = self.alpha.to_bits() as i32;
+
+        for value in lora_header {
+            lora_file.write_all(&value.to_le_bytes())?;
+        }
+        let params = unsafe { slice::from_raw_parts(self.params_memory.ptr, self.num_parameters) };
+        for &param in params {
+            lora_file.write_all(&param.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Scale of the adapter outputs, alpha / rank.
+    pub fn scale(&self) -> f32 {
+        self.alpha / self.rank as f32
+    }
+
+    /// Adds the adapted weights to the base weights, W += scale * B A, so that the model computes
+    /// the same outputs without the adapters, e.g. to export it.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Parameters of the base model.
+    ///
+    /// # Safety
+    ///
+    /// `params` must point to the parameters of a model with the layers and channels of the
+    /// adapters.
+    pub unsafe fn merge_into(&self, params: &ParameterTensors) {
+        let L = self.num_layers;
+        let C = self.channels;
+        let R = self.rank;
+        let scale = self.scale();
+        for target in LoraTarget::ALL {
+            let (IC, OC) = target.shape(C);
+            let (a, b) = self.tensors(self.params_memory, target);
+            let weight = target.base_weight(params);
+            for l in 0..L {
+                let l_a = a.ptr.add(l * R * IC);
+                let l_b = b.ptr.add(l * OC * R);
+                let l_weight = weight.ptr.add(l * OC * IC);
+                for o in 0..OC {
+                    for r in 0..R {
+                        let d = scale * *l_b.add(o * R + r);
+                        for i in 0..IC {
+                            *l_weight.add(o * IC + i) += d * *l_a.add(r * IC + i);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Computes the adapter of a layer after the matmul of its base weights.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The adapted weight matrix.
+    /// * `l` - The layer.
+    /// * `out` - Output of the matmul with the base weights (B, T, OC), the adapter output is added.
+    /// * `inp` - Input of the matmul (B, T, C).
+    /// * `h` - Memory for the output of the down projection (B, T, R).
+    /// * `B` - Batch size.
+    /// * `T` - Sequence length.
+    ///
+    /// # Safety
+    ///
+    /// `out`, `inp` and `h` must hold (B, T, OC), (B, T, C) and (B, T, R) values.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn forward(
+        &self,
+        target: LoraTarget,
+        l: usize,
+        out: SendPtr<f32>,
+        inp: SendPtr<f32>,
+        h: SendPtr<f32>,
+        B: usize,
+        T: usize,
+    ) {
+        let R = self.rank;
+        let (C, OC) = target.shape(self.channels);
+        let (a, b) = self.tensors(self.params_memory, target);
+        let l_a = SendPtr::new(a.ptr.add(l * R * C));
+        let l_b = SendPtr::new(b.ptr.add(l * OC * R));
+        lora_forward(out, h, inp, l_a, l_b, self.scale(), B, T, C, OC, R);
+    }
+
+    /// Backpropagates through the adapter of a layer, using the down projection output stored in
+    /// `acts_memory` by the forward pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The adapted weight matrix.
+    /// * `l` - The layer.
+    /// * `dinp` - Gradient of the input of the matmul (B, T, C), accumulated into.
+    /// * `dout` - Gradient of the output of the matmul (B, T, OC).
+    /// * `inp` - Input of the matmul (B, T, C).
+    ///
+    /// # Safety
+    ///
+    /// `forward` must have stored the down projection of the same input in `acts_memory`, and
+    /// `dinp`, `dout` and `inp` must hold (B, T, C), (B, T, OC) and (B, T, C) values.
+    pub unsafe fn backward(
+        &self,
+        target: LoraTarget,
+        l: usize,
+        dinp: SendPtr<f32>,
+        dout: SendPtr<f32>,
+        inp: SendPtr<f32>,
+    ) {
+        let B = self.batch_size;
+        let T = self.seq_len;
+        let R = self.rank;
+        let (C, OC) = target.shape(self.channels);
+        let (a, b) = self.tensors(self.params_memory, target);
+        let (da, db) = self.tensors(self.grads_memory, target);
+        let l_a = SendPtr::new(a.ptr.add(l * R * C));
+        let l_b = SendPtr::new(b.ptr.add(l * OC * R));
+        let dl_a = SendPtr::new(da.ptr.add(l * R * C));
+        let dl_b = SendPtr::new(db.ptr.add(l * OC * R));
+        let h = self.acts(target, l);
+        lora_backward(dinp, dl_a, dl_b, dout, inp, h, l_a, l_b, self.scale(), B, T, C, OC, R);
+    }
+
+    /// Memory for the down projection output of an adapter in the training forward pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The adapted weight matrix.
+    /// * `l` - The layer.
+    pub fn acts(&self, target: LoraTarget, l: usize) -> SendPtr<f32> {
+        let index = target as usize * self.num_layers + l;
+        let size = self.batch_size * self.seq_len * self.rank;
+        SendPtr::new(self.acts_memory.ptr.wrapping_add(index * size))
+    }
+
+    /// Allocates `acts_memory` and the gradients for a batch size and a sequence length, if they
+    /// are not allocated for them yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `B` - Batch size.
+    /// * `T` - Sequence length.
+    ///
+    /// # Safety
+    ///
+    /// The adapters must not have been freed.
+    pub unsafe fn alloc_acts(&mut self, B: usize, T: usize) {
+        if !self.acts_memory.ptr.is_null() && (B, T) == (self.batch_size, self.seq_len) {
+            return;
+        }
+        free_memory(self.acts_memory, self.num_acts());
+        self.batch_size = B;
+        self.seq_len = T;
+        let layout = Layout::array::<f32>(self.num_acts()).expect("Layout error");
+        self.acts_memory = SendPtr::new(alloc::alloc(layout) as *mut f32);
+        if self.grads_memory.ptr.is_null() {
+            let layout = Layout::array::<f32>(self.num_parameters).expect("Layout error");
+            self.grads_memory = SendPtr::new(alloc::alloc_zeroed(layout) as *mut f32);
+        }
+    }
+
+    /// Sets the gradients of the adapters to zero.
+    ///
+    /// # Safety
+    ///
+    /// The adapters must not have been freed.
+    pub unsafe fn zero_grad(&mut self) {
+        if !self.grads_memory.ptr.is_null() {
+            ptr::write_bytes(self.grads_memory.ptr, 0, self.num_parameters);
+        }
+    }
+
+    /// Updates the adapter parameters using AdamW optimization.
+    ///
+    /// # Arguments
+    ///
+    /// * `learning_rate` - Learning rate.
+    /// * `beta1` - Exponential decay rate for the first moment estimates.
+    /// * `beta2` - Exponential decay rate for the second moment estimates.
+    /// * `eps` - Small constant for numerical stability.
+    /// * `weight_decay` - Weight decay coefficient.
+    /// * `t` - Time step.
+    ///
+    /// # Safety
+    ///
+    /// The adapters must not have been freed.
+    pub unsafe fn update(
+        &mut self,
+        learning_rate: f32,
+        beta1: f32,
+        beta2: f32,
+        eps: f32,
+        weight_decay: f32,
+        t: usize,
+    ) {
+        assert!(!self.grads_memory.ptr.is_null(), "Error: must backward before update");
+        // Lazily allocate the memory for m_memory and v_memory
+        let layout = Layout::array::<f32>(self.num_parameters).unwrap();
+        if self.m_memory.ptr.is_null() {
+            self.m_memory = SendPtr::new(alloc::alloc_zeroed(layout) as *mut f32);
+        }
+        if self.v_memory.ptr.is_null() {
+            self.v_memory = SendPtr::new(alloc::alloc_zeroed(layout) as *mut f32);
+        }
+        adamw_update(
+            self.params_memory,
+            self.grads_memory,
+            self.m_memory,
+            self.v_memory,
+            self.num_parameters,
+            learning_rate,
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+            t,
+        );
+    }
+
+    /// Frees the memory allocated for the adapters.
+    ///
+    /// # Safety
+    ///
+    /// The adapters must not be used afterwards.
+    pub unsafe fn free(&mut self) {
+        free_memory(self.params_memory, self.num_parameters);
+        free_memory(self.grads_memory, self.num_parameters);
+        free_memory(self.m_memory, self.num_parameters);
+        free_memory(self.v_memory, self.num_parameters);
+        free_memory(self.acts_memory, self.num_acts());
+        self.params_memory = SendPtr::new(null_mut());
+        self.grads_memory = SendPtr::new(null_mut());
+        self.m_memory = SendPtr::new(null_mut());
+        self.v_memory = SendPtr::new(null_mut());
+        self.acts_memory = SendPtr::new(null_mut());
+    }
+
+    /// Creates adapters with allocated but uninitialized parameters.
+    fn empty(num_layers: usize, channels: usize, rank: usize, alpha: f32) -> Self {
+        let num_parameters = count_parameters(num_layers, channels, rank);
+        let layout = Layout::array::<f32>(num_parameters).expect("Layout error");
+        let params_memory = unsafe { SendPtr::new(alloc::alloc(layout) as *mut f32) };
+        if params_memory.ptr.is_null() {
+            panic!("Memory allocation failed");
+        }
+
+        LoraAdapters {
+            rank,
+            alpha,
+            num_layers,
+            channels,
+            params_memory,
+            num_parameters,
+            grads_memory: SendPtr::new(null_mut()),
+            m_memory: SendPtr::new(null_mut()),
+            v_memory: SendPtr::new(null_mut()),
+            acts_memory: SendPtr::new(null_mut()),
+            batch_size: 0,
+            seq_len: 0,
+        }
+    }
+
+    /// Pointers to the A (L, R, C) and B (L, OC, R) tensors of a target, in the parameters or the
+    /// gradients.
+    fn tensors(&self, memory: SendPtr<f32>, target: LoraTarget) -> (SendPtr<f32>, SendPtr<f32>) {
+        let L = self.num_layers;
+        let R = self.rank;
+        let mut offset = 0;
+        for other in LoraTarget::ALL {
+            let (C, OC) = other.shape(self.channels);
+            if other == target {
+                let a = memory.ptr.wrapping_add(offset);
+                let b = memory.ptr.wrapping_add(offset + L * R * C);
+                return (SendPtr::new(a), SendPtr::new(b));
+            }
+            offset += L * R * (C + OC);
+        }
+        unreachable!()
+    }
+
+    /// Number of floats of `acts_memory`.
+    fn num_acts(&self) -> usize {
+        LoraTarget::ALL.len() * self.num_layers * self.batch_size * self.seq_len * self.rank
+    }
+}
+
+/// Number of parameters of adapters of a given rank, for a model of a given size.
+fn count_parameters(num_layers: usize, channels: usize, rank: usize) -> usize {
+    LoraTarget::ALL
+        .iter()
+        .map(|target| {
+            let (C, OC) = target.shape(channels);
+            num_layers * rank * (C + OC)
+        })
+        .sum()
+}
+
+/// Frees a block allocated for `num_elements` floats, if any.
+unsafe fn free_memory(send_ptr: SendPtr<f32>, num_elements: usize) {
+    if !send_ptr.ptr.is_null() {
+        let layout = Layout::array::<f32>(num_elements).expect("Layout error");
+        alloc::dealloc(send_ptr.ptr as *mut u8, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt2::GPT2;
+
+    const B: usize = 2;
+    const T: usize = 8;
+
+    /// The tiny model with a smaller vocabulary.
+    fn tiny_model() -> GPT2 {
+        GPT2::from_config(GPT2Config::tiny().with_vocab_size(1000), 42).unwrap()
+    }
+
+    /// Adapters whose up projections are not zero, as after some training.
+    fn trained_adapters(config: &GPT2Config) -> LoraAdapters {
+        let lora = LoraAdapters::new(config, 4, 8.0, 1);
+        let mut rng_state = 9;
+        unsafe {
+            normal_fill(
+                slice::from_raw_parts_mut(lora.params_memory.ptr, lora.num_parameters),
+                0.0,
+                0.1,
+                &mut rng_state,
+            );
+        }
+        lora
+    }
+
+    /// Logits of a forward pass on fixed tokens (B, T, Vp).
+    fn logits(model: &mut GPT2) -> Vec<f32> {
+        let tokens: Vec<i32> = (0..(B * T) as i32).map(|i| i * 37 % 1000).collect();
+        let Vp = model.config.padded_vocab_size;
+        model.forward(SendPtr::new(tokens.as_ptr() as *mut i32), SendPtr::new(null_mut()), B, T);
+        unsafe { slice::from_raw_parts(model.acts.logits.ptr, B * T * Vp).to_vec() }
+    }
+
+    #[test]
+    fn new_adapters_leave_the_outputs_unchanged() {
+        let mut model = tiny_model();
+        let base = logits(&mut model);
+        model.lora = Some(LoraAdapters::new(&model.config, 4, 8.0, 1));
+        assert_eq!(logits(&mut model), base);
+        unsafe { model.free() };
+    }
+
+    #[test]
+    fn merged_adapters_match_the_adapted_model() {
+        let mut model = tiny_model();
+        let base = logits(&mut model);
+        model.lora = Some(trained_adapters(&model.config));
+        let adapted = logits(&mut model);
+        assert_ne!(adapted, base);
+
+        model.merge_lora().unwrap();
+        assert!(model.lora.is_none());
+        let merged = logits(&mut model);
+        for (i, (&m, &a)) in merged.iter().zip(&adapted).enumerate() {
+            assert!((m - a).abs() <= 1e-4 * a.abs().max(1.0), "logit {}: {} vs {}", i, m, a);
+        }
+        unsafe { model.free() };
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let config = GPT2Config::tiny();
+        let mut lora = trained_adapters(&config);
+        let path = std::env::temp_dir().join(format!("llm-rs-lora-test-{}.bin", std::process::id()));
+        lora.save(&path).unwrap();
+        let file_size = std::fs::metadata(&path).unwrap().len();
+        assert_eq!(file_size, LORA_HEADER_BYTES + (lora.num_parameters * mem::size_of::<f32>()) as u64);
+
+        let mut loaded = LoraAdapters::load(&path, &config).unwrap();
+        assert_eq!(
+            (loaded.rank, loaded.alpha, loaded.num_layers, loaded.channels),
+            (lora.rank, lora.alpha, lora.num_layers, lora.channels)
+        );
+        unsafe {
+            assert_eq!(
+                slice::from_raw_parts(loaded.params_memory.ptr, loaded.num_parameters),
+                slice::from_raw_parts(lora.params_memory.ptr, lora.num_parameters)
+            );
+        }
+
+        // Adapters only fit a model of the same size
+        let other = GPT2Config::gpt2(3, 4, 128);
+        assert!(matches!(LoraAdapters::load(&path, &other), Err(Error::InconsistentHeader(_))));
+        std::fs::remove_file(&path).unwrap();
+        unsafe {
+            lora.free();
+            loaded.free();
+        }
+    }
+}
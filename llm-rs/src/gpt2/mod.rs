@@ -1,20 +1,36 @@
 mod activation_tensors;
+mod hf;
+mod inference;
+mod lora;
 mod parameter_tensors;
 mod passes;
 
 use core::slice;
 use std::alloc::{self, Layout};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::path::Path;
 use std::mem;
 use std::ptr::{self, null_mut};
 
-use activation_tensors::*;
-use parameter_tensors::*;
+pub use activation_tensors::*;
+pub use inference::*;
+pub use lora::*;
+pub use parameter_tensors::*;
 use passes::*;
 
+use memmap2::Mmap;
+
+use crate::error::{Error, Result};
+use crate::random::normal_fill;
 use crate::send_ptr::SendPtr;
+use crate::train_state::write_atomically;
+
+/// Size of the header of the checkpoint files, 256 i32 values.
+const MODEL_HEADER_BYTES: u64 = 256 * mem::size_of::<i32>() as u64;
+
+/// Target of the positions that have no loss and no gradient, e.g. prompts or padding.
+pub const IGNORE_INDEX: i32 = -1;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct GPT2Config {
@@ -38,21 +54,149 @@ pub struct GPT2Config {
 }
 
 impl GPT2Config {
-    /// Creates a new GPT2Config instance.
+    /// Creates the configuration of a GPT-2 model with the GPT-2 tokenizer and context length.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_layers` - Number of layers.
+    /// * `num_heads` - Number of attention heads.
+    /// * `channels` - Number of channels.
     ///
     /// # Returns
     ///
     /// A new `GPT2Config` instance.
-    fn new() -> Self {
+    fn gpt2(num_layers: usize, num_heads: usize, channels: usize) -> Self {
+        GPT2Config {
+            max_seq_len: 1024,
+            vocab_size: 50257,
+            padded_vocab_size: 50304,
+            num_layers,
+            num_heads,
+            channels,
+        }
+    }
+
+    /// GPT-2 small (124M parameters).
+    pub fn d12() -> Self {
+        Self::gpt2(12, 12, 768)
+    }
+
+    /// GPT-2 medium (350M parameters).
+    pub fn d24() -> Self {
+        Self::gpt2(24, 16, 1024)
+    }
+
+    /// GPT-2 large (774M parameters).
+    pub fn d36() -> Self {
+        Self::gpt2(36, 20, 1280)
+    }
+
+    /// GPT-2 XL (1558M parameters).
+    pub fn d48() -> Self {
+        Self::gpt2(48, 25, 1600)
+    }
+
+    /// A tiny 2-layer model with the GPT-2 tokenizer, for debugging and quick experiments.
+    pub fn tiny() -> Self {
+        GPT2Config {
+            max_seq_len: 256,
+            ..Self::gpt2(2, 4, 128)
+        }
+    }
+
+    /// Looks up a named configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - One of `d12`, `d24`, `d36`, `d48` or `tiny`.
+    ///
+    /// # Returns
+    ///
+    /// The matching `GPT2Config`, or `None` for an unknown name.
+    pub fn from_preset(name: &str) -> Option<Self> {
+        match name {
+            "d12" => Some(Self::d12()),
+            "d24" => Some(Self::d24()),
+            "d36" => Some(Self::d36()),
+            "d48" => Some(Self::d48()),
+            "tiny" => Some(Self::tiny()),
+            _ => None,
+        }
+    }
+
+    /// Changes the vocabulary, e.g. for a tokenizer trained with `BpeTrainer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab_size` - Number of tokens of the tokenizer.
+    ///
+    /// # Returns
+    ///
+    /// The configuration with the new vocabulary size, padded to a multiple of 128.
+    pub fn with_vocab_size(self, vocab_size: usize) -> Self {
         GPT2Config {
-            max_seq_len: 0,
-            vocab_size: 0,
-            padded_vocab_size: 0,
-            num_layers: 0,
-            num_heads: 0,
-            channels: 0,
+            vocab_size,
+            padded_vocab_size: vocab_size.div_ceil(128) * 128,
+            ..self
         }
     }
+
+    /// Checks that the configuration describes a valid model.
+    pub fn validate(&self) -> Result<()> {
+        let values = [
+            self.max_seq_len,
+            self.vocab_size,
+            self.padded_vocab_size,
+            self.num_layers,
+            self.num_heads,
+            self.channels,
+        ];
+        if values.contains(&0) {
+            return Err(Error::InconsistentHeader(format!(
+                "hyperparameters must be positive, found {:?}",
+                self
+            )));
+        }
+        if self.padded_vocab_size < self.vocab_size {
+            return Err(Error::InconsistentHeader(format!(
+                "padded_vocab_size {} is smaller than vocab_size {}",
+                self.padded_vocab_size, self.vocab_size
+            )));
+        }
+        if !self.channels.is_multiple_of(self.num_heads) {
+            return Err(Error::InconsistentHeader(format!(
+                "channels {} is not divisible by num_heads {}",
+                self.channels, self.num_heads
+            )));
+        }
+        Ok(())
+    }
+
+    /// Computes the number of elements of each parameter tensor, in checkpoint order.
+    pub fn param_sizes(&self) -> [usize; NUM_PARAMETER_TENSORS] {
+        let maxT = self.max_seq_len;
+        let Vp = self.padded_vocab_size;
+        let L = self.num_layers;
+        let C = self.channels;
+        [
+            Vp * C,            // wte
+            maxT * C,          // wpe
+            L * C,             // ln1w
+            L * C,             // ln1b
+            L * (3 * C) * C,   // qkvw
+            L * (3 * C),       // qkvb
+            L * C * C,         // attprojw
+            L * C,             // attprojb
+            L * C,             // ln2w
+            L * C,             // ln2b
+            L * (4 * C) * C,   // fcw
+            L * (4 * C),       // fcb
+            L * C * (4 * C),   // fcprojw
+            L * C,             // fcprojb
+            C,                 // lnfw
+            C,                 // lnfb
+        ]
+    }
 }
 
 pub struct GPT2 {
@@ -68,13 +212,17 @@ pub struct GPT2 {
     /// Memory block containing all model parameters.
     pub params_memory: SendPtr<f32>,
 
+    /// Read-only memory map `params_memory` points into, for models created with `new_mmap`.
+    pub params_mmap: Option<Mmap>,
+
     /// Total number of parameters.
     pub num_parameters: usize,
 
     /// Gradients of the weights.
     pub grads: ParameterTensors,
 
-    /// Memory block containing all gradients of the model parameters.
+    /// Memory block containing all gradients of the model parameters, never allocated when training
+    /// with `lora`.
     pub grads_memory: SendPtr<f32>,
 
     /// Buffer for the AdamW optimizer.
@@ -113,27 +261,50 @@ pub struct GPT2 {
     /// The target tokens for the current forward pass
     pub targets: SendPtr<i32>,
 
+    /// Start of the document of each position of the current forward pass, all 0 without documents
+    pub doc_starts: SendPtr<i32>,
+
+    /// Whether the position embeddings count from the start of each document instead of the row
+    pub reset_positions: bool,
+
     /// After a forward pass with targets, will be populated with the mean loss
     pub mean_loss: f32,
+
+    /// Low-rank adapters added to the weights. With them, only the adapters are trained.
+    pub lora: Option<LoraAdapters>,
 }
 
 impl GPT2 {
-    /// Creates a new GPT-2 model instance from a checkpoint file.
+    /// Creates a GPT-2 model instance for the given configuration, without allocating its parameters.
     ///
     /// # Arguments
     ///
-    /// * `checkpoint_path` - Path to the checkpoint file containing model parameters and configuration.
+    /// * `config` - A valid model configuration.
     ///
     /// # Returns
     ///
-    /// A new `GPT2` model instance.
-    pub fn new(checkpoint_path: &Path) -> Self {
-        let mut model = GPT2 {
-            config: GPT2Config::new(),
+    /// A new `GPT2` model instance whose `params_memory` still has to be allocated and filled.
+    fn with_config(config: GPT2Config) -> Self {
+        println!("[GPT-2]");
+        println!("max_seq_len: {}", config.max_seq_len);
+        println!("vocab_size: {}", config.vocab_size);
+        println!("padded_vocab_size: {}", config.padded_vocab_size);
+        println!("num_layers: {}", config.num_layers);
+        println!("num_heads: {}", config.num_heads);
+        println!("channels: {}", config.channels);
+
+        // Count the number of parameters
+        let param_sizes = config.param_sizes();
+        let num_parameters: usize = param_sizes.iter().sum();
+        println!("num_parameters: {}", num_parameters);
+
+        GPT2 {
+            config,
             params: ParameterTensors::new(),
-            param_sizes: [0; NUM_PARAMETER_TENSORS],
+            param_sizes,
             params_memory: SendPtr::new(null_mut()),
-            num_parameters: 0,
+            params_mmap: None,
+            num_parameters,
             grads: ParameterTensors::new(),
             grads_memory: SendPtr::new(null_mut()),
             m_memory: SendPtr::new(null_mut()),
@@ -146,93 +317,222 @@ impl GPT2 {
             grads_acts_memory: SendPtr::new(null_mut()),
             inputs: SendPtr::new(null_mut()),
             targets: SendPtr::new(null_mut()),
+            doc_starts: SendPtr::new(null_mut()),
+            reset_positions: false,
             batch_size: 0,
             seq_len: 0,
             mean_loss: -1.0,
-        };
+            lora: None,
+        }
+    }
 
+    /// Creates a new GPT-2 model instance from a checkpoint file.
+    ///
+    /// # Arguments
+    ///
+    /// * `checkpoint_path` - Path to the checkpoint file containing model parameters and configuration.
+    ///
+    /// # Returns
+    ///
+    /// A new `GPT2` model instance, or an error if the checkpoint cannot be read or is invalid.
+    pub fn new(checkpoint_path: &Path) -> Result<Self> {
         // Read model from a checkpoint file
-        let mut model_file = File::open(checkpoint_path).unwrap_or_else(|_| {
-            panic!("Error opening model file");
-        });
-
-        // Read model header
-        let mut model_header = [0; 256];
-        model_file
-            .read_exact(unsafe {
-                slice::from_raw_parts_mut(
-                    model_header.as_mut_ptr() as *mut u8,
-                    model_header.len() * mem::size_of::<i32>(),
-                )
-            })
-            .expect("Failed to read model header");
-
-        // Check magic number and version
-        if model_header[0] != 20240326 {
-            panic!("Bad magic model file");
-        }
-        if model_header[1] != 3 {
-            panic!("Bad version in model file\n---> HINT: try to re-run `python train_gpt2.py`");
-        }
-
-        // Read in hyperparameters
-        let maxT = model_header[2] as usize;
-        let V = model_header[3] as usize;
-        let L = model_header[4] as usize;
-        let NH = model_header[5] as usize;
-        let C = model_header[6] as usize;
-        let Vp = model_header[7] as usize;
-        model.config = GPT2Config {
-            max_seq_len: maxT,
-            vocab_size: V,
-            padded_vocab_size: Vp,
-            num_layers: L,
-            num_heads: NH,
-            channels: C,
-        };
-        println!("[GPT-2]");
-        println!("max_seq_len: {}", maxT);
-        println!("vocab_size: {}", V);
-        println!("padded_vocab_size: {}", Vp);
-        println!("num_layers: {}", L);
-        println!("num_heads: {}", NH);
-        println!("channels: {}", C);
+        let mut model_file = File::open(checkpoint_path)?;
+        let file_size = model_file.metadata()?.len();
+        let (config, param_bytes) = read_model_header(&mut model_file, file_size)?;
+        let mut model = GPT2::with_config(config);
+        let num_parameters = model.num_parameters;
+
+        // Make sure the whole payload is there before allocating for it
+        let expected_size = MODEL_HEADER_BYTES + (num_parameters * param_bytes) as u64;
+        if file_size < expected_size {
+            return Err(Error::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
 
         // Allocate space for all the parameters and read them in
-        model.param_sizes[0] = Vp * C; // wte
-        model.param_sizes[1] = maxT * C; // wpe
-        model.param_sizes[2] = L * C; // ln1w
-        model.param_sizes[3] = L * C; // ln1b
-        model.param_sizes[4] = L * (3 * C) * C; // qkvw
-        model.param_sizes[5] = L * (3 * C); // qkvb
-        model.param_sizes[6] = L * C * C; // attprojw
-        model.param_sizes[7] = L * C; // attprojb
-        model.param_sizes[8] = L * C; // ln2w
-        model.param_sizes[9] = L * C; // ln2b
-        model.param_sizes[10] = L * (4 * C) * C; // fcw
-        model.param_sizes[11] = L * (4 * C); // fcb
-        model.param_sizes[12] = L * C * (4 * C); // fcprojw
-        model.param_sizes[13] = L * C; // fcprojb
-        model.param_sizes[14] = C; // lnfw
-        model.param_sizes[15] = C; // lnfb
-
-        // Count the number of parameters
-        let num_parameters: usize = model.param_sizes.iter().sum();
-        println!("num_parameters: {}", num_parameters);
-        model.num_parameters = num_parameters;
-
-        // Read in all the parameters from file
         unsafe {
             model.params_memory = model.params.alloc_and_point_parameters(&model.param_sizes);
-            model_file
-                .read_exact(slice::from_raw_parts_mut(
-                    model.params_memory.ptr as *mut u8,
+            let params = slice::from_raw_parts_mut(model.params_memory.ptr, num_parameters);
+            let result = if param_bytes == mem::size_of::<f32>() {
+                model_file.read_exact(slice::from_raw_parts_mut(
+                    params.as_mut_ptr() as *mut u8,
                     num_parameters * mem::size_of::<f32>(),
                 ))
-                .expect("Failed to read parameters");
+            } else {
+                read_bf16_into(&mut model_file, params)
+            };
+            if let Err(err) = result {
+                model.free();
+                return Err(err.into());
+            }
+        }
+
+        Ok(model)
+    }
+
+    /// Creates a new GPT-2 model instance whose parameters are memory-mapped from a checkpoint file,
+    /// for inference.
+    ///
+    /// # Arguments
+    ///
+    /// * `checkpoint_path` - Path to an fp32 (version 3) checkpoint file.
+    ///
+    /// # Returns
+    ///
+    /// A new `GPT2` model instance, or an error if the checkpoint cannot be mapped or is invalid.
+    ///
+    /// # Note
+    ///
+    /// `params_memory` points into a read-only shared mapping of the file, right after the header,
+    /// so nothing is copied and all the processes mapping the same checkpoint share the page cache.
+    /// The parameters cannot be modified: `update` and `merge_lora` return an error on such a model.
+    /// The file must not be modified while it is mapped.
+    pub fn new_mmap(checkpoint_path: &Path) -> Result<Self> {
+        let mut model_file = File::open(checkpoint_path)?;
+        let file_size = model_file.metadata()?.len();
+        let (config, param_bytes) = read_model_header(&mut model_file, file_size)?;
+        // bf16 parameters have to be converted, so they cannot be used in place
+        if param_bytes != mem::size_of::<f32>() {
+            return Err(Error::InconsistentHeader(
+                "only fp32 (version 3) checkpoints can be memory-mapped".to_string(),
+            ));
+        }
+        let mut model = GPT2::with_config(config);
+
+        let expected_size = MODEL_HEADER_BYTES + (model.num_parameters * param_bytes) as u64;
+        if file_size < expected_size {
+            return Err(Error::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
+
+        unsafe {
+            let params_mmap = Mmap::map(&model_file)?;
+            // The mapping is page aligned and the header is 1024 bytes, so the floats are aligned
+            model.params_memory =
+                SendPtr::new(params_mmap.as_ptr().add(MODEL_HEADER_BYTES as usize) as *mut f32);
+            model.params.point_parameters(model.params_memory, &model.param_sizes);
+            model.params_mmap = Some(params_mmap);
         }
 
-        model
+        Ok(model)
+    }
+
+    /// Creates a new GPT-2 model with randomly initialized weights, for training from scratch.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Model configuration, e.g. one of the `GPT2Config` presets.
+    /// * `seed` - Seed of the random initialization.
+    ///
+    /// # Returns
+    ///
+    /// A new `GPT2` model instance, or an error if the configuration is invalid.
+    ///
+    /// # Note
+    ///
+    /// This follows the GPT-2 initialization: weights are drawn from N(0, 0.02), the projections
+    /// feeding the residual stream (attprojw and fcprojw) are further scaled by 1/sqrt(2 * L),
+    /// biases are zero and layer normalization weights are one. The padded rows of wte stay zero.
+    pub fn from_config(config: GPT2Config, seed: u64) -> Result<Self> {
+        config.validate()?;
+        let mut model = GPT2::with_config(config);
+
+        let V = model.config.vocab_size;
+        let L = model.config.num_layers;
+        let C = model.config.channels;
+        let std = 0.02;
+        let residual_std = std / (2.0 * L as f32).sqrt();
+        // xorshift must not start from 0
+        let mut rng_state = seed.max(1);
+
+        unsafe {
+            model.params_memory = model.params.alloc_and_point_parameters(&model.param_sizes);
+            let params = model.params;
+            let tensor = |ptr: SendPtr<f32>, index: usize| {
+                slice::from_raw_parts_mut(ptr.ptr, model.param_sizes[index])
+            };
+
+            let wte = tensor(params.wte, 0);
+            normal_fill(&mut wte[..V * C], 0.0, std, &mut rng_state);
+            wte[V * C..].fill(0.0);
+            normal_fill(tensor(params.wpe, 1), 0.0, std, &mut rng_state);
+            normal_fill(tensor(params.qkvw, 4), 0.0, std, &mut rng_state);
+            normal_fill(tensor(params.attprojw, 6), 0.0, residual_std, &mut rng_state);
+            normal_fill(tensor(params.fcw, 10), 0.0, std, &mut rng_state);
+            normal_fill(tensor(params.fcprojw, 12), 0.0, residual_std, &mut rng_state);
+
+            for (ptr, index) in [(params.ln1w, 2), (params.ln2w, 8), (params.lnfw, 14)] {
+                tensor(ptr, index).fill(1.0);
+            }
+            for (ptr, index) in [
+                (params.ln1b, 3),
+                (params.qkvb, 5),
+                (params.attprojb, 7),
+                (params.ln2b, 9),
+                (params.fcb, 11),
+                (params.fcprojb, 13),
+                (params.lnfb, 15),
+            ] {
+                tensor(ptr, index).fill(0.0);
+            }
+        }
+
+        Ok(model)
+    }
+
+    /// Saves the model parameters to an fp32 checkpoint file (version 3), readable by `GPT2::new` and llm.c.
+    ///
+    /// # Arguments
+    ///
+    /// * `checkpoint_path` - Path of the checkpoint file to write.
+    pub fn save(&self, checkpoint_path: &Path) -> Result<()> {
+        write_atomically(checkpoint_path, |model_file| self.write_checkpoint(model_file, 3))
+    }
+
+    /// Saves the model parameters to a bf16 checkpoint file (version 5), readable by `GPT2::new` and llm.c.
+    ///
+    /// # Arguments
+    ///
+    /// * `checkpoint_path` - Path of the checkpoint file to write.
+    pub fn save_bf16(&self, checkpoint_path: &Path) -> Result<()> {
+        write_atomically(checkpoint_path, |model_file| self.write_checkpoint(model_file, 5))
+    }
+
+    /// Writes the 256-int header and the parameter tensors in `param_sizes` order.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_file` - Where to write the checkpoint.
+    /// * `version` - Checkpoint version, 3 for fp32 or 5 for bf16 parameters.
+    pub(crate) fn write_checkpoint(&self, model_file: &mut impl Write, version: i32) -> Result<()> {
+        let mut model_header = [0i32; 256];
+        model_header[0] = 20240326;
+        model_header[1] = version;
+        model_header[2] = self.config.max_seq_len as i32;
+        model_header[3] = self.config.vocab_size as i32;
+        model_header[4] = self.config.num_layers as i32;
+        model_header[5] = self.config.num_heads as i32;
+        model_header[6] = self.config.channels as i32;
+        model_header[7] = self.config.padded_vocab_size as i32;
+
+        for value in model_header {
+            model_file.write_all(&value.to_le_bytes())?;
+        }
+
+        let params = unsafe { slice::from_raw_parts(self.params_memory.ptr, self.num_parameters) };
+        for &param in params {
+            if version == 5 {
+                model_file.write_all(&f32_to_bf16(param).to_le_bytes())?;
+            } else {
+                model_file.write_all(&param.to_le_bytes())?;
+            }
+        }
+        Ok(())
     }
 
     /// Performs the forward pass for a GPT-2 model, computing token embeddings, attention layers,
@@ -243,9 +543,38 @@ impl GPT2 {
     /// * `model` - Mutable reference to the GPT-2 model containing parameters and buffers.
     /// * `inputs` - Input tensor containing token indices.
     /// * `targets` - Target tensor containing token indices for loss calculation (optional).
+    ///   Positions whose target is `IGNORE_INDEX` are left out of the loss.
     /// * `B` - Batch size.
     /// * `T` - Sequence length.
     pub fn forward(&mut self, inputs: SendPtr<i32>, targets: SendPtr<i32>, B: usize, T: usize) {
+        self.forward_with_documents(inputs, targets, SendPtr::new(null_mut()), B, T);
+    }
+
+    /// Performs the forward pass for a GPT-2 model on rows packing several documents, where each
+    /// position only attends to the positions of its own document.
+    ///
+    /// # Arguments
+    ///
+    /// * `inputs` - Input tensor containing token indices.
+    /// * `targets` - Target tensor containing token indices for loss calculation (optional).
+    ///   Positions whose target is `IGNORE_INDEX` are left out of the loss.
+    /// * `doc_starts` - Start of the document of each position (B, T), between 0 and the
+    ///   position, e.g. from `DataLoader::doc_starts`. Null makes each row a single document.
+    /// * `B` - Batch size.
+    /// * `T` - Sequence length.
+    ///
+    /// # Note
+    ///
+    /// With `reset_positions`, the position embeddings also count from the start of each document.
+    /// `backward` uses the same documents.
+    pub fn forward_with_documents(
+        &mut self,
+        inputs: SendPtr<i32>,
+        targets: SendPtr<i32>,
+        doc_starts: SendPtr<i32>,
+        B: usize,
+        T: usize,
+    ) {
         // Ensure the model was initialized or error out
         if self.params_memory.ptr.is_null() {
             panic!("Error: model was not initialized properly.");
@@ -263,7 +592,11 @@ impl GPT2 {
             for i in 0..(B * T) {
                 assert!((*inputs.ptr.add(i) >= 0 && *inputs.ptr.add(i) < V as i32));
                 if !targets.ptr.is_null() {
-                    assert!((*targets.ptr.add(i) >= 0 && *targets.ptr.add(i) < V as i32));
+                    let target = *targets.ptr.add(i);
+                    assert!((target == IGNORE_INDEX || (target >= 0 && target < V as i32)));
+                }
+                if !doc_starts.ptr.is_null() {
+                    assert!((*doc_starts.ptr.add(i) >= 0 && *doc_starts.ptr.add(i) as usize <= i % T));
                 }
             }
         }
@@ -310,6 +643,7 @@ impl GPT2 {
                 let input_layout = Layout::array::<i32>(B * T).expect("Failed to create layout");
                 self.inputs.ptr = alloc::alloc(input_layout) as *mut i32;
                 self.targets.ptr = alloc::alloc(input_layout) as *mut i32; // might be unused if we never have targets but it's small
+                self.doc_starts.ptr = alloc::alloc(input_layout) as *mut i32;
             }
         } else {
             // Validate B, T is consistent with how we've allocated the memory before
@@ -323,19 +657,34 @@ impl GPT2 {
 
         // Cache the inputs/targets
         unsafe {
+            if let Some(lora) = &mut self.lora {
+                lora.alloc_acts(B, T);
+            }
             ptr::copy_nonoverlapping(inputs.ptr, self.inputs.ptr, B * T);
             if !targets.ptr.is_null() {
                 ptr::copy_nonoverlapping(targets.ptr, self.targets.ptr, B * T);
             }
+            if doc_starts.ptr.is_null() {
+                ptr::write_bytes(self.doc_starts.ptr, 0, B * T);
+            } else {
+                ptr::copy_nonoverlapping(doc_starts.ptr, self.doc_starts.ptr, B * T);
+            }
         }
+        let doc_starts = self.doc_starts;
+        let position_starts = if self.reset_positions {
+            doc_starts
+        } else {
+            SendPtr::new(null_mut())
+        };
 
         // Forward pass
         let params = &self.params;
         let acts = &mut self.acts;
+        let lora = self.lora.as_ref();
         let mut residual: SendPtr<f32> = SendPtr::new(null_mut());
 
         unsafe {
-            encoder_forward(acts.encoded, inputs, params.wte, params.wpe, B, T, C);
+            encoder_forward(acts.encoded, inputs, params.wte, params.wpe, position_starts, B, T, C);
 
             for l in 0..L {
                 residual.ptr = if l == 0 {
@@ -380,9 +729,17 @@ impl GPT2 {
                 layernorm_forward(
                     l_ln1, l_ln1_mean, l_ln1_rstd, residual, l_ln1w, l_ln1b, B, T, C,
                 );
+                // Adds the output of the adapter of a weight to the output of its matmul
+                let adapt = |target: LoraTarget, out: SendPtr<f32>, inp: SendPtr<f32>| {
+                    if let Some(lora) = lora {
+                        lora.forward(target, l, out, inp, lora.acts(target, l), B, T);
+                    }
+                };
                 matmul_forward(l_qkv, l_ln1, l_qkvw, l_qkvb, B, T, C, 3 * C);
-                attention_forward(l_atty, l_preatt, l_att, l_qkv, B, T, C, NH);
+                adapt(LoraTarget::Qkvw, l_qkv, l_ln1);
+                attention_forward(l_atty, l_preatt, l_att, l_qkv, doc_starts, B, T, C, NH);
                 matmul_forward(l_attproj, l_atty, l_attprojw, l_attprojb, B, T, C, C);
+                adapt(LoraTarget::Attprojw, l_attproj, l_atty);
                 residual_forward(l_residual2, residual, l_attproj, B * T * C);
                 layernorm_forward(
                     l_ln2,
@@ -396,8 +753,10 @@ impl GPT2 {
                     C,
                 );
                 matmul_forward(l_fch, l_ln2, l_fcw, l_fcb, B, T, C, 4 * C);
+                adapt(LoraTarget::Fcw, l_fch, l_ln2);
                 gelu_forward(l_fch_gelu, l_fch, B * T * 4 * C);
                 matmul_forward(l_fcproj, l_fch_gelu, l_fcprojw, l_fcprojb, B, T, 4 * C, C);
+                adapt(LoraTarget::Fcprojw, l_fcproj, l_fch_gelu);
                 residual_forward(l_residual3, l_residual2, l_fcproj, B * T * C);
             }
 
@@ -419,12 +778,15 @@ impl GPT2 {
             // Forward the cross-entropy loss function if we have the targets
             if !targets.ptr.is_null() {
                 crossentropy_forward(self.acts.losses, self.acts.probs, targets, B, T, Vp);
-                // Evaluate the mean loss
+                // Evaluate the mean loss over the positions with a target, 0 if there are none
                 let mut mean_loss = 0.0;
                 for i in 0..(B * T) {
                     mean_loss += *self.acts.losses.ptr.add(i);
                 }
-                mean_loss /= (B * T) as f32;
+                let num_targets = count_targets(targets, B * T);
+                if num_targets > 0 {
+                    mean_loss /= num_targets as f32;
+                }
                 self.mean_loss = mean_loss;
             } else {
                 // If we don't have targets, we don't have a loss
@@ -438,18 +800,29 @@ impl GPT2 {
     /// # Arguments
     ///
     /// * `model` - The GPT2 model.
+    ///
+    /// # Note
+    ///
+    /// With `lora`, the weights are frozen and only the gradients of the adapters are computed.
+    ///
+    /// # Safety
+    ///
+    /// `forward` must have been called with targets on the batch just before.
     pub unsafe fn backward(&mut self) {
         // Double-check we forwarded previously, with targets
         if self.mean_loss == -1.0 {
             panic!("Error: must forward with targets before backward");
         }
 
-        // Lazily allocate memory for gradients if needed
-        if self.grads_memory.ptr.is_null() {
-            self.grads_memory = self.grads.alloc_and_point_parameters(&self.param_sizes);
+        // Lazily allocate memory for gradients if needed, the frozen weights under adapters have none
+        if self.grads_acts_memory.ptr.is_null() {
             self.grads_acts_memory = self.grads_acts.alloc_and_point_activations(&self.act_sizes);
             self.zero_grad();
         }
+        if self.grads_memory.ptr.is_null() && self.lora.is_none() {
+            self.grads_memory = self.grads.alloc_and_point_parameters(&self.param_sizes);
+            self.zero_grad();
+        }
 
         // Convenience shortcuts
         let B = self.batch_size;
@@ -460,16 +833,40 @@ impl GPT2 {
         let NH = self.config.num_heads;
         let C = self.config.channels;
 
+        // Positions only attend within their documents, as in the forward pass
+        let doc_starts = self.doc_starts;
+        let position_starts = if self.reset_positions {
+            doc_starts
+        } else {
+            SendPtr::new(null_mut())
+        };
+
         // Start backpropagation
         let params = &self.params;
         let grads = &mut self.grads;
         let acts = &self.acts;
         let grads_acts = &mut self.grads_acts;
+        let lora = self.lora.as_ref();
+        // The weights are frozen under adapters, the passes only backpropagate to the inputs then
+        let frozen = lora.is_some();
+        let param_grad = |grad: SendPtr<f32>, offset: usize| {
+            if frozen {
+                SendPtr::new(null_mut())
+            } else {
+                SendPtr::new(grad.ptr.add(offset))
+            }
+        };
 
-        // Kick off the chain rule by filling in dlosses with 1.0 / (B * T)
-        let dloss_mean = 1.0 / (B * T) as f32;
+        // Kick off the chain rule by filling in dlosses with 1.0 / (number of targets), the
+        // ignored positions get no gradient
+        let num_targets = count_targets(self.targets, B * T);
+        let dloss_mean = 1.0 / num_targets.max(1) as f32;
         for i in 0..(B * T) {
-            *grads_acts.losses.ptr.add(i) = dloss_mean;
+            *grads_acts.losses.ptr.add(i) = if *self.targets.ptr.add(i) == IGNORE_INDEX {
+                0.0
+            } else {
+                dloss_mean
+            };
         }
 
         crossentropy_softmax_backward(
@@ -484,7 +881,7 @@ impl GPT2 {
         );
         matmul_backward(
             grads_acts.lnf,
-            grads.wte,
+            param_grad(grads.wte, 0),
             SendPtr::new(null_mut()),
             grads_acts.logits,
             acts.lnf,
@@ -498,8 +895,8 @@ impl GPT2 {
         let mut dresidual = SendPtr::new(grads_acts.residual3.ptr.add((L - 1) * B * T * C)); // write to last layer's residual
         layernorm_backward(
             dresidual,
-            grads.lnfw,
-            grads.lnfb,
+            param_grad(grads.lnfw, 0),
+            param_grad(grads.lnfb, 0),
             grads_acts.lnf,
             residual,
             params.lnfw,
@@ -531,18 +928,18 @@ impl GPT2 {
             let l_fcprojw = SendPtr::new(params.fcprojw.ptr.add(l * C * 4 * C));
 
             // Get the pointers of the gradients of the weights for this layer
-            let dl_ln1w = SendPtr::new(grads.ln1w.ptr.add(l * C));
-            let dl_ln1b = SendPtr::new(grads.ln1b.ptr.add(l * C));
-            let dl_qkvw = SendPtr::new(grads.qkvw.ptr.add(l * 3 * C * C));
-            let dl_qkvb = SendPtr::new(grads.qkvb.ptr.add(l * 3 * C));
-            let dl_attprojw = SendPtr::new(grads.attprojw.ptr.add(l * C * C));
-            let dl_attprojb = SendPtr::new(grads.attprojb.ptr.add(l * C));
-            let dl_ln2w = SendPtr::new(grads.ln2w.ptr.add(l * C));
-            let dl_ln2b = SendPtr::new(grads.ln2b.ptr.add(l * C));
-            let dl_fcw = SendPtr::new(grads.fcw.ptr.add(l * 4 * C * C));
-            let dl_fcb = SendPtr::new(grads.fcb.ptr.add(l * 4 * C));
-            let dl_fcprojw = SendPtr::new(grads.fcprojw.ptr.add(l * C * 4 * C));
-            let dl_fcprojb = SendPtr::new(grads.fcprojb.ptr.add(l * C));
+            let dl_ln1w = param_grad(grads.ln1w, l * C);
+            let dl_ln1b = param_grad(grads.ln1b, l * C);
+            let dl_qkvw = param_grad(grads.qkvw, l * 3 * C * C);
+            let dl_qkvb = param_grad(grads.qkvb, l * 3 * C);
+            let dl_attprojw = param_grad(grads.attprojw, l * C * C);
+            let dl_attprojb = param_grad(grads.attprojb, l * C);
+            let dl_ln2w = param_grad(grads.ln2w, l * C);
+            let dl_ln2b = param_grad(grads.ln2b, l * C);
+            let dl_fcw = param_grad(grads.fcw, l * 4 * C * C);
+            let dl_fcb = param_grad(grads.fcb, l * 4 * C);
+            let dl_fcprojw = param_grad(grads.fcprojw, l * C * 4 * C);
+            let dl_fcprojb = param_grad(grads.fcprojb, l * C);
 
             // Get the pointers of the activations for this layer
             let l_ln1 = SendPtr::new(acts.ln1.ptr.add(l * B * T * C));
@@ -586,8 +983,14 @@ impl GPT2 {
                 4 * C,
                 C,
             );
+            if let Some(lora) = lora {
+                lora.backward(LoraTarget::Fcprojw, l, dl_fch_gelu, dl_fcproj, l_fch_gelu);
+            }
             gelu_backward(dl_fch, l_fch, dl_fch_gelu, B * T * 4 * C);
             matmul_backward(dl_ln2, dl_fcw, dl_fcb, dl_fch, l_ln2, l_fcw, B, T, C, 4 * C);
+            if let Some(lora) = lora {
+                lora.backward(LoraTarget::Fcw, l, dl_ln2, dl_fch, l_ln2);
+            }
             layernorm_backward(
                 dl_residual2,
                 dl_ln2w,
@@ -614,8 +1017,11 @@ impl GPT2 {
                 C,
                 C,
             );
+            if let Some(lora) = lora {
+                lora.backward(LoraTarget::Attprojw, l, dl_atty, dl_attproj, l_atty);
+            }
             attention_backward(
-                dl_qkv, dl_preatt, dl_att, dl_atty, l_qkv, l_att, B, T, C, NH,
+                dl_qkv, dl_preatt, dl_att, dl_atty, l_qkv, l_att, doc_starts, B, T, C, NH,
             );
             matmul_backward(
                 dl_ln1,
@@ -629,20 +1035,26 @@ impl GPT2 {
                 C,
                 3 * C,
             );
+            if let Some(lora) = lora {
+                lora.backward(LoraTarget::Qkvw, l, dl_ln1, dl_qkv, l_ln1);
+            }
             layernorm_backward(
                 dresidual, dl_ln1w, dl_ln1b, dl_ln1, residual, l_ln1w, l_ln1_mean, l_ln1_rstd, B,
                 T, C,
             );
         }
-        encoder_backward(
-            grads.wte,
-            grads.wpe,
-            grads_acts.encoded,
-            self.inputs,
-            B,
-            T,
-            C,
-        );
+        if !frozen {
+            encoder_backward(
+                grads.wte,
+                grads.wpe,
+                grads_acts.encoded,
+                self.inputs,
+                position_starts,
+                B,
+                T,
+                C,
+            );
+        }
     }
 
     /// Sets all gradients in the model to zero.
@@ -650,6 +1062,10 @@ impl GPT2 {
     /// # Arguments
     ///
     /// * `model` - The GPT2 model.
+    ///
+    /// # Safety
+    ///
+    /// The model must not have been freed.
     pub unsafe fn zero_grad(&mut self) {
         if !self.grads_memory.ptr.is_null() {
             // Create a slice from the grads_memory pointer
@@ -665,9 +1081,14 @@ impl GPT2 {
             // Set all elements in the grads_acts_slice to 0
             ptr::write_bytes(grads_acts_slice.as_mut_ptr(), 0, self.num_activations);
         }
+
+        if let Some(lora) = &mut self.lora {
+            lora.zero_grad();
+        }
     }
 
-    /// Updates the GPT2 model parameters using AdamW optimization.
+    /// Updates the GPT2 model parameters using AdamW optimization. With `lora`, only the adapters
+    /// are updated and the base weights stay frozen.
     ///
     /// # Arguments
     ///
@@ -678,6 +1099,14 @@ impl GPT2 {
     /// * `eps` - Small constant for numerical stability.
     /// * `weight_decay` - Weight decay coefficient.
     /// * `t` - Time step.
+    ///
+    /// # Returns
+    ///
+    /// An error if the parameters are memory-mapped, which leaves the model unchanged.
+    ///
+    /// # Safety
+    ///
+    /// The model must not have been freed.
     pub unsafe fn update(
         &mut self,
         learning_rate: f32,
@@ -686,7 +1115,18 @@ impl GPT2 {
         eps: f32,
         weight_decay: f32,
         t: usize,
-    ) {
+    ) -> Result<()> {
+        if let Some(lora) = &mut self.lora {
+            lora.update(learning_rate, beta1, beta2, eps, weight_decay, t);
+            return Ok(());
+        }
+        if self.params_mmap.is_some() {
+            return Err(Error::InvalidInput(
+                "memory-mapped parameters are read-only, load the model with GPT2::new to train it"
+                    .to_string(),
+            ));
+        }
+
         // Lazily allocate the memory for m_memory and v_memory
         if self.m_memory.ptr.is_null() {
             let m_layout = Layout::array::<f32>(self.num_parameters).unwrap();
@@ -697,27 +1137,46 @@ impl GPT2 {
             self.v_memory = SendPtr::new(alloc::alloc_zeroed(v_layout) as *mut f32);
         }
 
-        // Iterate over the parameters and update using AdamW
-        for i in 0..self.num_parameters {
-            let param = *self.params_memory.ptr.add(i);
-            let grad = *self.grads_memory.ptr.add(i);
-
-            // Update the first moment (momentum)
-            let m = beta1 * *self.m_memory.ptr.add(i) + (1.0 - beta1) * grad;
-            // Update the second moment (RMSprop)
-            let v = beta2 * *self.v_memory.ptr.add(i) + (1.0 - beta2) * grad * grad;
-            // Bias-correct both moments
-            let m_hat = m / (1.0 - beta1.powi(t as i32));
-            let v_hat = v / (1.0 - beta2.powi(t as i32));
-
-            // Update m and v in the model
-            *self.m_memory.ptr.add(i) = m;
-            *self.v_memory.ptr.add(i) = v;
+        adamw_update(
+            self.params_memory,
+            self.grads_memory,
+            self.m_memory,
+            self.v_memory,
+            self.num_parameters,
+            learning_rate,
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+            t,
+        );
+        Ok(())
+    }
 
-            // Update the parameters
-            *self.params_memory.ptr.add(i) -=
-                learning_rate * (m_hat / (v_hat.sqrt() + eps) + weight_decay * param);
+    /// Adds the low-rank adapters to the weights and removes them, so that the model computes the
+    /// same outputs as a plain GPT-2, e.g. to save it with `save` or `save_safetensors`.
+    ///
+    /// # Note
+    ///
+    /// Does nothing without adapters. The AdamW buffers of the adapters are dropped with them.
+    ///
+    /// # Returns
+    ///
+    /// An error if the parameters are memory-mapped, in which case the adapters are kept.
+    pub fn merge_lora(&mut self) -> Result<()> {
+        if self.lora.is_some() && self.params_mmap.is_some() {
+            return Err(Error::InvalidInput(
+                "memory-mapped parameters are read-only, load the model with GPT2::new to merge adapters"
+                    .to_string(),
+            ));
+        }
+        if let Some(mut lora) = self.lora.take() {
+            unsafe {
+                lora.merge_into(&self.params);
+                lora.free();
+            }
         }
+        Ok(())
     }
 
     /// Frees the memory allocated for the GPT2 model.
@@ -725,6 +1184,10 @@ impl GPT2 {
     /// # Arguments
     ///
     /// * `model` - The GPT2 model.
+    ///
+    /// # Safety
+    ///
+    /// The model must not be used afterwards.
     pub unsafe fn free(&mut self) {
         unsafe fn free_memory<T>(send_ptr: SendPtr<T>, num_elements: usize) {
             if !send_ptr.ptr.is_null() {
@@ -733,8 +1196,10 @@ impl GPT2 {
             }
         }
 
-        // Deallocate memory for model parameters
-        free_memory(self.params_memory, self.num_parameters);
+        // Deallocate memory for model parameters, or unmap them
+        if self.params_mmap.take().is_none() {
+            free_memory(self.params_memory, self.num_parameters);
+        }
         free_memory(self.grads_memory, self.num_parameters);
         free_memory(self.m_memory, self.num_parameters);
         free_memory(self.v_memory, self.num_parameters);
@@ -746,6 +1211,7 @@ impl GPT2 {
         // Deallocate memory for inputs and targets
         free_memory(self.inputs, self.batch_size * self.seq_len);
         free_memory(self.targets, self.batch_size * self.seq_len);
+        free_memory(self.doc_starts, self.batch_size * self.seq_len);
 
         // Set pointers to null after deallocation
         self.params_memory = SendPtr::new(null_mut());
@@ -756,5 +1222,326 @@ impl GPT2 {
         self.grads_acts_memory = SendPtr::new(null_mut());
         self.inputs = SendPtr::new(null_mut());
         self.targets = SendPtr::new(null_mut());
+        self.doc_starts = SendPtr::new(null_mut());
+
+        // Deallocate the adapters
+        if let Some(mut lora) = self.lora.take() {
+            lora.free();
+        }
+    }
+}
+
+/// Counts the targets that are not `IGNORE_INDEX`.
+///
+/// # Arguments
+///
+/// * `targets` - Target indices.
+/// * `N` - Number of targets.
+unsafe fn count_targets(targets: SendPtr<i32>, N: usize) -> usize {
+    slice::from_raw_parts(targets.ptr, N)
+        .iter()
+        .filter(|&&target| target != IGNORE_INDEX)
+        .count()
+}
+
+/// Reads and checks the header of a checkpoint file.
+///
+/// # Arguments
+///
+/// * `model_file` - Checkpoint file, positioned at its start.
+/// * `file_size` - Size of the checkpoint file in bytes.
+///
+/// # Returns
+///
+/// The model configuration and the size in bytes of each stored parameter.
+fn read_model_header(model_file: &mut File, file_size: u64) -> Result<(GPT2Config, usize)> {
+    let mut model_header = [0i32; 256];
+    if file_size < MODEL_HEADER_BYTES {
+        return Err(Error::Truncated {
+            expected: MODEL_HEADER_BYTES,
+            actual: file_size,
+        });
+    }
+    model_file.read_exact(unsafe {
+        slice::from_raw_parts_mut(
+            model_header.as_mut_ptr() as *mut u8,
+            model_header.len() * mem::size_of::<i32>(),
+        )
+    })?;
+
+    // Check magic number and version
+    if model_header[0] != 20240326 {
+        return Err(Error::BadMagic {
+            expected: 20240326,
+            found: model_header[0],
+        });
+    }
+    // Version 3 stores the parameters in fp32, version 5 in bf16
+    let param_bytes = match model_header[1] {
+        3 => mem::size_of::<f32>(),
+        5 => mem::size_of::<u16>(),
+        version => return Err(Error::BadVersion(version)),
+    };
+
+    // Read in hyperparameters
+    if let Some(&value) = model_header[2..8].iter().find(|&&value| value <= 0) {
+        return Err(Error::InconsistentHeader(format!(
+            "hyperparameters must be positive, found {}",
+            value
+        )));
+    }
+    let config = GPT2Config {
+        max_seq_len: model_header[2] as usize,
+        vocab_size: model_header[3] as usize,
+        padded_vocab_size: model_header[7] as usize,
+        num_layers: model_header[4] as usize,
+        num_heads: model_header[5] as usize,
+        channels: model_header[6] as usize,
+    };
+    config.validate()?;
+
+    Ok((config, param_bytes))
+}
+
+/// Reads bf16 values from `file` and upcasts them to fp32 into `out`.
+///
+/// # Arguments
+///
+/// * `file` - File positioned at the start of the bf16 values.
+/// * `out` - Destination slice, one fp32 per bf16 value to read.
+fn read_bf16_into(file: &mut File, out: &mut [f32]) -> io::Result<()> {
+    // Go through a small buffer instead of holding the whole bf16 payload in memory
+    let mut buffer = vec![0u8; 1 << 20];
+    for out_chunk in out.chunks_mut(buffer.len() / mem::size_of::<u16>()) {
+        let bytes = &mut buffer[..out_chunk.len() * mem::size_of::<u16>()];
+        file.read_exact(bytes)?;
+        for (value, bf16) in out_chunk.iter_mut().zip(bytes.chunks_exact(mem::size_of::<u16>())) {
+            // bf16 is the upper half of an fp32
+            *value = f32::from_bits((u16::from_le_bytes([bf16[0], bf16[1]]) as u32) << 16);
+        }
+    }
+    Ok(())
+}
+
+/// Converts an fp32 value to bf16, rounding to the nearest even value like PyTorch does.
+///
+/// # Arguments
+///
+/// * `value` - The fp32 value to convert.
+///
+/// # Returns
+///
+/// The bits of the bf16 value.
+fn f32_to_bf16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    if value.is_nan() {
+        // Keep it a (quiet) NaN even if the payload only lives in the lower half
+        return ((bits >> 16) | 0x40) as u16;
+    }
+    let rounding_bias = 0x7FFF + ((bits >> 16) & 1);
+    ((bits + rounding_bias) >> 16) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::random_u32;
+
+    /// Outputs of a forward and backward pass.
+    struct Pass {
+        /// Logits (B, T, Vp).
+        logits: Vec<f32>,
+        /// Mean loss over the targets.
+        loss: f32,
+        /// Gradients of all the parameters.
+        grads: Vec<f32>,
+    }
+
+    /// Random tokens between 0 and V.
+    fn random_tokens(n: usize, V: usize, rng_state: &mut u64) -> Vec<i32> {
+        (0..n).map(|_| (random_u32(rng_state) as usize % V) as i32).collect()
+    }
+
+    /// Runs a forward and backward pass, with `doc_starts` if any.
+    fn run(model: &mut GPT2, inputs: &[i32], targets: &[i32], doc_starts: Option<&[i32]>, B: usize, T: usize) -> Pass {
+        let Vp = model.config.padded_vocab_size;
+        let as_ptr = |tokens: &[i32]| SendPtr::new(tokens.as_ptr() as *mut i32);
+        let doc_starts = doc_starts.map_or(SendPtr::new(null_mut()), as_ptr);
+        model.forward_with_documents(as_ptr(inputs), as_ptr(targets), doc_starts, B, T);
+        unsafe {
+            model.zero_grad();
+            model.backward();
+            Pass {
+                logits: slice::from_raw_parts(model.acts.logits.ptr, B * T * Vp).to_vec(),
+                loss: model.mean_loss,
+                grads: slice::from_raw_parts(model.grads_memory.ptr, model.num_parameters).to_vec(),
+            }
+        }
+    }
+
+    /// The tiny model with a smaller vocabulary, padded like GPT-2's.
+    fn tiny_model() -> GPT2 {
+        GPT2::from_config(GPT2Config::tiny().with_vocab_size(1000), 42).unwrap()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32], what: &str) {
+        assert_eq!(actual.len(), expected.len());
+        for (i, (&a, &e)) in actual.iter().zip(expected).enumerate() {
+            assert!((a - e).abs() <= 1e-5 * e.abs().max(1.0), "{} {}: {} vs {}", what, i, a, e);
+        }
+    }
+
+    #[test]
+    fn packed_document_matches_its_own_row() {
+        let mut model = tiny_model();
+        model.reset_positions = true;
+        let V = model.config.vocab_size;
+        let Vp = model.config.padded_vocab_size;
+        let T = 8;
+        let mut rng_state = 3;
+        // A document of 3 tokens, then one of 5 tokens whose targets are the only ones counted
+        let first = random_tokens(3, V, &mut rng_state);
+        let second = random_tokens(5, V, &mut rng_state);
+        let second_targets = random_tokens(5, V, &mut rng_state);
+
+        let packed_inputs = [first.clone(), second.clone()].concat();
+        let packed_targets = [vec![IGNORE_INDEX; 3], second_targets.clone()].concat();
+        let doc_starts = [0, 0, 0, 3, 3, 3, 3, 3];
+        let packed = run(&mut model, &packed_inputs, &packed_targets, Some(&doc_starts), 1, T);
+
+        // The second document alone at the start of a row, the positions after it don't matter
+        let alone_inputs = [second, first].concat();
+        let alone_targets = [second_targets, vec![IGNORE_INDEX; 3]].concat();
+        let alone = run(&mut model, &alone_inputs, &alone_targets, None, 1, T);
+
+        assert_close(&packed.logits[3 * Vp..], &alone.logits[..5 * Vp], "logit");
+        assert!((packed.loss - alone.loss).abs() <= 1e-6, "{} vs {}", packed.loss, alone.loss);
+        assert_close(&packed.grads, &alone.grads, "gradient");
+        unsafe { model.free() };
+    }
+
+    #[test]
+    fn zero_doc_starts_match_forward() {
+        // B * T multiple of 8 or not, for both attention kernels
+        for (B, T) in [(2, 8), (1, 7)] {
+            for reset_positions in [false, true] {
+                let mut model = tiny_model();
+                model.reset_positions = reset_positions;
+                let V = model.config.vocab_size;
+                let mut rng_state = 5;
+                let inputs = random_tokens(B * T, V, &mut rng_state);
+                let targets = random_tokens(B * T, V, &mut rng_state);
+
+                let plain = run(&mut model, &inputs, &targets, None, B, T);
+                let zeros = run(&mut model, &inputs, &targets, Some(&vec![0; B * T]), B, T);
+                assert_eq!(zeros.logits, plain.logits);
+                assert_eq!(zeros.loss, plain.loss);
+                assert_eq!(zeros.grads, plain.grads);
+                unsafe { model.free() };
+            }
+        }
+    }
+
+    /// Parameters of a model, copied out of its memory.
+    fn params(model: &GPT2) -> Vec<f32> {
+        unsafe { slice::from_raw_parts(model.params_memory.ptr, model.num_parameters).to_vec() }
+    }
+
+    #[test]
+    fn saved_checkpoints_load_back() {
+        let dir = std::env::temp_dir().join(format!("llm-rs-save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut model = tiny_model();
+        let expected = params(&model);
+
+        // fp32 keeps every bit
+        let path = dir.join("model.bin");
+        model.save(&path).unwrap();
+        let mut loaded = GPT2::new(&path).unwrap();
+        assert_eq!(loaded.config, model.config);
+        assert!(params(&loaded).iter().zip(&expected).all(|(a, e)| a.to_bits() == e.to_bits()));
+        unsafe { loaded.free() };
+
+        // bf16 keeps the 8 upper bits of the mantissa, rounded
+        let path = dir.join("model_bf16.bin");
+        model.save_bf16(&path).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            MODEL_HEADER_BYTES + 2 * model.num_parameters as u64
+        );
+        let mut loaded = GPT2::new(&path).unwrap();
+        assert_eq!(loaded.config, model.config);
+        for (&a, &e) in params(&loaded).iter().zip(&expected) {
+            assert!((a - e).abs() <= e.abs() / 256.0, "{} vs {}", a, e);
+        }
+        unsafe { loaded.free() };
+
+        unsafe { model.free() };
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn f32_to_bf16_rounds_to_nearest_even() {
+        let bf16 = |bits: u32| f32_to_bf16(f32::from_bits(bits));
+        // Exact values keep their upper half
+        assert_eq!(f32_to_bf16(1.0), 0x3F80);
+        assert_eq!(f32_to_bf16(-2.0), 0xC000);
+        assert_eq!(f32_to_bf16(-0.0), 0x8000);
+        // Below, above and exactly halfway between two bf16 values
+        assert_eq!(bf16(0x3F80_7FFF), 0x3F80);
+        assert_eq!(bf16(0x3F80_8001), 0x3F81);
+        assert_eq!(bf16(0x3F80_8000), 0x3F80);
+        assert_eq!(bf16(0x3F81_8000), 0x3F82);
+        // The carry goes into the exponent, and past the largest value to infinity
+        assert_eq!(bf16(0x3FFF_FFFF), 0x4000);
+        assert_eq!(f32_to_bf16(f32::MAX), 0x7F80);
+        assert_eq!(f32_to_bf16(f32::INFINITY), 0x7F80);
+        assert_eq!(f32_to_bf16(f32::NEG_INFINITY), 0xFF80);
+        // NaNs stay NaNs, even with the payload in the lower half only
+        assert_eq!(f32_to_bf16(f32::NAN), 0x7FC0);
+        for bits in [0x7F80_0001, 0xFF80_0001, 0x7FFF_FFFF] {
+            assert!(f32::from_bits((bf16(bits) as u32) << 16).is_nan(), "{:#x}", bits);
+        }
+    }
+
+    #[test]
+    fn mapped_model_matches_loaded_model() {
+        let dir = std::env::temp_dir().join(format!("llm-rs-mmap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.bin");
+        let mut model = tiny_model();
+        model.save(&path).unwrap();
+        unsafe { model.free() };
+
+        let (B, T) = (2, 8);
+        let mut rng_state = 9;
+        let inputs = random_tokens(B * T, 1000, &mut rng_state);
+        let logits = |model: &mut GPT2| {
+            model.forward(SendPtr::new(inputs.as_ptr() as *mut i32), SendPtr::new(null_mut()), B, T);
+            unsafe { slice::from_raw_parts(model.acts.logits.ptr, B * T * model.config.padded_vocab_size).to_vec() }
+        };
+        let mut loaded = GPT2::new(&path).unwrap();
+        let mut mapped = GPT2::new_mmap(&path).unwrap();
+        assert_eq!(mapped.config, loaded.config);
+        assert_eq!(logits(&mut mapped), logits(&mut loaded));
+
+        // The mapping is read-only
+        unsafe {
+            assert!(matches!(mapped.update(1e-4, 0.9, 0.999, 1e-8, 0.0, 1), Err(Error::InvalidInput(_))));
+        }
+        mapped.lora = Some(LoraAdapters::new(&mapped.config, 4, 8.0, 1));
+        assert!(matches!(mapped.merge_lora(), Err(Error::InvalidInput(_))));
+        assert!(mapped.lora.is_some());
+
+        // bf16 checkpoints cannot be used in place
+        let bf16_path = dir.join("model_bf16.bin");
+        loaded.save_bf16(&bf16_path).unwrap();
+        assert!(matches!(GPT2::new_mmap(&bf16_path), Err(Error::InconsistentHeader(_))));
+
+        unsafe {
+            loaded.free();
+            mapped.free();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
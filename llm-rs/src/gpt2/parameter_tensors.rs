@@ -56,6 +56,12 @@ pub struct ParameterTensors {
     pub lnfb: SendPtr<f32>,
 }
 
+impl Default for ParameterTensors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ParameterTensors {
     /// Creates a new ParameterTensors instance.
     ///
@@ -93,6 +99,11 @@ impl ParameterTensors {
     /// # Returns
     ///
     /// * Pointer to the allocated memory for parameters.
+    ///
+    /// # Safety
+    ///
+    /// The returned memory is not freed when the tensors are dropped, it must be freed with the
+    /// layout of the sum of `param_sizes`.
     pub unsafe fn alloc_and_point_parameters(
         &mut self,
         param_sizes: &[usize; NUM_PARAMETER_TENSORS],
@@ -110,6 +121,26 @@ impl ParameterTensors {
         }
 
         // Assign the tensors to the allocated memory
+        self.point_parameters(params_memory, param_sizes);
+
+        params_memory
+    }
+
+    /// Sets the parameter tensor pointers within a `ParameterTensors` structure to an existing memory block.
+    ///
+    /// # Arguments
+    ///
+    /// * `params_memory` - Pointer to a memory block holding all the parameter tensors one after the other.
+    /// * `param_sizes` - Array of sizes for each parameter tensor.
+    ///
+    /// # Safety
+    ///
+    /// `params_memory` must hold at least the sum of `param_sizes` values, and outlive the tensors.
+    pub unsafe fn point_parameters(
+        &mut self,
+        params_memory: SendPtr<f32>,
+        param_sizes: &[usize; NUM_PARAMETER_TENSORS],
+    ) {
         let mut params_memory_iterator = params_memory;
         let mut ptrs: [*mut SendPtr<f32>; NUM_PARAMETER_TENSORS] = [
             &mut self.wte,
@@ -134,7 +165,5 @@ impl ParameterTensors {
             **ptr = params_memory_iterator;
             params_memory_iterator.ptr = params_memory_iterator.ptr.add(param_sizes[i]);
         }
-
-        params_memory
     }
 }
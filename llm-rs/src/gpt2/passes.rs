@@ -1,10 +1,37 @@
+// The kernels are a direct port of llm.c: they take many raw pointer arguments and rebind
+// `SendPtr`s inside rayon closures so that the whole wrapper (not the raw field) gets captured.
+#![allow(
+    clippy::too_many_arguments,
+    clippy::redundant_locals,
+    clippy::needless_range_loop
+)]
+
 use rayon::prelude::*;
 use std::f32::consts::PI;
 
+use super::IGNORE_INDEX;
 use crate::send_ptr::SendPtr;
 
 const LOOP_UNROLL: usize = 8;
 
+/// Returns the position where the document of position `t` of row `b` starts.
+///
+/// # Arguments
+///
+/// * `doc_starts` - Start of the document of each position (B, T), or null when each row is a
+///   single document.
+/// * `b` - Row of the batch.
+/// * `t` - Position in the row.
+/// * `T` - Sequence length.
+#[inline]
+unsafe fn doc_start(doc_starts: SendPtr<i32>, b: usize, t: usize, T: usize) -> usize {
+    if doc_starts.ptr.is_null() {
+        0
+    } else {
+        *doc_starts.ptr.add(b * T + t) as usize
+    }
+}
+
 // ----------------------------------------------------------------------------
 // All the individual layers' forward and backward passes
 // B = batch_size, T = sequence_length, C = channels, V = vocab_size
@@ -18,6 +45,8 @@ const LOOP_UNROLL: usize = 8;
 /// * `inp` - Input tensor containing token indices.
 /// * `wte` - Token embedding matrix.
 /// * `wpe` - Positional embedding matrix.
+/// * `doc_starts` - Start of the document of each position, to count the positions from it. Null
+///   counts them from the start of the row.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `C` - Embedding dimension.
@@ -26,6 +55,7 @@ pub unsafe fn encoder_forward(
     inp: SendPtr<i32>,
     wte: SendPtr<f32>,
     wpe: SendPtr<f32>,
+    doc_starts: SendPtr<i32>,
     B: usize,
     T: usize,
     C: usize,
@@ -36,11 +66,12 @@ pub unsafe fn encoder_forward(
             let inp = inp;
             let wte = wte;
             let wpe = wpe;
+            let doc_starts = doc_starts;
 
             let out_bt = out.ptr.add(b * T * C + t * C);
             let ix = *inp.ptr.add(b * T + t) as usize;
             let wte_ix = wte.ptr.add(ix * C);
-            let wpe_t = wpe.ptr.add(t * C);
+            let wpe_t = wpe.ptr.add((t - doc_start(doc_starts, b, t, T)) * C);
 
             for i in 0..C {
                 *out_bt.add(i) = *wte_ix.add(i) + *wpe_t.add(i);
@@ -57,6 +88,7 @@ pub unsafe fn encoder_forward(
 /// * `dwpe` - Gradient of the positional embedding matrix.
 /// * `dout` - Gradient of the output tensor.
 /// * `inp` - Input tensor containing token indices.
+/// * `doc_starts` - Start of the document of each position, as given to `encoder_forward`.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `C` - Embedding dimension.
@@ -65,6 +97,7 @@ pub unsafe fn encoder_backward(
     dwpe: SendPtr<f32>,
     dout: SendPtr<f32>,
     inp: SendPtr<i32>,
+    doc_starts: SendPtr<i32>,
     B: usize,
     T: usize,
     C: usize,
@@ -75,11 +108,12 @@ pub unsafe fn encoder_backward(
             let dwpe = dwpe;
             let dout = dout;
             let inp = inp;
+            let doc_starts = doc_starts;
 
             let dout_bt = dout.ptr.add(b * T * C + t * C);
             let ix = *inp.ptr.add(b * T + t) as usize;
             let dwte_ix = dwte.ptr.add(ix * C);
-            let dwpe_t = dwpe.ptr.add(t * C);
+            let dwpe_t = dwpe.ptr.add((t - doc_start(doc_starts, b, t, T)) * C);
 
             for i in 0..C {
                 let d = *dout_bt.add(i);
@@ -172,7 +206,8 @@ pub unsafe fn layernorm_forward(
 /// # Arguments
 ///
 /// * `dinp` - Gradient of the input tensor.
-/// * `dweight` - Gradient of the weight vector.
+/// * `dweight` - Gradient of the weight vector. Null skips the gradients of the weight and bias,
+///   e.g. for frozen weights.
 /// * `dbias` - Gradient of the bias vector.
 /// * `dout` - Gradient of the output tensor.
 /// * `inp` - Input tensor.
@@ -230,11 +265,13 @@ pub unsafe fn layernorm_backward(
                 let norm_bti = (*inp_bt.add(i) - mean_bt) * rstd_bt;
                 let dnorm_i = *weight.ptr.add(i) * *dout_bt.add(i);
 
-                // Gradient contribution to bias
-                *dbias.ptr.add(i) += *dout_bt.add(i);
+                if !dweight.ptr.is_null() {
+                    // Gradient contribution to bias
+                    *dbias.ptr.add(i) += *dout_bt.add(i);
 
-                // Gradient contribution to weight
-                *dweight.ptr.add(i) += norm_bti * *dout_bt.add(i);
+                    // Gradient contribution to weight
+                    *dweight.ptr.add(i) += norm_bti * *dout_bt.add(i);
+                }
 
                 // Gradient contribution to input
                 let mut dval: f32 = 0.0;
@@ -339,7 +376,7 @@ pub unsafe fn matmul_forward(
     OC: usize,
 ) {
     // Fallback to naive implementation if B * T is not a multiple of LOOP_UNROLL
-    if (B * T) % LOOP_UNROLL != 0 {
+    if !(B * T).is_multiple_of(LOOP_UNROLL) {
         matmul_forward_naive(out, inp, weight, bias, B, T, C, OC);
         return;
     }
@@ -388,7 +425,8 @@ pub unsafe fn matmul_forward(
 /// # Arguments
 ///
 /// * `dinp` - Gradient of the input tensor.
-/// * `dweight` - Gradient of the weight matrix.
+/// * `dweight` - Gradient of the weight matrix. Null skips the gradients of the weight and bias,
+///   e.g. for frozen weights.
 /// * `dbias` - Gradient of the bias vector.
 /// * `dout` - Gradient of the output tensor.
 /// * `inp` - Input tensor.
@@ -415,7 +453,7 @@ pub unsafe fn matmul_backward(
     OC: usize,
 ) {
     // Fallback to naive implementation if B * T is not a multiple of LOOP_UNROLL
-    if (B * T) % LOOP_UNROLL != 0 {
+    if !(B * T).is_multiple_of(LOOP_UNROLL) {
         // Parallelize over B and T for input gradient computation
         (0..B).into_par_iter().for_each(|b| {
             (0..T).into_par_iter().for_each(|t| {
@@ -462,6 +500,10 @@ pub unsafe fn matmul_backward(
             });
     }
 
+    if dweight.ptr.is_null() {
+        return;
+    }
+
     // Parallelize over output channels for weight and bias gradient computation
     (0..OC).into_par_iter().for_each(|o| {
         for b in 0..B {
@@ -487,6 +529,163 @@ pub unsafe fn matmul_backward(
     });
 }
 
+/// Adds the output of a low-rank adapter to the output of a matrix multiplication:
+/// out += scale * (inp A^T) B^T.
+///
+/// # Arguments
+///
+/// * `out` - Output tensor of the matrix multiplication (B, T, OC).
+/// * `h` - Output of the down projection inp A^T (B, T, R), kept for the backward pass.
+/// * `inp` - Input tensor (B, T, C).
+/// * `lora_a` - Down projection of the adapter (R, C).
+/// * `lora_b` - Up projection of the adapter (OC, R).
+/// * `scale` - Scale of the adapter output.
+/// * `B` - Batch size.
+/// * `T` - Sequence length.
+/// * `C` - Input feature dimension.
+/// * `OC` - Output feature dimension.
+/// * `R` - Rank of the adapter.
+pub unsafe fn lora_forward(
+    out: SendPtr<f32>,
+    h: SendPtr<f32>,
+    inp: SendPtr<f32>,
+    lora_a: SendPtr<f32>,
+    lora_b: SendPtr<f32>,
+    scale: f32,
+    B: usize,
+    T: usize,
+    C: usize,
+    OC: usize,
+    R: usize,
+) {
+    (0..B * T).into_par_iter().for_each(|bt| {
+        let out = out;
+        let h = h;
+        let inp = inp;
+        let lora_a = lora_a;
+        let lora_b = lora_b;
+
+        let inp_bt = inp.ptr.add(bt * C);
+        let h_bt = h.ptr.add(bt * R);
+        for r in 0..R {
+            let arow = lora_a.ptr.add(r * C);
+            let mut val = 0.0;
+            for i in 0..C {
+                val += *inp_bt.add(i) * *arow.add(i);
+            }
+            *h_bt.add(r) = val;
+        }
+
+        let out_bt = out.ptr.add(bt * OC);
+        for o in 0..OC {
+            let brow = lora_b.ptr.add(o * R);
+            let mut val = 0.0;
+            for r in 0..R {
+                val += *h_bt.add(r) * *brow.add(r);
+            }
+            *out_bt.add(o) += scale * val;
+        }
+    });
+}
+
+/// Computes the backward pass of a low-rank adapter, accumulating the gradients of the input
+/// and of both projections.
+///
+/// # Arguments
+///
+/// * `dinp` - Gradient of the input tensor (B, T, C).
+/// * `dlora_a` - Gradient of the down projection (R, C).
+/// * `dlora_b` - Gradient of the up projection (OC, R).
+/// * `dout` - Gradient of the output tensor (B, T, OC).
+/// * `inp` - Input tensor (B, T, C).
+/// * `h` - Output of the down projection from the forward pass (B, T, R).
+/// * `lora_a` - Down projection of the adapter (R, C).
+/// * `lora_b` - Up projection of the adapter (OC, R).
+/// * `scale` - Scale of the adapter output.
+/// * `B` - Batch size.
+/// * `T` - Sequence length.
+/// * `C` - Input feature dimension.
+/// * `OC` - Output feature dimension.
+/// * `R` - Rank of the adapter.
+pub unsafe fn lora_backward(
+    dinp: SendPtr<f32>,
+    dlora_a: SendPtr<f32>,
+    dlora_b: SendPtr<f32>,
+    dout: SendPtr<f32>,
+    inp: SendPtr<f32>,
+    h: SendPtr<f32>,
+    lora_a: SendPtr<f32>,
+    lora_b: SendPtr<f32>,
+    scale: f32,
+    B: usize,
+    T: usize,
+    C: usize,
+    OC: usize,
+    R: usize,
+) {
+    // Gradient of the down projection output, dh = scale * dout B, then dinp += dh A
+    let mut dh_memory = vec![0.0f32; B * T * R];
+    let dh = SendPtr::new(dh_memory.as_mut_ptr());
+    (0..B * T).into_par_iter().for_each(|bt| {
+        let dinp = dinp;
+        let dout = dout;
+        let dh = dh;
+        let lora_a = lora_a;
+        let lora_b = lora_b;
+
+        let dout_bt = dout.ptr.add(bt * OC);
+        let dh_bt = dh.ptr.add(bt * R);
+        for r in 0..R {
+            let mut val = 0.0;
+            for o in 0..OC {
+                val += *dout_bt.add(o) * *lora_b.ptr.add(o * R + r);
+            }
+            *dh_bt.add(r) = scale * val;
+        }
+
+        let dinp_bt = dinp.ptr.add(bt * C);
+        for r in 0..R {
+            let arow = lora_a.ptr.add(r * C);
+            let d = *dh_bt.add(r);
+            for i in 0..C {
+                *dinp_bt.add(i) += *arow.add(i) * d;
+            }
+        }
+    });
+
+    // dB += scale * dout^T h, parallel over the output channels
+    (0..OC).into_par_iter().for_each(|o| {
+        let dout = dout;
+        let h = h;
+        let dlora_b = dlora_b;
+
+        let dbrow = dlora_b.ptr.add(o * R);
+        for bt in 0..B * T {
+            let d = scale * *dout.ptr.add(bt * OC + o);
+            let h_bt = h.ptr.add(bt * R);
+            for r in 0..R {
+                *dbrow.add(r) += d * *h_bt.add(r);
+            }
+        }
+    });
+
+    // dA += dh^T inp, parallel over the ranks
+    (0..R).into_par_iter().for_each(|r| {
+        let inp = inp;
+        let dh = dh;
+        let dlora_a = dlora_a;
+
+        let darow = dlora_a.ptr.add(r * C);
+        for bt in 0..B * T {
+            let d = *dh.ptr.add(bt * R + r);
+            let inp_bt = inp.ptr.add(bt * C);
+            for i in 0..C {
+                *darow.add(i) += d * *inp_bt.add(i);
+            }
+        }
+    });
+}
+
 /// Naive implementation of the forward pass for multi-head attention, generating output and storing attention scores.
 ///
 /// # Arguments
@@ -495,6 +694,8 @@ pub unsafe fn matmul_backward(
 /// * `preatt` - Pre-attention scores.
 /// * `att` - Post-attention scores.
 /// * `inp` - Input tensor containing query, key, and value vectors.
+/// * `doc_starts` - Start of the document of each position, to only attend within documents.
+///   Null attends to the whole row.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `C` - Feature dimension.
@@ -504,6 +705,7 @@ pub unsafe fn attention_forward_naive(
     preatt: SendPtr<f32>,
     att: SendPtr<f32>,
     inp: SendPtr<f32>,
+    doc_starts: SendPtr<i32>,
     B: usize,
     T: usize,
     C: usize,
@@ -520,13 +722,15 @@ pub unsafe fn attention_forward_naive(
                 let preatt = preatt;
                 let att = att;
                 let inp = inp;
+                let doc_starts = doc_starts;
 
+                let start = doc_start(doc_starts, b, t, T);
                 let query_t = inp.ptr.add(b * T * C3 + t * C3 + h * hs);
                 let preatt_bth = preatt.ptr.add(b * NH * T * T + h * T * T + t * T);
                 let att_bth = att.ptr.add(b * NH * T * T + h * T * T + t * T);
 
                 let mut maxval = f32::NEG_INFINITY;
-                for t2 in 0..=t {
+                for t2 in start..=t {
                     let key_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + C);
                     let mut val = 0.0;
                     for i in 0..hs {
@@ -540,7 +744,7 @@ pub unsafe fn attention_forward_naive(
                 }
 
                 let mut expsum = 0.0;
-                for t2 in 0..=t {
+                for t2 in start..=t {
                     let expv = (*preatt_bth.add(t2) - maxval).exp();
                     expsum += expv;
                     *att_bth.add(t2) = expv;
@@ -548,7 +752,7 @@ pub unsafe fn attention_forward_naive(
                 let expsum_inv = if expsum == 0.0 { 0.0 } else { 1.0 / expsum };
 
                 for t2 in 0..T {
-                    if t2 <= t {
+                    if (start..=t).contains(&t2) {
                         *att_bth.add(t2) *= expsum_inv;
                     } else {
                         *att_bth.add(t2) = 0.0;
@@ -559,7 +763,7 @@ pub unsafe fn attention_forward_naive(
                 for i in 0..hs {
                     *out_bth.add(i) = 0.0;
                 }
-                for t2 in 0..=t {
+                for t2 in start..=t {
                     let value_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C);
                     let att_btht2 = *att_bth.add(t2);
                     for i in 0..hs {
@@ -579,6 +783,8 @@ pub unsafe fn attention_forward_naive(
 /// * `preatt` - Pre-attention scores.
 /// * `att` - Post-attention scores.
 /// * `inp` - Input tensor containing query, key, and value vectors.
+/// * `doc_starts` - Start of the document of each position, to only attend within documents.
+///   Null attends to the whole row.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `C` - Feature dimension.
@@ -588,6 +794,7 @@ pub unsafe fn attention_forward(
     preatt: SendPtr<f32>,
     att: SendPtr<f32>,
     inp: SendPtr<f32>,
+    doc_starts: SendPtr<i32>,
     B: usize,
     T: usize,
     C: usize,
@@ -598,8 +805,8 @@ pub unsafe fn attention_forward(
     let scale = 1.0 / (hs as f32).sqrt(); // scale for dot product
 
     // Fallback to naive implementation if B * T is not a multiple of LOOP_UNROLL
-    if (B * T) % LOOP_UNROLL != 0 {
-        attention_forward_naive(out, preatt, att, inp, B, T, C, NH);
+    if !(B * T).is_multiple_of(LOOP_UNROLL) {
+        attention_forward_naive(out, preatt, att, inp, doc_starts, B, T, C, NH);
         return;
     }
 
@@ -611,19 +818,21 @@ pub unsafe fn attention_forward(
             let preatt = preatt;
             let att = att;
             let inp = inp;
+            let doc_starts = doc_starts;
 
             for h in 0..NH {
                 for ibt in 0..LOOP_UNROLL {
                     let bt = obt + ibt;
                     let t = bt % T;
                     let b = bt / T;
+                    let start = doc_start(doc_starts, b, t, T);
 
                     let query_t = inp.ptr.add(b * T * C3 + t * C3 + h * hs);
                     let preatt_bth = preatt.ptr.add(b * NH * T * T + h * T * T + t * T);
                     let att_bth = att.ptr.add(b * NH * T * T + h * T * T + t * T);
 
                     let mut maxval = f32::NEG_INFINITY;
-                    for t2 in 0..=t {
+                    for t2 in start..=t {
                         let key_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + C);
                         let mut val = 0.0;
                         for i in 0..hs {
@@ -637,7 +846,7 @@ pub unsafe fn attention_forward(
                     }
 
                     let mut expsum = 0.0;
-                    for t2 in 0..=t {
+                    for t2 in start..=t {
                         let expv = (*preatt_bth.add(t2) - maxval).exp();
                         expsum += expv;
                         *att_bth.add(t2) = expv;
@@ -645,7 +854,7 @@ pub unsafe fn attention_forward(
                     let expsum_inv = if expsum == 0.0 { 0.0 } else { 1.0 / expsum };
 
                     for t2 in 0..T {
-                        if t2 <= t {
+                        if (start..=t).contains(&t2) {
                             *att_bth.add(t2) *= expsum_inv;
                         } else {
                             *att_bth.add(t2) = 0.0;
@@ -656,7 +865,7 @@ pub unsafe fn attention_forward(
                     for i in 0..hs {
                         *out_bth.add(i) = 0.0;
                     }
-                    for t2 in 0..=t {
+                    for t2 in start..=t {
                         let value_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C);
                         let att_btht2 = *att_bth.add(t2);
                         for i in 0..hs {
@@ -668,6 +877,83 @@ pub unsafe fn attention_forward(
         });
 }
 
+/// Computes the forward pass of multi-head attention for new positions only, attending to the keys and
+/// values cached for all the positions up to them.
+///
+/// # Arguments
+///
+/// * `out` - Output tensor for attention results (B, N, C).
+/// * `qkv_cache` - Query, key and value vectors of the positions seen so far (B, T, 3*C), including the new ones.
+/// * `B` - Batch size.
+/// * `T` - Capacity of the cache, in positions.
+/// * `pos` - Position of the first new position.
+/// * `N` - Number of new positions.
+/// * `C` - Feature dimension.
+/// * `NH` - Number of attention heads.
+pub unsafe fn attention_forward_cached(
+    out: SendPtr<f32>,
+    qkv_cache: SendPtr<f32>,
+    B: usize,
+    T: usize,
+    pos: usize,
+    N: usize,
+    C: usize,
+    NH: usize,
+) {
+    let C3 = C * 3; // feature dimension scaled by 3
+    let hs = C / NH; // head size
+    let scale = 1.0 / (hs as f32).sqrt(); // scale for dot product
+
+    (0..B * N).into_par_iter().for_each(|bn| {
+        let out = out;
+        let qkv_cache = qkv_cache;
+
+        let b = bn / N;
+        let t = pos + bn % N;
+        let mut att = vec![0.0f32; t + 1];
+
+        for h in 0..NH {
+            let query_t = qkv_cache.ptr.add(b * T * C3 + t * C3 + h * hs);
+
+            // Scores against the keys of all the positions up to t
+            let mut maxval = f32::NEG_INFINITY;
+            for (t2, att_t2) in att.iter_mut().enumerate() {
+                let key_t2 = qkv_cache.ptr.add(b * T * C3 + t2 * C3 + h * hs + C);
+                let mut val = 0.0;
+                for i in 0..hs {
+                    val += *query_t.add(i) * *key_t2.add(i);
+                }
+                val *= scale;
+                if val > maxval {
+                    maxval = val;
+                }
+                *att_t2 = val;
+            }
+
+            let mut expsum = 0.0;
+            for att_t2 in att.iter_mut() {
+                let expv = (*att_t2 - maxval).exp();
+                expsum += expv;
+                *att_t2 = expv;
+            }
+            let expsum_inv = if expsum == 0.0 { 0.0 } else { 1.0 / expsum };
+
+            // Weighted sum of the values
+            let out_bth = out.ptr.add(bn * C + h * hs);
+            for i in 0..hs {
+                *out_bth.add(i) = 0.0;
+            }
+            for (t2, &att_t2) in att.iter().enumerate() {
+                let value_t2 = qkv_cache.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C);
+                let att_btht2 = att_t2 * expsum_inv;
+                for i in 0..hs {
+                    *out_bth.add(i) += att_btht2 * *value_t2.add(i);
+                }
+            }
+        }
+    });
+}
+
 /// Naive implementation of the backward pass for attention mechanisms, updating gradients for inputs,
 /// pre-attention weights, and attention weights.
 ///
@@ -679,6 +965,7 @@ pub unsafe fn attention_forward(
 /// * `dout` - Gradient of the output tensor.
 /// * `inp` - Input tensor.
 /// * `att` - Attention weights.
+/// * `doc_starts` - Start of the document of each position, as given to `attention_forward`.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `C` - Feature dimension.
@@ -690,6 +977,7 @@ pub unsafe fn attention_backward_naive(
     dout: SendPtr<f32>,
     inp: SendPtr<f32>,
     att: SendPtr<f32>,
+    doc_starts: SendPtr<i32>,
     B: usize,
     T: usize,
     C: usize,
@@ -708,7 +996,9 @@ pub unsafe fn attention_backward_naive(
                 let dout = dout;
                 let inp = inp;
                 let att = att;
+                let doc_starts = doc_starts;
 
+                let start = doc_start(doc_starts, b, t, T);
                 let att_bth = att.ptr.add(b * NH * T * T + h * T * T + t * T);
                 let datt_bth = datt.ptr.add(b * NH * T * T + h * T * T + t * T);
                 let dpreatt_bth = dpreatt.ptr.add(b * NH * T * T + h * T * T + t * T);
@@ -717,7 +1007,7 @@ pub unsafe fn attention_backward_naive(
 
                 // Backward pass 4: through the value accumulation
                 let dout_bth = dout.ptr.add(b * T * C + t * C + h * hs);
-                for t2 in 0..=t {
+                for t2 in start..=t {
                     let value_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C); // +C*2 because it's value
                     let dvalue_t2 = dinp.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C); // +C*2 because it's value
                     for i in 0..hs {
@@ -727,8 +1017,8 @@ pub unsafe fn attention_backward_naive(
                 }
 
                 // Backward pass 2 & 3: the softmax
-                for t2 in 0..=t {
-                    for t3 in 0..=t {
+                for t2 in start..=t {
+                    for t3 in start..=t {
                         let indicator = if t2 == t3 { 1.0 } else { 0.0 };
                         let local_derivative = *att_bth.add(t2) * (indicator - *att_bth.add(t3));
                         *dpreatt_bth.add(t3) += local_derivative * *datt_bth.add(t2);
@@ -736,7 +1026,7 @@ pub unsafe fn attention_backward_naive(
                 }
 
                 // Backward pass 1: the query @ key matmul
-                for t2 in 0..=t {
+                for t2 in start..=t {
                     let key_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + C); // +C because it's key
                     let dkey_t2 = dinp.ptr.add(b * T * C3 + t2 * C3 + h * hs + C); // +C because it's key
                     for i in 0..hs {
@@ -760,6 +1050,7 @@ pub unsafe fn attention_backward_naive(
 /// * `dout` - Gradient of the output tensor.
 /// * `inp` - Input tensor.
 /// * `att` - Attention weights.
+/// * `doc_starts` - Start of the document of each position, as given to `attention_forward`.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `C` - Feature dimension.
@@ -769,8 +1060,9 @@ pub unsafe fn attention_backward(
     dpreatt: SendPtr<f32>,
     datt: SendPtr<f32>,
     dout: SendPtr<f32>,
-    att: SendPtr<f32>,
     inp: SendPtr<f32>,
+    att: SendPtr<f32>,
+    doc_starts: SendPtr<i32>,
     B: usize,
     T: usize,
     C: usize,
@@ -781,8 +1073,8 @@ pub unsafe fn attention_backward(
     let scale = 1.0 / (hs as f32).sqrt(); // scale for dot product
 
     // Fallback to naive implementation if B * T is not a multiple of LOOP_UNROLL
-    if (B * T) % LOOP_UNROLL != 0 {
-        attention_backward_naive(dinp, dpreatt, datt, dout, inp, att, B, T, C, NH);
+    if !(B * T).is_multiple_of(LOOP_UNROLL) {
+        attention_backward_naive(dinp, dpreatt, datt, dout, inp, att, doc_starts, B, T, C, NH);
         return;
     }
 
@@ -796,11 +1088,13 @@ pub unsafe fn attention_backward(
             let dout = dout;
             let inp = inp;
             let att = att;
+            let doc_starts = doc_starts;
 
             for ibt in 0..LOOP_UNROLL {
                 let bt = obt + ibt;
                 let b = bt / T;
                 let t = bt % T;
+                let start = doc_start(doc_starts, b, t, T);
 
                 for h in 0..NH {
                     let att_bth = att.ptr.add(b * NH * T * T + h * T * T + t * T);
@@ -811,7 +1105,7 @@ pub unsafe fn attention_backward(
 
                     // Backward pass 4: through the value accumulation
                     let dout_bth = dout.ptr.add(b * T * C + t * C + h * hs);
-                    for t2 in 0..=t {
+                    for t2 in start..=t {
                         let value_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C); // +C*2 because it's value
                         let dvalue_t2 = dinp.ptr.add(b * T * C3 + t2 * C3 + h * hs + 2 * C); // +C*2 because it's value
                         for i in 0..hs {
@@ -821,8 +1115,8 @@ pub unsafe fn attention_backward(
                     }
 
                     // Backward pass 2 & 3: the softmax
-                    for t2 in 0..=t {
-                        for t3 in 0..=t {
+                    for t2 in start..=t {
+                        for t3 in start..=t {
                             let indicator = if t2 == t3 { 1.0 } else { 0.0 };
                             let local_derivative =
                                 *att_bth.add(t2) * (indicator - *att_bth.add(t3));
@@ -831,7 +1125,7 @@ pub unsafe fn attention_backward(
                     }
 
                     // Backward pass 1: the query @ key matmul
-                    for t2 in 0..=t {
+                    for t2 in start..=t {
                         let key_t2 = inp.ptr.add(b * T * C3 + t2 * C3 + h * hs + C); // +C because it's key
                         let dkey_t2 = dinp.ptr.add(b * T * C3 + t2 * C3 + h * hs + C); // +C because it's key
                         for i in 0..hs {
@@ -1008,7 +1302,7 @@ pub unsafe fn softmax_forward(
 ///
 /// * `losses` - Output losses (B, T).
 /// * `probs` - Input probabilities (B, T, Vp).
-/// * `targets` - Target indices (B, T).
+/// * `targets` - Target indices (B, T). The loss of the `IGNORE_INDEX` targets is 0.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `Vp` - Padded vocabulary size.
@@ -1030,10 +1324,14 @@ pub unsafe fn crossentropy_forward(
             let probs_bt = probs.ptr.add(b * T * Vp + t * Vp);
 
             // Get the target index
-            let ix = *targets.ptr.add(b * T + t) as usize;
+            let ix = *targets.ptr.add(b * T + t);
+            if ix == IGNORE_INDEX {
+                *losses.ptr.add(b * T + t) = 0.0;
+                return;
+            }
 
             // Compute the cross-entropy loss and store it
-            *losses.ptr.add(b * T + t) = -probs_bt.add(ix).read().ln();
+            *losses.ptr.add(b * T + t) = -probs_bt.add(ix as usize).read().ln();
         });
     });
 }
@@ -1045,7 +1343,7 @@ pub unsafe fn crossentropy_forward(
 /// * `dlogits` - Gradient of the logits (B, T, Vp).
 /// * `dlosses` - Gradient of the losses (B, T).
 /// * `probs` - Probabilities (B, T, Vp).
-/// * `targets` - Target indices (B, T).
+/// * `targets` - Target indices (B, T). The `IGNORE_INDEX` targets get no gradient.
 /// * `B` - Batch size.
 /// * `T` - Sequence length.
 /// * `V` - Real vocabulary size.
@@ -1071,7 +1369,11 @@ pub unsafe fn crossentropy_softmax_backward(
             let dlogits_bt = dlogits.ptr.add(b * T * Vp + t * Vp);
             let probs_bt = probs.ptr.add(b * T * Vp + t * Vp);
             let dloss = *dlosses.ptr.add(b * T + t);
-            let ix = *targets.ptr.add(b * T + t) as usize;
+            let ix = *targets.ptr.add(b * T + t);
+            if ix == IGNORE_INDEX {
+                return;
+            }
+            let ix = ix as usize;
 
             // Loop only to V, leaving padded dimensions untouched
             for i in 0..V {
@@ -1082,3 +1384,287 @@ pub unsafe fn crossentropy_softmax_backward(
         });
     });
 }
+
+/// Updates parameters with one step of AdamW.
+///
+/// # Arguments
+///
+/// * `params` - Parameters to update.
+/// * `grads` - Gradients of the parameters.
+/// * `m_memory` - First moment buffer of the optimizer.
+/// * `v_memory` - Second moment buffer of the optimizer.
+/// * `N` - Number of parameters.
+/// * `learning_rate` - Learning rate.
+/// * `beta1` - Exponential decay rate for the first moment estimates.
+/// * `beta2` - Exponential decay rate for the second moment estimates.
+/// * `eps` - Small constant for numerical stability.
+/// * `weight_decay` - Weight decay coefficient.
+/// * `t` - Time step.
+pub unsafe fn adamw_update(
+    params: SendPtr<f32>,
+    grads: SendPtr<f32>,
+    m_memory: SendPtr<f32>,
+    v_memory: SendPtr<f32>,
+    N: usize,
+    learning_rate: f32,
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+    weight_decay: f32,
+    t: usize,
+) {
+    for i in 0..N {
+        let param = *params.ptr.add(i);
+        let grad = *grads.ptr.add(i);
+
+        // Update the first moment (momentum)
+        let m = beta1 * *m_memory.ptr.add(i) + (1.0 - beta1) * grad;
+        // Update the second moment (RMSprop)
+        let v = beta2 * *v_memory.ptr.add(i) + (1.0 - beta2) * grad * grad;
+        // Bias-correct both moments
+        let m_hat = m / (1.0 - beta1.powi(t as i32));
+        let v_hat = v / (1.0 - beta2.powi(t as i32));
+
+        // Update m and v
+        *m_memory.ptr.add(i) = m;
+        *v_memory.ptr.add(i) = v;
+
+        // Update the parameters
+        *params.ptr.add(i) -= learning_rate * (m_hat / (v_hat.sqrt() + eps) + weight_decay * param);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::{normal_fill, random_u32};
+    use std::ptr::null_mut;
+
+    fn ptr<T>(buffer: &mut [T]) -> SendPtr<T> {
+        SendPtr::new(buffer.as_mut_ptr())
+    }
+
+    /// A buffer of N(0, 1) values.
+    fn random(n: usize, rng_state: &mut u64) -> Vec<f32> {
+        let mut buffer = vec![0.0; n];
+        normal_fill(&mut buffer, 0.0, 1.0, rng_state);
+        buffer
+    }
+
+    #[test]
+    fn null_doc_starts_match_zeros() {
+        let (C, NH, V) = (16, 4, 10);
+        // B * T multiple of 8 or not, for both attention kernels
+        for (B, T) in [(2, 8), (1, 7)] {
+            let mut rng_state = 1;
+            let mut inp = random(B * T * 3 * C, &mut rng_state);
+            let mut dout = random(B * T * C, &mut rng_state);
+            let mut wte = random(V * C, &mut rng_state);
+            let mut wpe = random(T * C, &mut rng_state);
+            let mut tokens: Vec<i32> = (0..B * T).map(|_| (random_u32(&mut rng_state) % V as u32) as i32).collect();
+            let mut zeros = vec![0; B * T];
+
+            let outputs = [SendPtr::new(null_mut()), ptr(&mut zeros)].map(|doc_starts| unsafe {
+                let mut out = vec![0.0; B * T * C];
+                let mut preatt = vec![0.0; B * NH * T * T];
+                let mut att = vec![0.0; B * NH * T * T];
+                let mut dinp = vec![0.0; B * T * 3 * C];
+                let mut dpreatt = vec![0.0; B * NH * T * T];
+                let mut datt = vec![0.0; B * NH * T * T];
+                attention_forward(ptr(&mut out), ptr(&mut preatt), ptr(&mut att), ptr(&mut inp), doc_starts, B, T, C, NH);
+                attention_backward(
+                    ptr(&mut dinp),
+                    ptr(&mut dpreatt),
+                    ptr(&mut datt),
+                    ptr(&mut dout),
+                    ptr(&mut inp),
+                    ptr(&mut att),
+                    doc_starts,
+                    B,
+                    T,
+                    C,
+                    NH,
+                );
+
+                let mut encoded = vec![0.0; B * T * C];
+                let mut dwte = vec![0.0; V * C];
+                let mut dwpe = vec![0.0; T * C];
+                encoder_forward(ptr(&mut encoded), ptr(&mut tokens), ptr(&mut wte), ptr(&mut wpe), doc_starts, B, T, C);
+                encoder_backward(ptr(&mut dwte), ptr(&mut dwpe), ptr(&mut dout), ptr(&mut tokens), doc_starts, B, T, C);
+                [out, att, dinp, encoded, dwte, dwpe]
+            });
+            assert_eq!(outputs[0], outputs[1]);
+        }
+    }
+
+    #[test]
+    fn lora_backward_matches_finite_differences() {
+        let (B, T, C, OC, R) = (2, 3, 8, 6, 2);
+        let scale = 0.5;
+        let mut rng_state = 2;
+        let mut inp = random(B * T * C, &mut rng_state);
+        let mut lora_a = random(R * C, &mut rng_state);
+        let mut lora_b = random(OC * R, &mut rng_state);
+        let mut dout = random(B * T * OC, &mut rng_state);
+
+        let mut h = vec![0.0; B * T * R];
+        let mut out = vec![0.0; B * T * OC];
+        let mut dinp = vec![0.0; B * T * C];
+        let mut dlora_a = vec![0.0; R * C];
+        let mut dlora_b = vec![0.0; OC * R];
+        unsafe {
+            lora_forward(
+                ptr(&mut out),
+                ptr(&mut h),
+                ptr(&mut inp),
+                ptr(&mut lora_a),
+                ptr(&mut lora_b),
+                scale,
+                B,
+                T,
+                C,
+                OC,
+                R,
+            );
+            lora_backward(
+                ptr(&mut dinp),
+                ptr(&mut dlora_a),
+                ptr(&mut dlora_b),
+                ptr(&mut dout),
+                ptr(&mut inp),
+                ptr(&mut h),
+                ptr(&mut lora_a),
+                ptr(&mut lora_b),
+                scale,
+                B,
+                T,
+                C,
+                OC,
+                R,
+            );
+        }
+
+        // The loss sum(dout * out) has dout as the gradient of the output
+        let loss = |inp: &mut [f32], lora_a: &mut [f32], lora_b: &mut [f32]| unsafe {
+            let mut out = vec![0.0; B * T * OC];
+            let mut h = vec![0.0; B * T * R];
+            lora_forward(ptr(&mut out), ptr(&mut h), ptr(inp), ptr(lora_a), ptr(lora_b), scale, B, T, C, OC, R);
+            out.iter().zip(&dout).map(|(&o, &d)| o as f64 * d as f64).sum::<f64>()
+        };
+
+        // The loss is linear in each parameter, so central differences are exact up to rounding
+        let eps = 1e-2;
+        let check = |name: &str, grads: &[f32], which: usize| {
+            for (i, &grad) in grads.iter().enumerate() {
+                let perturbed = |delta: f32| {
+                    let mut inp = inp.clone();
+                    let mut lora_a = lora_a.clone();
+                    let mut lora_b = lora_b.clone();
+                    [&mut inp, &mut lora_a, &mut lora_b][which][i] += delta;
+                    loss(&mut inp, &mut lora_a, &mut lora_b)
+                };
+                let numeric = ((perturbed(eps) - perturbed(-eps)) / (2.0 * eps as f64)) as f32;
+                assert!(
+                    (grad - numeric).abs() <= 1e-3 * numeric.abs().max(1.0),
+                    "{} {}: {} vs {}",
+                    name,
+                    i,
+                    grad,
+                    numeric
+                );
+            }
+        };
+        check("dinp", &dinp, 0);
+        check("dlora_a", &dlora_a, 1);
+        check("dlora_b", &dlora_b, 2);
+    }
+
+    #[test]
+    fn lora_with_zero_up_projection_adds_nothing() {
+        let (B, T, C, OC, R) = (2, 3, 8, 6, 2);
+        let mut rng_state = 3;
+        let mut inp = random(B * T * C, &mut rng_state);
+        let mut lora_a = random(R * C, &mut rng_state);
+        let mut lora_b = vec![0.0; OC * R];
+        let expected = random(B * T * OC, &mut rng_state);
+        let mut out = expected.clone();
+        let mut h = vec![0.0; B * T * R];
+        unsafe {
+            lora_forward(
+                ptr(&mut out),
+                ptr(&mut h),
+                ptr(&mut inp),
+                ptr(&mut lora_a),
+                ptr(&mut lora_b),
+                1.0,
+                B,
+                T,
+                C,
+                OC,
+                R,
+            );
+        }
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn attention_backward_matches_finite_differences() {
+        let (C, NH) = (8, 2);
+        // B * T multiple of 8 or not, for both kernels
+        for (B, T) in [(2, 4), (1, 3)] {
+            let mut rng_state = 4;
+            let mut inp = random(B * T * 3 * C, &mut rng_state);
+            let mut dout = random(B * T * C, &mut rng_state);
+            let mut out = vec![0.0; B * T * C];
+            let mut preatt = vec![0.0; B * NH * T * T];
+            let mut att = vec![0.0; B * NH * T * T];
+            let mut dinp = vec![0.0; B * T * 3 * C];
+            let mut dpreatt = vec![0.0; B * NH * T * T];
+            let mut datt = vec![0.0; B * NH * T * T];
+            let null = SendPtr::new(null_mut());
+            unsafe {
+                attention_forward(ptr(&mut out), ptr(&mut preatt), ptr(&mut att), ptr(&mut inp), null, B, T, C, NH);
+                attention_backward(
+                    ptr(&mut dinp),
+                    ptr(&mut dpreatt),
+                    ptr(&mut datt),
+                    ptr(&mut dout),
+                    ptr(&mut inp),
+                    ptr(&mut att),
+                    null,
+                    B,
+                    T,
+                    C,
+                    NH,
+                );
+            }
+
+            // The loss sum(dout * out) has dout as the gradient of the output
+            let loss = |inp: &mut [f32]| unsafe {
+                let mut out = vec![0.0; B * T * C];
+                let mut preatt = vec![0.0; B * NH * T * T];
+                let mut att = vec![0.0; B * NH * T * T];
+                attention_forward(ptr(&mut out), ptr(&mut preatt), ptr(&mut att), ptr(inp), null, B, T, C, NH);
+                out.iter().zip(&dout).map(|(&o, &d)| o as f64 * d as f64).sum::<f64>()
+            };
+            let eps = 1e-3;
+            for (i, &grad) in dinp.iter().enumerate() {
+                let mut perturbed = inp.clone();
+                perturbed[i] += eps;
+                let plus = loss(&mut perturbed);
+                perturbed[i] -= 2.0 * eps;
+                let minus = loss(&mut perturbed);
+                let numeric = ((plus - minus) / (2.0 * eps as f64)) as f32;
+                assert!(
+                    (grad - numeric).abs() <= 1e-2 * numeric.abs().max(1.0),
+                    "B={} T={} dinp {}: {} vs {}",
+                    B,
+                    T,
+                    i,
+                    grad,
+                    numeric
+                );
+            }
+        }
+    }
+}
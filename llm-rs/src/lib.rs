@@ -0,0 +1,17 @@
+#![allow(non_snake_case)]
+
+pub mod bpe_trainer;
+pub mod dataloader;
+pub mod debug_state;
+pub mod decoding;
+pub mod error;
+pub mod evaluate;
+pub mod generate;
+pub mod gpt2;
+pub mod prepro;
+pub mod random;
+pub mod sampler;
+pub mod send_ptr;
+pub mod sft;
+pub mod tokenizer;
+pub mod train_state;
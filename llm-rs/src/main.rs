@@ -1,78 +1,115 @@
 #![allow(non_snake_case)]
 
-pub mod dataloader;
-pub mod gpt2;
-pub mod tokenizer;
-pub mod send_ptr;
-
-use std::alloc::{self, Layout};
+use std::env;
 use std::io::{self, Write};
 use std::path::Path;
-use std::ptr::null_mut;
+use std::process;
+use std::str::FromStr;
 use std::time::Instant;
 
-use dataloader::DataLoader;
-use gpt2::*;
-use send_ptr::SendPtr;
-use tokenizer::*;
+use llm_rs::dataloader::DataLoader;
+use llm_rs::decoding::{BeamSearch, ContrastiveSearch};
+use llm_rs::error::Result;
+use llm_rs::evaluate::evaluate;
+use llm_rs::generate::{generate, GenerateOptions};
+use llm_rs::gpt2::*;
+use llm_rs::sampler::Sampler;
+use llm_rs::send_ptr::SendPtr;
+use llm_rs::sft::{ChatTemplate, Message, SftLoader};
+use llm_rs::tokenizer::*;
+use llm_rs::train_state::{recover_checkpoint, TrainState};
 
 const BATCH_SIZE: usize = 4;
 const SEQ_LENGTH: usize = 64;
+const NUM_STEPS: usize = 40;
+const CHECKPOINT_EVERY: usize = 10;
 
-// ----------------------------------------------------------------------------
-// Testing
-// ----------------------------------------------------------------------------
-
-/// Generates a random `u32` using the xorshift* algorithm.
+/// Parses the value of a command line option, exiting with an error message if it is invalid.
 ///
 /// # Arguments
 ///
-/// * `state` - A mutable reference to the RNG state.
+/// * `value` - The value given to the option, if the option is there.
+/// * `option` - The name of the option, used in the error message.
 ///
 /// # Returns
 ///
-/// A random `u32` value.
-fn random_u32(state: &mut u64) -> u32 {
-    *state ^= *state >> 12;
-    *state ^= *state << 25;
-    *state ^= *state >> 27;
-    ((*state).wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32
+/// The parsed value, or `None` if the option is not there.
+fn parse_option<T: FromStr>(value: Option<&str>, option: &str) -> Option<T> {
+    value.map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value '{}' for {}", value, option);
+            process::exit(1);
+        })
+    })
 }
 
-/// Generates a random `f32` in the range [0, 1).
-///
-/// # Arguments
-///
-/// * `state` - A mutable reference to the RNG state.
-///
-/// # Returns
-///
-/// A random `f32` value in the range [0, 1).
-fn random_f32(state: &mut u64) -> f32 {
-    (random_u32(state) >> 8) as f32 / 16777216.0
+/// Where the training batches come from.
+enum TrainData {
+    /// Windows of token files
+    Tokens(DataLoader),
+
+    /// Packed conversations, with the loss on the assistant messages only
+    Conversations(SftLoader),
 }
 
-/// Samples an index from the given probabilities.
-///
-/// # Arguments
-///
-/// * `probabilities` - A raw pointer to an array of probabilities. They must sum to 1.
-/// * `n` - The number of probabilities.
-/// * `coin` - A random number in the range [0, 1).
-///
-/// # Returns
-///
-/// The sampled index based on the given probabilities.
-fn sample_mult(probabilities: *const f32, n: usize, coin: f32) -> usize {
-    unsafe {
-        let mut cdf = 0.0;
-        for i in 0..n {
-            cdf += *probabilities.add(i);
-            if coin < cdf {
-                return i;
+impl TrainData {
+    /// Loads the next batch.
+    ///
+    /// # Returns
+    ///
+    /// The inputs, targets and document starts of the batch, or an error if the token files
+    /// cannot be read.
+    fn next_batch(&mut self) -> Result<(SendPtr<i32>, SendPtr<i32>, SendPtr<i32>)> {
+        match self {
+            TrainData::Tokens(loader) => {
+                loader.next_batch()?;
+                Ok((loader.inputs, loader.targets, loader.doc_starts))
             }
+            TrainData::Conversations(loader) => {
+                loader.next_batch();
+                Ok((loader.inputs, loader.targets, loader.doc_starts))
+            }
+        }
+    }
+
+    /// Number of complete passes over the training data.
+    fn epoch(&self) -> usize {
+        match self {
+            TrainData::Tokens(loader) => loader.epoch,
+            TrainData::Conversations(loader) => loader.epoch,
+        }
+    }
+
+    /// Fraction of the batches of the current epoch already read.
+    fn epoch_progress(&self) -> f64 {
+        match self {
+            TrainData::Tokens(loader) => loader.epoch_progress(),
+            TrainData::Conversations(loader) => loader.epoch_progress(),
+        }
+    }
+
+    /// Position of the next batch, as saved in the `TrainState`.
+    ///
+    /// # Returns
+    ///
+    /// The epoch, shard and sample of the next batch. The conversations are a single shard.
+    fn position(&self) -> (usize, usize, usize) {
+        match self {
+            TrainData::Tokens(loader) => (loader.epoch, loader.shard_index, loader.sample_index),
+            TrainData::Conversations(loader) => (loader.epoch, 0, loader.batch_index),
+        }
+    }
+
+    /// Moves to a position saved in the `TrainState`.
+    ///
+    /// # Returns
+    ///
+    /// An error if the position is outside of the training data.
+    fn resume(&mut self, (epoch, shard, sample): (usize, usize, usize)) -> Result<()> {
+        match self {
+            TrainData::Tokens(loader) => loader.resume(epoch, shard, sample),
+            TrainData::Conversations(loader) => loader.resume(epoch, sample),
         }
-        n - 1 // in case of rounding errors
     }
 }
 
@@ -83,99 +120,433 @@ fn sample_mult(probabilities: *const f32, n: usize, coin: f32) -> usize {
 pub fn main() {
     let mut lock = io::stdout().lock();
 
-    // Initialize the GPT-2 model from a checkpoint
-    let checkpoint_path = Path::new("gpt2_124M.bin");
-    let mut model = GPT2::new(checkpoint_path);
+    // With `--resume`, continue from the last checkpoint written by the training loop.
+    // With `--init <preset>`, train a randomly initialized model from scratch.
+    // With `--hf <model.safetensors>`, start from HuggingFace GPT-2 weights.
+    // With `--save-hf <model.safetensors>`, also export the trained weights for transformers.
+    // `--temperature`, `--top-k`, `--top-p`, `--min-p`, `--repetition-penalty` and
+    // `--frequency-penalty` control how the samples are generated. `--num-beams <n>` decodes them
+    // with beam search instead, `--contrastive` with contrastive search. `--prompt <text>` makes
+    // them continue a text instead of starting from scratch. `--tokenizer <tokenizer.bin>` uses
+    // another tokenizer than GPT-2's, e.g. one written by `train_tokenizer`, and sets the vocabulary
+    // of the models created with `--init`. `--train-data` and `--val-data` read the tokens from
+    // other files than the TinyShakespeare or TinyStories ones, e.g. the ones written by `prepro`.
+    // `--sft <conversations.jsonl>` fine-tunes on the assistant messages of chat conversations
+    // instead, laid out with the default chat template or the one of `--chat-template <file.json>`.
+    // `--lora <rank>` freezes the weights and trains low-rank adapters on them, scaled by
+    // `--lora-alpha` (the rank by default). `--load-lora <adapters.bin>` starts from saved adapters.
+    let args: Vec<String> = env::args().collect();
+    let resume = args.iter().any(|arg| arg == "--resume");
+    let contrastive = args.iter().any(|arg| arg == "--contrastive");
+    let reset_positions = args.iter().any(|arg| arg == "--reset-positions");
+    let mask_documents = reset_positions || args.iter().any(|arg| arg == "--mask-documents");
+    let option_value = |option: &str| {
+        args.iter()
+            .position(|arg| arg == option)
+            .map(|index| args.get(index + 1).map(String::as_str).unwrap_or(""))
+    };
+    let init_preset = option_value("--init");
+    let hf_path = option_value("--hf");
+    let save_hf_path = option_value("--save-hf");
+    let resume_checkpoint_path = Path::new("gpt2_124M_resume.bin");
+    let resume_state_path = Path::new("gpt2_124M_resume_state.bin");
+    let resume_lora_path = Path::new("gpt2_124M_resume_lora.bin");
+
+    // With adapters the weights are frozen, a resumed run reloads them from where they came from
+    let lora_rank: Option<usize> = parse_option(option_value("--lora"), "--lora");
+    let load_lora = option_value("--load-lora");
+    let use_lora = lora_rank.is_some() || load_lora.is_some();
+    let resume_weights = resume && !use_lora;
+    // The checkpoints hold the trained adapters instead of the weights when there are some, and
+    // an interrupted checkpoint is completed or dropped before resuming
+    let resume_trained_path = if use_lora {
+        resume_lora_path
+    } else {
+        resume_checkpoint_path
+    };
+    if resume {
+        if let Err(err) = recover_checkpoint(resume_trained_path, resume_state_path) {
+            eprintln!("Error recovering the interrupted checkpoint: {}", err);
+            process::exit(1);
+        }
+    }
+
+    // Initialize the Tokenizer
+    let tokenizer_path = Path::new(option_value("--tokenizer").unwrap_or("gpt2_tokenizer.bin"));
+    let tokenizer = Tokenizer::new(tokenizer_path).ok();
+    if tokenizer.is_none() {
+        eprintln!("---");
+        eprintln!("WARNING: Failed to open the tokenizer file {}", tokenizer_path.display());
+        eprintln!("The Tokenizer is a new feature added April 14 2024.");
+        eprintln!("Re-run `python train_gpt2.py` to write it");
+        eprintln!("---");
+    }
+
+    // Initialize the GPT-2 model from a checkpoint, or from a config
+    let mut model = match (init_preset, hf_path) {
+        (_, Some(hf_path)) if !resume_weights => {
+            let hf_path = Path::new(hf_path);
+            GPT2::from_safetensors(hf_path).unwrap_or_else(|err| {
+                eprintln!("Error loading model from {}: {}", hf_path.display(), err);
+                process::exit(1);
+            })
+        }
+        (Some(preset), _) if !resume_weights => {
+            let mut config = GPT2Config::from_preset(preset).unwrap_or_else(|| {
+                eprintln!("Unknown model preset '{}', expected one of d12, d24, d36, d48, tiny", preset);
+                process::exit(1);
+            });
+            if let Some(tokenizer) = &tokenizer {
+                config = config.with_vocab_size(tokenizer.vocab_size() as usize);
+            }
+            GPT2::from_config(config, 42).unwrap_or_else(|err| {
+                eprintln!("Error initializing model: {}", err);
+                process::exit(1);
+            })
+        }
+        _ => {
+            let checkpoint_path = if resume_weights {
+                resume_checkpoint_path
+            } else {
+                Path::new("gpt2_124M.bin")
+            };
+            GPT2::new(checkpoint_path).unwrap_or_else(|err| {
+                eprintln!("Error loading model from {}: {}", checkpoint_path.display(), err);
+                process::exit(1);
+            })
+        }
+    };
+    if let Some(tokenizer) = &tokenizer {
+        if tokenizer.vocab_size() as usize > model.config.vocab_size {
+            eprintln!(
+                "The tokenizer has {} tokens, more than the {} of the model",
+                tokenizer.vocab_size(),
+                model.config.vocab_size
+            );
+            process::exit(1);
+        }
+    }
+
+    // Train low-rank adapters instead of the weights
+    if use_lora {
+        let lora = if resume {
+            LoraAdapters::load(resume_lora_path, &model.config)
+        } else if let Some(load_lora) = load_lora {
+            LoraAdapters::load(Path::new(load_lora), &model.config)
+        } else {
+            let rank = lora_rank.unwrap();
+            if rank == 0 {
+                eprintln!("The adapters need a rank of at least 1");
+                process::exit(1);
+            }
+            let alpha = parse_option(option_value("--lora-alpha"), "--lora-alpha").unwrap_or(rank as f32);
+            Ok(LoraAdapters::new(&model.config, rank, alpha, 42))
+        };
+        let lora = lora.unwrap_or_else(|err| {
+            eprintln!("Error loading adapters: {}", err);
+            process::exit(1);
+        });
+        writeln!(lock, "lora: rank {}, alpha {}, {} trained parameters", lora.rank, lora.alpha, lora.num_parameters).unwrap();
+        model.lora = Some(lora);
+    }
 
-    // Build DataLoaders from token files
+    // Build DataLoaders from token files, or glob patterns of shards
     let tiny_stories_train = Path::new("data/TinyStories_train.bin");
     let tiny_stories_val = Path::new("data/TinyStories_val.bin");
     let tiny_shakespeare_train = Path::new("data/tiny_shakespeare_train.bin");
     let tiny_shakespeare_val = Path::new("data/tiny_shakespeare_val.bin");
 
-    let train_tokens = if tiny_shakespeare_train.exists() {
+    let train_tokens = if let Some(train_data) = option_value("--train-data") {
+        Path::new(train_data)
+    } else if tiny_shakespeare_train.exists() {
         tiny_shakespeare_train
     } else {
         tiny_stories_train
     };
-    let val_tokens = if tiny_shakespeare_val.exists() {
+    let val_tokens = if let Some(val_data) = option_value("--val-data") {
+        Path::new(val_data)
+    } else if tiny_shakespeare_val.exists() {
         tiny_shakespeare_val
     } else {
         tiny_stories_val
     };
 
-    unsafe {
-        let mut train_loader = DataLoader::new(train_tokens, BATCH_SIZE, SEQ_LENGTH);
-        let mut val_loader = DataLoader::new(val_tokens, BATCH_SIZE, SEQ_LENGTH);
-        writeln!(lock, "train dataset num_batches: {}", train_loader.num_batches).unwrap();
-        writeln!(lock, "val dataset num_batches: {}", val_loader.num_batches).unwrap();
+    // Fine-tune on conversations instead of the token files
+    let sft_data = option_value("--sft").map(Path::new);
+    let chat_template = match option_value("--chat-template") {
+        Some(template_path) => ChatTemplate::from_json(Path::new(template_path)).unwrap_or_else(|err| {
+            eprintln!("Error loading chat template: {}", err);
+            process::exit(1);
+        }),
+        None => ChatTemplate::default(),
+    };
+    let bpe_tokenizer = || match &tokenizer {
+        Some(tokenizer) if tokenizer.can_encode() => tokenizer,
+        _ => {
+            eprintln!("--sft and --prompt need a tokenizer file with the BPE merges");
+            eprintln!("Re-run `python train_gpt2.py` to write it");
+            process::exit(1);
+        }
+    };
 
-        let val_num_batches = 5;
+    unsafe {
+        // The training batches are read in order unless `--shuffle-seed` shuffles them every epoch
+        let shuffle_seed: Option<u64> = parse_option(option_value("--shuffle-seed"), "--shuffle-seed");
+        let eot_token = tokenizer.as_ref().and_then(Tokenizer::eot_token).unwrap_or(50256);
+        let mut train_data = if let Some(sft_data) = sft_data {
+            let mut train_loader = SftLoader::new(sft_data, bpe_tokenizer(), &chat_template, BATCH_SIZE, SEQ_LENGTH)
+                .unwrap_or_else(|err| {
+                    eprintln!("Error loading conversations from {}: {}", sft_data.display(), err);
+                    process::exit(1);
+                });
+            if let Some(shuffle_seed) = shuffle_seed {
+                train_loader.shuffle(shuffle_seed);
+            }
+            // Each conversation starts at position 0, as if it had a row of its own
+            model.reset_positions = true;
+            writeln!(lock, "train dataset num_batches: {} ({} conversations, {} truncated, {} tokens with a loss)",
+                train_loader.num_batches,
+                train_loader.num_conversations,
+                train_loader.num_truncated,
+                train_loader.num_loss_tokens
+            ).unwrap();
+            TrainData::Conversations(train_loader)
+        } else {
+            let mut train_loader = DataLoader::new(train_tokens, BATCH_SIZE, SEQ_LENGTH).unwrap_or_else(|err| {
+                eprintln!("Error loading tokens from {}: {}", train_tokens.display(), err);
+                process::exit(1);
+            });
+            if let Some(shuffle_seed) = shuffle_seed {
+                train_loader.shuffle(shuffle_seed);
+            }
+            // `--prefetch <n>` reads the training batches ahead on a background thread, the steps do
+            // not wait for the file then
+            if let Some(prefetch_batches) = parse_option(option_value("--prefetch"), "--prefetch") {
+                train_loader.prefetch(prefetch_batches);
+            }
+            // Keep the documents of a batch from attending to each other, they start with EOT
+            if mask_documents {
+                train_loader.eot_token = Some(eot_token as i32);
+                model.reset_positions = reset_positions;
+            }
+            writeln!(lock, "train dataset num_batches: {} ({} shards)", train_loader.num_batches, train_loader.num_shards()).unwrap();
+            TrainData::Tokens(train_loader)
+        };
+        // Fine-tuning on conversations is only validated on token files given explicitly
+        let mut val_loader = if sft_data.is_none() || option_value("--val-data").is_some() {
+            let mut val_loader = DataLoader::new(val_tokens, BATCH_SIZE, SEQ_LENGTH).unwrap_or_else(|err| {
+                eprintln!("Error loading tokens from {}: {}", val_tokens.display(), err);
+                process::exit(1);
+            });
+            if mask_documents {
+                val_loader.eot_token = Some(eot_token as i32);
+            }
+            writeln!(lock, "val dataset num_batches: {} ({} shards)", val_loader.num_batches, val_loader.num_shards()).unwrap();
+            Some(val_loader)
+        } else {
+            None
+        };
 
-        // Initialize the Tokenizer
-        let tokenizer_path = Path::new("gpt2_tokenizer.bin");
-        let mut tokenizer = Tokenizer::new(tokenizer_path);
+        // Number of validation batches, 0 for the whole split
+        let val_num_batches = parse_option(option_value("--val-batches"), "--val-batches").unwrap_or(5);
+        let val_max_batches = Some(val_num_batches).filter(|&num_batches| num_batches > 0);
 
-        // Memory for generating samples
-        let mut rng_state: u64 = 1337;
-        let gen_tokens_layout = Layout::array::<i32>(BATCH_SIZE * SEQ_LENGTH).expect("Failed to create layout");
-        let gen_tokens = SendPtr::new(alloc::alloc(gen_tokens_layout) as *mut i32);
+        // Settings for generating samples
+        let mut sampler = Sampler::new(1337);
+        if let Some(temperature) = parse_option(option_value("--temperature"), "--temperature") {
+            sampler.temperature = temperature;
+        }
+        if let Some(top_k) = parse_option(option_value("--top-k"), "--top-k") {
+            sampler.top_k = top_k;
+        }
+        if let Some(top_p) = parse_option(option_value("--top-p"), "--top-p") {
+            sampler.top_p = top_p;
+        }
+        if let Some(min_p) = parse_option(option_value("--min-p"), "--min-p") {
+            sampler.min_p = min_p;
+        }
+        if let Some(penalty) = parse_option(option_value("--repetition-penalty"), "--repetition-penalty") {
+            sampler.repetition_penalty = penalty;
+        }
+        if let Some(penalty) = parse_option(option_value("--frequency-penalty"), "--frequency-penalty") {
+            sampler.frequency_penalty = penalty;
+        }
+        let num_beams: Option<usize> = parse_option(option_value("--num-beams"), "--num-beams");
+        // Each step of a beam search keeps the best 2 continuations of every beam
+        if let Some(num_beams) = num_beams.filter(|&num_beams| num_beams == 0 || 2 * num_beams > model.config.vocab_size) {
+            eprintln!("Invalid value '{}' for --num-beams, expected between 1 and {}", num_beams, model.config.vocab_size / 2);
+            process::exit(1);
+        }
         let genT = 64;
 
+        // Start from the EOT token, followed by the prompt if any. When fine-tuning on
+        // conversations, the prompt is a user message for the model to answer.
+        let prompt_text = option_value("--prompt");
+        let mut prompt = vec![eot_token as i32];
+        if let Some(text) = prompt_text {
+            let tokens = if sft_data.is_some() {
+                let message = Message {
+                    role: "user".to_string(),
+                    content: text.to_string(),
+                };
+                chat_template.encode_prompt(bpe_tokenizer(), &[message])
+            } else {
+                bpe_tokenizer().encode(text)
+            };
+            prompt.extend(tokens.into_iter().map(|token| token as i32));
+            if prompt.len() >= genT {
+                eprintln!("The prompt is too long: {} tokens, at most {}", prompt.len() - 1, genT - 2);
+                process::exit(1);
+            }
+        }
+
+        // Restore the optimizer, RNG and data position of the interrupted run
+        let mut start_step = 0;
+        if resume {
+            let state = TrainState::load(resume_state_path, &mut model).unwrap_or_else(|err| {
+                eprintln!("Error loading training state from {}: {}", resume_state_path.display(), err);
+                process::exit(1);
+            });
+            start_step = state.step;
+            sampler.rng_state = state.rng_state;
+            let resumed = train_data.resume((
+                state.train_epoch as usize,
+                state.train_shard as usize,
+                state.train_sample as usize,
+            ));
+            if let Err(err) = resumed {
+                eprintln!("Error resuming the training data: {}", err);
+                process::exit(1);
+            }
+            writeln!(lock, "resuming from step {} (epoch {}, {:.1}% done)",
+                start_step,
+                train_data.epoch(),
+                train_data.epoch_progress() * 100.0
+            ).unwrap();
+        }
+
         // Training loop
-        for step in 0..=40 {
+        for step in start_step..=NUM_STEPS {
             // Estimate validation loss periodically
-            if step % 10 == 0 {
-                let mut val_loss = 0.0;
-                val_loader.reset();
-                for _ in 0..val_num_batches {
-                    val_loader.next_batch();
-                    model.forward(val_loader.inputs, val_loader.targets, BATCH_SIZE, SEQ_LENGTH);
-                    val_loss += model.mean_loss;
+            if let Some(val_loader) = val_loader.as_mut().filter(|_| step % 10 == 0) {
+                let evaluation = evaluate(&mut model, val_loader, tokenizer.as_ref(), val_max_batches)
+                    .unwrap_or_else(|err| {
+                        eprintln!("Error reading the validation data: {}", err);
+                        process::exit(1);
+                    });
+                write!(lock, "val loss {} (perplexity {:.2}", evaluation.mean_loss as f32, evaluation.perplexity).unwrap();
+                if let Some(bits_per_byte) = evaluation.bits_per_byte {
+                    write!(lock, ", {:.4} bits per byte", bits_per_byte).unwrap();
                 }
-                val_loss /= val_num_batches as f32;
-                writeln!(lock, "val loss {}", val_loss).unwrap();
+                writeln!(lock, ", {} tokens)", evaluation.num_tokens).unwrap();
             }
 
             // Generate text periodically
             if step > 0 && step % 20 == 0 {
-                for i in 0..BATCH_SIZE * SEQ_LENGTH {
-                    *gen_tokens.ptr.add(i) = 50256;
-                }
                 writeln!(lock, "generating:\n---").unwrap();
-                for t in 1..genT {
-                    model.forward(gen_tokens, SendPtr::new(null_mut()), BATCH_SIZE, SEQ_LENGTH);
-                    let probs = model
-                        .acts
-                        .probs
-                        .ptr.add((t - 1) * model.config.padded_vocab_size);
-                    let coin = random_f32(&mut rng_state);
-                    let next_token = sample_mult(probs, model.config.vocab_size, coin) as u32;
-                    *gen_tokens.ptr.add(t) = next_token as i32;
-                    if tokenizer.init_ok {
-                        let token_str = tokenizer.decode(next_token);
-                        safe_print(token_str, &mut lock);
+                write!(lock, "{}", prompt_text.unwrap_or("")).unwrap();
+                let mut decoder = tokenizer.as_ref().map(|tokenizer| StreamDecoder::new(tokenizer, true));
+                let mut print_token = |token: u32| {
+                    if let Some(decoder) = &mut decoder {
+                        let piece = decoder.push(token).unwrap_or_default();
+                        safe_print(&piece, &mut lock);
                     } else {
-                        write!(lock, "{} ", next_token).unwrap();
+                        write!(lock, "{} ", token).unwrap();
+                    }
+                };
+                if let Some(num_beams) = num_beams {
+                    let hypotheses = BeamSearch::new(num_beams, None).generate(&model, &prompt, genT - prompt.len());
+                    if let Some(best) = hypotheses.first() {
+                        best.tokens.iter().for_each(|&token| print_token(token as u32));
+                    }
+                } else if contrastive {
+                    let tokens = ContrastiveSearch::new(None).generate(&model, &prompt, genT - prompt.len());
+                    tokens.iter().for_each(|&token| print_token(token as u32));
+                } else {
+                    let options = GenerateOptions::new(genT - prompt.len());
+                    let mut generation = generate(&model, tokenizer.as_ref(), &mut sampler, &prompt, options);
+                    let mut printed = 0;
+                    for (token, piece) in generation.by_ref() {
+                        if tokenizer.is_some() {
+                            safe_print(&piece, &mut lock);
+                            printed += piece.len();
+                        } else {
+                            write!(lock, "{} ", token).unwrap();
+                        }
                     }
-                    // io::stdout().flush().unwrap();
+                    safe_print(&generation.text()[printed..], &mut lock);
+                }
+                if let Some(decoder) = &mut decoder {
+                    safe_print(&decoder.finish().unwrap_or_default(), &mut lock);
                 }
                 writeln!(lock, "\n---").unwrap();
             }
 
             // Training step
             let start = Instant::now();
-            train_loader.next_batch();
-            model.forward(train_loader.inputs, train_loader.targets, BATCH_SIZE, SEQ_LENGTH);
+            let epoch = train_data.epoch();
+            let (inputs, targets, doc_starts) = train_data.next_batch().unwrap_or_else(|err| {
+                eprintln!("Error reading the training data: {}", err);
+                process::exit(1);
+            });
+            model.forward_with_documents(inputs, targets, doc_starts, BATCH_SIZE, SEQ_LENGTH);
             model.zero_grad();
             model.backward();
-            model.update(1e-4, 0.9, 0.999, 1e-8, 0.0, step + 1);
+            if let Err(err) = model.update(1e-4, 0.9, 0.999, 1e-8, 0.0, step + 1) {
+                eprintln!("Error updating the model: {}", err);
+                process::exit(1);
+            }
             let duration = start.elapsed();
             writeln!(lock, "step {}: train loss {:.6} (took {:.2} ms)",
                 step,
                 model.mean_loss,
                 duration.as_secs_f64() * 1000.0
             ).unwrap();
+            if train_data.epoch() != epoch {
+                writeln!(lock, "finished epoch {} of the training data", epoch).unwrap();
+            }
+
+            // Checkpoint the weights and the training state periodically
+            if (step + 1) % CHECKPOINT_EVERY == 0 {
+                let (train_epoch, train_shard, train_sample) = train_data.position();
+                let state = TrainState {
+                    step: step + 1,
+                    rng_state: sampler.rng_state,
+                    train_epoch: train_epoch as u64,
+                    train_shard: train_shard as u64,
+                    train_sample: train_sample as u64,
+                };
+                if let Err(err) = state.save_checkpoint(resume_trained_path, resume_state_path, &model) {
+                    eprintln!("Error saving training state: {}", err);
+                }
+            }
+        }
+    }
+
+    // Keep the trained adapters around, then merge them into the weights to save the whole model
+    if let Some(lora) = &model.lora {
+        let lora_path = Path::new("gpt2_124M_lora.bin");
+        match lora.save(lora_path) {
+            Ok(()) => writeln!(lock, "saved trained adapters to {}", lora_path.display()).unwrap(),
+            Err(err) => eprintln!("Error saving adapters to {}: {}", lora_path.display(), err),
+        }
+        if let Err(err) = model.merge_lora() {
+            eprintln!("Error merging the adapters: {}", err);
+            process::exit(1);
+        }
+    }
+
+    // Keep the trained weights around
+    let trained_path = Path::new("gpt2_124M_trained.bin");
+    match model.save(trained_path) {
+        Ok(()) => writeln!(lock, "saved trained model to {}", trained_path.display()).unwrap(),
+        Err(err) => eprintln!("Error saving model to {}: {}", trained_path.display(), err),
+    }
+    if let Some(save_hf_path) = save_hf_path {
+        let save_hf_path = Path::new(save_hf_path);
+        match model.save_safetensors(save_hf_path) {
+            Ok(()) => writeln!(lock, "saved trained model to {}", save_hf_path.display()).unwrap(),
+            Err(err) => eprintln!("Error saving model to {}: {}", save_hf_path.display(), err),
         }
     }
 }
@@ -0,0 +1,510 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::dataloader::TokenWriter;
+use crate::error::{Error, Result};
+use crate::tokenizer::Tokenizer;
+
+/// Size of the text read before encoding it, so that the documents are encoded in parallel
+/// without holding the whole corpus.
+const BATCH_BYTES: usize = 1 << 26;
+
+/// Number of tokens copied at a time from the temporary file to the token files.
+const CHUNK_TOKENS: usize = 1 << 16;
+
+/// How `preprocess` turns the input files into token files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreproOptions {
+    /// Separates the documents of the text files. Without one, each text file is a document.
+    pub delimiter: Option<String>,
+
+    /// Field holding the text of each line of the JSONL files.
+    pub text_field: String,
+
+    /// Fraction of the tokens going to the validation split.
+    pub val_fraction: f64,
+
+    /// Maximum number of tokens per train shard. Without one, the train split is a single file.
+    pub shard_size: Option<usize>,
+}
+
+impl Default for PreproOptions {
+    /// Creates the default options: one document per text file, the `text` field of JSONL files
+    /// and 10% of the tokens for validation.
+    ///
+    /// # Returns
+    ///
+    /// New `PreproOptions`.
+    fn default() -> Self {
+        PreproOptions {
+            delimiter: None,
+            text_field: "text".to_string(),
+            val_fraction: 0.1,
+            shard_size: None,
+        }
+    }
+}
+
+/// What `preprocess` wrote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreproStats {
+    /// Number of documents read.
+    pub num_documents: usize,
+
+    /// Number of tokens of the train split.
+    pub train_tokens: usize,
+
+    /// Number of tokens of the validation split.
+    pub val_tokens: usize,
+
+    /// The token files written, validation first.
+    pub files: Vec<PathBuf>,
+}
+
+/// Tokenizes local documents into the `.bin` token files read by `DataLoader`.
+///
+/// The inputs are text files, JSONL files (`.jsonl` extension, one JSON object per line) or
+/// directories of such files, read in name order. Every document starts with the EOT token, like
+/// in the Python preprocessing scripts, so the model sees where documents end. The first
+/// documents make up the validation split, the rest the train split.
+///
+/// The documents are encoded a batch of files at a time into `<prefix>_tokens.tmp`, which is then
+/// copied into the token files a chunk at a time, so the corpus is never held in memory (only a
+/// file larger than the batch is read at once). The temporary file is removed at the end.
+///
+/// # Arguments
+///
+/// * `inputs` - Files or directories to read the documents from.
+/// * `tokenizer` - Tokenizer with merges and an EOT token.
+/// * `output_prefix` - Prefix of the files written: `<prefix>_val.bin` and `<prefix>_train.bin`,
+///   or `<prefix>_train_000000.bin`, `<prefix>_train_000001.bin`... with a shard size.
+/// * `options` - How to split the documents and the tokens.
+///
+/// # Returns
+///
+/// The number of documents and tokens written, or an error if an input cannot be read or parsed,
+/// or if the tokenizer cannot encode documents or the shard size is 0.
+pub fn preprocess(
+    inputs: &[PathBuf],
+    tokenizer: &Tokenizer,
+    output_prefix: &Path,
+    options: &PreproOptions,
+) -> Result<PreproStats> {
+    if !tokenizer.can_encode() {
+        return Err(Error::InvalidInput(
+            "the tokenizer has no merges, re-run `python train_gpt2.py` to write them".to_string(),
+        ));
+    }
+    let Some(eot_token) = tokenizer.eot_token() else {
+        return Err(Error::InvalidInput(
+            "the tokenizer has no EOT token to separate the documents".to_string(),
+        ));
+    };
+    if options.shard_size == Some(0) {
+        return Err(Error::InvalidInput("the shards need at least one token".to_string()));
+    }
+
+    let mut files = Vec::new();
+    for input in inputs {
+        collect_files(input, &mut files)?;
+    }
+
+    // The validation split is a fraction of all the tokens: tokenize everything to a temporary
+    // file first, then split it
+    let prefix = output_prefix.to_string_lossy();
+    let tokens_path = PathBuf::from(format!("{}_tokens.tmp", prefix));
+    let result = tokenize_files(&files, tokenizer, eot_token, options, &tokens_path)
+        .and_then(|(num_documents, total_tokens)| {
+            split_tokens(&tokens_path, tokenizer, eot_token, total_tokens, &prefix, options)
+                .map(|stats| PreproStats { num_documents, ..stats })
+        });
+    let _ = fs::remove_file(&tokens_path);
+    result
+}
+
+/// Tokenizes the documents of the files, in order, into a temporary file of raw uint32 tokens.
+///
+/// # Arguments
+///
+/// * `files` - The files to read the documents from.
+/// * `tokenizer` - Tokenizer with merges.
+/// * `eot_token` - Token starting every document.
+/// * `options` - How to read the documents.
+/// * `tokens_path` - Path to the temporary file.
+///
+/// # Returns
+///
+/// The number of documents and tokens written.
+fn tokenize_files(
+    files: &[PathBuf],
+    tokenizer: &Tokenizer,
+    eot_token: u32,
+    options: &PreproOptions,
+    tokens_path: &Path,
+) -> Result<(usize, usize)> {
+    let mut tokens_file = BufWriter::new(File::create(tokens_path)?);
+    let mut num_documents = 0;
+    let mut num_tokens = 0;
+    let mut documents = Vec::new();
+    let mut batch_bytes = 0;
+    for (i, file) in files.iter().enumerate() {
+        let start = documents.len();
+        read_documents(file, options, &mut documents)?;
+        batch_bytes += documents[start..].iter().map(String::len).sum::<usize>();
+        if batch_bytes < BATCH_BYTES && i + 1 < files.len() {
+            continue;
+        }
+
+        // Documents are independent, encode them in parallel
+        let tokenized: Vec<Vec<u32>> = documents
+            .par_iter()
+            .map(|document| {
+                let mut tokens = vec![eot_token];
+                tokens.extend(tokenizer.encode_ordinary(document));
+                tokens
+            })
+            .collect();
+        for token in tokenized.iter().flatten() {
+            tokens_file.write_all(&token.to_le_bytes())?;
+        }
+        num_documents += documents.len();
+        num_tokens += tokenized.iter().map(Vec::len).sum::<usize>();
+        documents.clear();
+        batch_bytes = 0;
+    }
+    tokens_file.flush()?;
+    Ok((num_documents, num_tokens))
+}
+
+/// Splits the temporary tokens file into the validation file and the train files.
+///
+/// # Arguments
+///
+/// * `tokens_path` - Path to the temporary file of raw uint32 tokens.
+/// * `tokenizer` - Tokenizer the tokens come from, to pick the format of the files.
+/// * `eot_token` - Token starting every document, the splits only end before one.
+/// * `total_tokens` - Number of tokens in the temporary file.
+/// * `prefix` - Prefix of the files written.
+/// * `options` - The validation fraction and the shard size.
+///
+/// # Returns
+///
+/// The number of tokens of each split and the files written, validation first.
+fn split_tokens(
+    tokens_path: &Path,
+    tokenizer: &Tokenizer,
+    eot_token: u32,
+    total_tokens: usize,
+    prefix: &str,
+    options: &PreproOptions,
+) -> Result<PreproStats> {
+    let mut stats = PreproStats {
+        num_documents: 0,
+        train_tokens: 0,
+        val_tokens: 0,
+        files: Vec::new(),
+    };
+    // Tokens that all fit in uint16 are written as shards, like `write_tokens` does
+    let uint16 = tokenizer.vocab_size() <= u16::MAX as u32 + 1;
+    let val_target = (total_tokens as f64 * options.val_fraction).ceil() as usize;
+    let mut val = None;
+    if val_target > 0 {
+        let path = PathBuf::from(format!("{}_val.bin", prefix));
+        val = Some(TokenWriter::create(&path, uint16)?);
+        stats.files.push(path);
+    }
+    let mut train: Option<TokenWriter> = None;
+    let mut num_shards = 0;
+    let mut shard_tokens = 0;
+
+    let mut tokens_file = BufReader::new(File::open(tokens_path)?);
+    let mut buffer = vec![0u8; 4 * CHUNK_TOKENS];
+    let mut position = 0;
+    while position < total_tokens {
+        let chunk_len = CHUNK_TOKENS.min(total_tokens - position);
+        tokens_file.read_exact(&mut buffer[..4 * chunk_len])?;
+        let chunk: Vec<u32> = buffer[..4 * chunk_len]
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        let mut rest = chunk.as_slice();
+
+        // The first documents go to validation, until they reach the target
+        if let Some(writer) = val.as_mut() {
+            let end = (0..rest.len())
+                .find(|&i| position + i >= val_target && rest[i] == eot_token)
+                .unwrap_or(rest.len());
+            writer.write(&rest[..end])?;
+            if end < rest.len() {
+                stats.val_tokens = val.take().unwrap().finish()?;
+            }
+            rest = &rest[end..];
+        }
+
+        // The next ones to the train split, one shard after the other
+        while !rest.is_empty() {
+            let writer = match train.as_mut() {
+                Some(writer) => writer,
+                None => {
+                    let path = match options.shard_size {
+                        Some(_) => PathBuf::from(format!("{}_train_{:06}.bin", prefix, num_shards)),
+                        None => PathBuf::from(format!("{}_train.bin", prefix)),
+                    };
+                    stats.files.push(path.clone());
+                    num_shards += 1;
+                    train.insert(TokenWriter::create(&path, uint16)?)
+                }
+            };
+            let room = options.shard_size.map_or(rest.len(), |shard_size| shard_size - shard_tokens);
+            let len = room.min(rest.len());
+            writer.write(&rest[..len])?;
+            shard_tokens += len;
+            rest = &rest[len..];
+            if options.shard_size == Some(shard_tokens) {
+                stats.train_tokens += train.take().unwrap().finish()?;
+                shard_tokens = 0;
+            }
+        }
+        position += chunk_len;
+    }
+
+    if let Some(writer) = val {
+        stats.val_tokens = writer.finish()?;
+    }
+    if let Some(writer) = train {
+        stats.train_tokens += writer.finish()?;
+    }
+    Ok(stats)
+}
+
+/// Collects the files of a path, recursing into directories in name order.
+///
+/// # Arguments
+///
+/// * `path` - A file or a directory.
+/// * `files` - The files found so far.
+pub fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        collect_files(&entry, files)?;
+    }
+    Ok(())
+}
+
+/// Reads the documents of a text or JSONL file, skipping the blank ones.
+///
+/// # Arguments
+///
+/// * `path` - Path to the file. Bytes that are not valid UTF-8 are read as U+FFFD.
+/// * `options` - The delimiter of the text files and the field of the JSONL files.
+/// * `documents` - The documents read so far.
+fn read_documents(path: &Path, options: &PreproOptions, documents: &mut Vec<String>) -> Result<()> {
+    let data = fs::read(path)?;
+    let text = String::from_utf8_lossy(&data);
+    let mut push = |document: &str| {
+        if !document.trim().is_empty() {
+            documents.push(document.to_string());
+        }
+    };
+
+    if path.extension().is_some_and(|extension| extension == "jsonl") {
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| {
+                Error::InvalidInput(format!("line {} of {}: {}", i + 1, path.display(), reason))
+            };
+            let value: serde_json::Value =
+                serde_json::from_str(line).map_err(|err| invalid(err.to_string()))?;
+            let Some(document) = value.get(&options.text_field).and_then(|text| text.as_str())
+            else {
+                return Err(invalid(format!(
+                    "no string field \"{}\"",
+                    options.text_field
+                )));
+            };
+            push(document);
+        }
+    } else {
+        match &options.delimiter {
+            Some(delimiter) => text.split(delimiter.as_str()).for_each(push),
+            None => push(&text),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOT: u32 = 256;
+
+    /// A tokenizer encoding every byte as its own token.
+    fn byte_tokenizer() -> Tokenizer {
+        let mut token_table: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        token_table.push(b"<|endoftext|>".to_vec());
+        Tokenizer::from_tokens(token_table, Vec::new(), Some(EOT)).unwrap()
+    }
+
+    /// Reads the tokens of a shard with a header and uint16 tokens.
+    fn read_shard(path: &Path) -> Vec<u32> {
+        let data = fs::read(path).unwrap();
+        let header: Vec<i32> = data[..1024]
+            .chunks_exact(4)
+            .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(header[..2], [20240520, 1]);
+        let tokens: Vec<u32> = data[1024..]
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()) as u32)
+            .collect();
+        assert_eq!(tokens.len(), header[2] as usize);
+        tokens
+    }
+
+    /// The tokens of the documents, each starting with EOT.
+    fn documents(texts: &[&str]) -> Vec<u32> {
+        texts
+            .iter()
+            .flat_map(|text| std::iter::once(EOT).chain(text.bytes().map(u32::from)))
+            .collect()
+    }
+
+    /// A directory with the given files, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("llm-rs-prepro-{}-{}", name, std::process::id()));
+            fs::create_dir_all(dir.join("inputs")).unwrap();
+            for (file, content) in files {
+                fs::write(dir.join("inputs").join(file), content).unwrap();
+            }
+            TestDir(dir)
+        }
+
+        fn run(&self, options: &PreproOptions) -> Result<PreproStats> {
+            preprocess(&[self.0.join("inputs")], &byte_tokenizer(), &self.0.join("out"), options)
+        }
+
+        fn output(&self, suffix: &str) -> PathBuf {
+            self.0.join(format!("out_{}.bin", suffix))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn all_train(options: PreproOptions) -> PreproOptions {
+        PreproOptions { val_fraction: 0.0, ..options }
+    }
+
+    #[test]
+    fn delimiter_splits_text_files() {
+        let dir = TestDir::new("delimiter", &[("a.txt", "one\n\ntwo\n\n \n\nthree"), ("b.txt", "four")]);
+
+        let options = all_train(PreproOptions { delimiter: Some("\n\n".to_string()), ..Default::default() });
+        let stats = dir.run(&options).unwrap();
+        // The blank document is skipped, the files are read in name order
+        assert_eq!(stats.num_documents, 4);
+        assert_eq!(stats.files, [dir.output("train")]);
+        assert_eq!(read_shard(&dir.output("train")), documents(&["one", "two", "three", "four"]));
+        assert_eq!((stats.train_tokens, stats.val_tokens), (19, 0));
+
+        // Without a delimiter each file is a document
+        let stats = dir.run(&all_train(PreproOptions::default())).unwrap();
+        assert_eq!(stats.num_documents, 2);
+        assert_eq!(read_shard(&dir.output("train")), documents(&["one\n\ntwo\n\n \n\nthree", "four"]));
+        assert!(!dir.0.join("out_tokens.tmp").exists());
+    }
+
+    #[test]
+    fn jsonl_documents_come_from_the_text_field() {
+        let lines = "{\"text\": \"hi\", \"body\": \"x\"}\n\n{\"body\": \"y\", \"text\": \"yo\"}\n{\"text\": \" \"}\n";
+        let dir = TestDir::new("jsonl", &[("a.jsonl", lines)]);
+
+        let stats = dir.run(&all_train(PreproOptions::default())).unwrap();
+        assert_eq!(stats.num_documents, 2);
+        assert_eq!(read_shard(&dir.output("train")), documents(&["hi", "yo"]));
+
+        // The blank line is skipped, the 4th line has no body
+        let options = all_train(PreproOptions { text_field: "body".to_string(), ..Default::default() });
+        let err = dir.run(&options).unwrap_err().to_string();
+        assert!(err.contains("line 4 of") && err.contains("no string field \"body\""), "{}", err);
+        let dir = TestDir::new("jsonl-body", &[("a.jsonl", lines.trim_end_matches("{\"text\": \" \"}\n"))]);
+        let stats = dir.run(&options).unwrap();
+        assert_eq!(read_shard(&dir.output("train")), documents(&["x", "y"]));
+        assert_eq!(stats.num_documents, 2);
+        let dir = TestDir::new("jsonl-invalid", &[("a.jsonl", "{\"text\": \"hi\"}\nnot json\n")]);
+        let err = dir.run(&PreproOptions::default()).unwrap_err().to_string();
+        assert!(err.contains("line 2 of"), "{}", err);
+    }
+
+    #[test]
+    fn first_documents_go_to_validation() {
+        let dir = TestDir::new("val", &[("a.txt", "aaaa|bb|c")]);
+        let with_val = |val_fraction| PreproOptions {
+            delimiter: Some("|".to_string()),
+            val_fraction,
+            ..Default::default()
+        };
+
+        // 10 tokens: documents are added to validation until it has 3
+        let stats = dir.run(&with_val(0.3)).unwrap();
+        assert_eq!(stats.files, [dir.output("val"), dir.output("train")]);
+        assert_eq!(read_shard(&dir.output("val")), documents(&["aaaa"]));
+        assert_eq!(read_shard(&dir.output("train")), documents(&["bb", "c"]));
+        assert_eq!((stats.train_tokens, stats.val_tokens), (5, 5));
+
+        let stats = dir.run(&with_val(0.6)).unwrap();
+        assert_eq!(read_shard(&dir.output("val")), documents(&["aaaa", "bb"]));
+        assert_eq!((stats.train_tokens, stats.val_tokens), (2, 8));
+
+        fs::remove_file(dir.output("train")).unwrap();
+        let stats = dir.run(&with_val(1.0)).unwrap();
+        assert_eq!(stats.files, [dir.output("val")]);
+        assert_eq!((stats.train_tokens, stats.val_tokens), (0, 10));
+        assert!(!dir.output("train").exists());
+    }
+
+    #[test]
+    fn train_split_is_sharded() {
+        // Documents longer than the chunks copied from the temporary file
+        let long = ["x".repeat(70000), "y".repeat(70000), "z".repeat(10)];
+        let long: Vec<&str> = long.iter().map(String::as_str).collect();
+        let dir = TestDir::new("shards", &[("a.txt", &long.join("|"))]);
+        let options = PreproOptions {
+            delimiter: Some("|".to_string()),
+            val_fraction: 0.4,
+            shard_size: Some(50000),
+            ..Default::default()
+        };
+
+        let stats = dir.run(&options).unwrap();
+        let shards: Vec<PathBuf> = (0..2).map(|i| dir.output(&format!("train_{:06}", i))).collect();
+        assert_eq!(stats.files, [vec![dir.output("val")], shards.clone()].concat());
+        assert_eq!(read_shard(&dir.output("val")), documents(&long[..1]));
+        let train = documents(&long[1..]);
+        assert_eq!(read_shard(&shards[0]), train[..50000]);
+        assert_eq!(read_shard(&shards[1]), train[50000..]);
+        assert_eq!((stats.num_documents, stats.train_tokens, stats.val_tokens), (3, 70012, 70001));
+
+        let options = PreproOptions { shard_size: Some(0), ..options };
+        assert!(dir.run(&options).is_err());
+    }
+}
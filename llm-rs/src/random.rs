@@ -0,0 +1,67 @@
+// ----------------------------------------------------------------------------
+// Random number generation
+// ----------------------------------------------------------------------------
+
+/// Generates a random `u32` using the xorshift* algorithm.
+///
+/// # Arguments
+///
+/// * `state` - A mutable reference to the RNG state.
+///
+/// # Returns
+///
+/// A random `u32` value.
+pub fn random_u32(state: &mut u64) -> u32 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    ((*state).wrapping_mul(0x2545F4914F6CDD1D) >> 32) as u32
+}
+
+/// Generates a random `f32` in the range [0, 1).
+///
+/// # Arguments
+///
+/// * `state` - A mutable reference to the RNG state.
+///
+/// # Returns
+///
+/// A random `f32` value in the range [0, 1).
+pub fn random_f32(state: &mut u64) -> f32 {
+    (random_u32(state) >> 8) as f32 / 16777216.0
+}
+
+/// Fills a slice with samples of a normal distribution, using the Box-Muller transform.
+///
+/// # Arguments
+///
+/// * `data` - The slice to fill.
+/// * `mean` - Mean of the distribution.
+/// * `std` - Standard deviation of the distribution.
+/// * `state` - A mutable reference to the RNG state.
+pub fn normal_fill(data: &mut [f32], mean: f32, std: f32, state: &mut u64) {
+    for pair in data.chunks_mut(2) {
+        // 1 - u keeps the argument of the logarithm in (0, 1]
+        let u1 = 1.0 - random_f32(state);
+        let u2 = random_f32(state);
+        let radius = (-2.0 * u1.ln()).sqrt();
+        let theta = 2.0 * std::f32::consts::PI * u2;
+        pair[0] = mean + std * radius * theta.cos();
+        if let Some(second) = pair.get_mut(1) {
+            *second = mean + std * radius * theta.sin();
+        }
+    }
+}
+
+/// Shuffles a slice in place with the Fisher-Yates algorithm.
+///
+/// # Arguments
+///
+/// * `data` - The slice to shuffle.
+/// * `state` - A mutable reference to the RNG state.
+pub fn shuffle<T>(data: &mut [T], state: &mut u64) {
+    for i in (1..data.len()).rev() {
+        let j = (random_u32(state) as usize) % (i + 1);
+        data.swap(i, j);
+    }
+}
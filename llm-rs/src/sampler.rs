@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+
+use crate::random::random_f32;
+
+/// Picks the next token from the logits of a model, with the usual decoding controls.
+///
+/// The logits go through the bias, the penalties and the temperature, then the softmax
+/// probabilities are truncated by top-k, top-p and min-p and renormalized before sampling.
+/// The default settings sample from the plain softmax, like `sample_mult` on `acts.probs`.
+/// Sampling only depends on the seed and the inputs, so runs can be reproduced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sampler {
+    /// Divides the logits, lower is more deterministic. 0 always picks the most likely token.
+    pub temperature: f32,
+
+    /// Only sample among the `top_k` most likely tokens, 0 to disable.
+    pub top_k: usize,
+
+    /// Only sample among the most likely tokens whose cumulative probability reaches `top_p`,
+    /// 1 to disable.
+    pub top_p: f32,
+
+    /// Only sample among the tokens at least `min_p` times as likely as the most likely one,
+    /// 0 to disable.
+    pub min_p: f32,
+
+    /// Divides the positive logits (and multiplies the negative ones) of the tokens already in the
+    /// history, 1 to disable.
+    pub repetition_penalty: f32,
+
+    /// Subtracted from the logit of a token once per occurrence in the history, 0 to disable.
+    pub frequency_penalty: f32,
+
+    /// Added to the logit of the given tokens. `f32::NEG_INFINITY` bans a token.
+    pub logit_bias: HashMap<usize, f32>,
+
+    /// State of the RNG used for sampling.
+    pub rng_state: u64,
+}
+
+impl Sampler {
+    /// Creates a sampler with all the controls disabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - Seed of the sampling RNG.
+    ///
+    /// # Returns
+    ///
+    /// A new `Sampler` drawing from the plain softmax of the logits.
+    pub fn new(seed: u64) -> Self {
+        Sampler {
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 1.0,
+            min_p: 0.0,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+            logit_bias: HashMap::new(),
+            // xorshift must not start from 0
+            rng_state: seed.max(1),
+        }
+    }
+
+    /// Bans a token, it will never be sampled.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token to ban.
+    pub fn ban(&mut self, token: usize) {
+        self.logit_bias.insert(token, f32::NEG_INFINITY);
+    }
+
+    /// Computes the distribution the next token is drawn from.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - Logits of the next token, at least `V` of them (the padding is ignored).
+    /// * `V` - Real vocabulary size.
+    /// * `history` - Tokens of the sequence so far, for the penalties.
+    ///
+    /// # Returns
+    ///
+    /// The `V` probabilities, zero for the tokens removed by the bias or the truncations. When
+    /// no token has a finite logit left, all the probability goes to the first token that is not
+    /// banned, and they are all zero if every token is banned.
+    pub fn probabilities(&self, logits: &[f32], V: usize, history: &[i32]) -> Vec<f32> {
+        let mut logits = logits[..V].to_vec();
+
+        for (&token, &bias) in &self.logit_bias {
+            if token < V {
+                logits[token] += bias;
+            }
+        }
+
+        // Penalties of the tokens already seen
+        if self.repetition_penalty != 1.0 || self.frequency_penalty != 0.0 {
+            let mut counts = HashMap::new();
+            for &token in history {
+                if (0..V as i32).contains(&token) {
+                    *counts.entry(token as usize).or_insert(0usize) += 1;
+                }
+            }
+            for (token, count) in counts {
+                let logit = &mut logits[token];
+                if *logit > 0.0 {
+                    *logit /= self.repetition_penalty;
+                } else {
+                    *logit *= self.repetition_penalty;
+                }
+                *logit -= self.frequency_penalty * count as f32;
+            }
+        }
+
+        let mut probs = vec![0.0; V];
+        if logits.iter().all(|&logit| logit == f32::NEG_INFINITY) {
+            // The softmax is undefined and every token is as likely, pick the first one not banned
+            if let Some(token) = (0..V).find(|token| self.logit_bias.get(token) != Some(&f32::NEG_INFINITY)) {
+                probs[token] = 1.0;
+            }
+            return probs;
+        }
+        if self.temperature <= 0.0 {
+            // Greedy decoding, ties go to the lowest token
+            let mut best = 0;
+            for (token, &logit) in logits.iter().enumerate() {
+                if logit > logits[best] {
+                    best = token;
+                }
+            }
+            probs[best] = 1.0;
+            return probs;
+        }
+
+        // Softmax at the given temperature
+        let mut maxval = f32::NEG_INFINITY;
+        for logit in logits.iter_mut() {
+            if self.temperature != 1.0 {
+                *logit /= self.temperature;
+            }
+            if *logit > maxval {
+                maxval = *logit;
+            }
+        }
+        let mut sum = 0.0;
+        for (prob, &logit) in probs.iter_mut().zip(&logits) {
+            *prob = (logit - maxval).exp();
+            sum += *prob;
+        }
+        for prob in probs.iter_mut() {
+            *prob /= sum;
+        }
+
+        if self.top_k == 0 && self.top_p >= 1.0 && self.min_p <= 0.0 {
+            return probs;
+        }
+
+        // Most likely tokens first, ties in token order
+        let mut order: Vec<usize> = (0..V).collect();
+        order.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+        let mut keep = if self.top_k > 0 { self.top_k.min(V) } else { V };
+        if self.top_p < 1.0 {
+            let mut cumulative = 0.0;
+            for (i, &token) in order[..keep].iter().enumerate() {
+                cumulative += probs[token];
+                if cumulative >= self.top_p {
+                    keep = i + 1;
+                    break;
+                }
+            }
+        }
+        if self.min_p > 0.0 {
+            let threshold = self.min_p * probs[order[0]];
+            keep = order[..keep]
+                .iter()
+                .take_while(|&&token| probs[token] >= threshold)
+                .count()
+                .max(1);
+        }
+
+        let kept_sum: f32 = order[..keep].iter().map(|&token| probs[token]).sum();
+        for &token in &order[keep..] {
+            probs[token] = 0.0;
+        }
+        for &token in &order[..keep] {
+            probs[token] /= kept_sum;
+        }
+        probs
+    }
+
+    /// Samples the next token.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - Logits of the next token, at least `V` of them (the padding is ignored).
+    /// * `V` - Real vocabulary size.
+    /// * `history` - Tokens of the sequence so far, for the penalties.
+    ///
+    /// # Returns
+    ///
+    /// The sampled token, or `None` if every token is banned.
+    pub fn sample(&mut self, logits: &[f32], V: usize, history: &[i32]) -> Option<usize> {
+        let probs = self.probabilities(logits, V, history);
+        let coin = random_f32(&mut self.rng_state);
+        let mut cdf = 0.0;
+        let mut last = None;
+        for (token, &prob) in probs.iter().enumerate() {
+            if prob > 0.0 {
+                cdf += prob;
+                last = Some(token);
+                if coin < cdf {
+                    return last;
+                }
+            }
+        }
+        last // in case of rounding errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Logits whose softmax is `probs`.
+    fn logits_of(probs: &[f32]) -> Vec<f32> {
+        probs.iter().map(|prob| prob.ln()).collect()
+    }
+
+    fn assert_probs(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (&a, &e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} vs {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn same_seed_samples_the_same_tokens() {
+        let logits = [0.0, 1.0, 2.0, 0.5, -1.0];
+        let samples = |seed: u64| {
+            let mut sampler = Sampler::new(seed);
+            (0..50).map(|_| sampler.sample(&logits, 5, &[]).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(samples(7), samples(7));
+        assert_ne!(samples(7), samples(8));
+    }
+
+    #[test]
+    fn truncations_keep_the_most_likely_tokens() {
+        let logits = logits_of(&[0.1, 0.4, 0.2, 0.3]);
+        let probabilities = |configure: &dyn Fn(&mut Sampler)| {
+            let mut sampler = Sampler::new(1);
+            configure(&mut sampler);
+            sampler.probabilities(&logits, 4, &[])
+        };
+        assert_probs(&probabilities(&|_| {}), &[0.1, 0.4, 0.2, 0.3]);
+        assert_probs(&probabilities(&|sampler| sampler.temperature = 0.0), &[0.0, 1.0, 0.0, 0.0]);
+        let top_two = [0.0, 0.4 / 0.7, 0.0, 0.3 / 0.7];
+        assert_probs(&probabilities(&|sampler| sampler.top_k = 2), &top_two);
+        assert_probs(&probabilities(&|sampler| sampler.top_p = 0.65), &top_two);
+        assert_probs(&probabilities(&|sampler| sampler.top_p = 0.35), &[0.0, 1.0, 0.0, 0.0]);
+        assert_probs(&probabilities(&|sampler| sampler.min_p = 0.6), &top_two);
+        // The truncations combine, the strictest one wins
+        let combined = probabilities(&|sampler| {
+            sampler.top_k = 3;
+            sampler.top_p = 0.65;
+            sampler.min_p = 0.1;
+        });
+        assert_probs(&combined, &top_two);
+    }
+
+    #[test]
+    fn penalties_lower_the_seen_tokens() {
+        let logits = [2.0, -2.0, 1.0];
+        let history = [0, 1, 0];
+        let softmax = |logits: &[f32]| {
+            let sum: f32 = logits.iter().map(|logit| logit.exp()).sum();
+            logits.iter().map(|logit| logit.exp() / sum).collect::<Vec<_>>()
+        };
+
+        // Positive logits are divided and negative ones multiplied, whatever the count
+        let mut sampler = Sampler::new(1);
+        sampler.repetition_penalty = 2.0;
+        assert_probs(&sampler.probabilities(&logits, 3, &history), &softmax(&[1.0, -4.0, 1.0]));
+
+        // Once per occurrence
+        let mut sampler = Sampler::new(1);
+        sampler.frequency_penalty = 0.5;
+        assert_probs(&sampler.probabilities(&logits, 3, &history), &softmax(&[1.0, -2.5, 1.0]));
+    }
+
+    #[test]
+    fn banned_tokens_are_never_sampled() {
+        let mut sampler = Sampler::new(3);
+        sampler.ban(1);
+        let logits = [0.0, 10.0, 0.0];
+        assert!((0..100).all(|_| sampler.sample(&logits, 3, &[]) != Some(1)));
+        sampler.temperature = 0.0;
+        assert_eq!(sampler.sample(&logits, 3, &[]), Some(0));
+
+        // Without a finite logit, the first token not banned
+        let logits = [f32::NEG_INFINITY; 3];
+        sampler.ban(0);
+        for temperature in [0.0, 1.0] {
+            sampler.temperature = temperature;
+            assert_eq!(sampler.sample(&logits, 3, &[]), Some(2));
+        }
+        sampler.ban(2);
+        assert_eq!(sampler.sample(&logits, 3, &[]), None);
+        assert_eq!(sampler.sample(&[0.0, 10.0, 0.0], 3, &[]), None);
+    }
+}
@@ -0,0 +1,649 @@
+use std::fs;
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::dataloader::permutation_state;
+use crate::error::{Error, Result};
+use crate::gpt2::IGNORE_INDEX;
+use crate::prepro::collect_files;
+use crate::random;
+use crate::send_ptr::SendPtr;
+use crate::tokenizer::Tokenizer;
+
+/// Text of the special token the template strings may contain.
+const EOT_TEXT: &str = "<|endoftext|>";
+
+/// A message of a conversation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// Who wrote the message, e.g. `system`, `user` or `assistant`.
+    pub role: String,
+
+    /// Text of the message.
+    pub content: String,
+}
+
+/// How the messages of a conversation are laid out as text for the model.
+///
+/// A conversation is `conversation_start`, then for each message `message_start` (with `{role}`
+/// replaced by its role), its content and `message_end`. The template strings may contain
+/// `<|endoftext|>`, which becomes the EOT token, while the roles and contents are always plain
+/// text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatTemplate {
+    /// Text starting each conversation.
+    pub conversation_start: String,
+
+    /// Text before the content of each message, `{role}` is replaced by the role of the message.
+    pub message_start: String,
+
+    /// Text after the content of each message. The model learns it after its own messages, so it
+    /// should tell where they stop.
+    pub message_end: String,
+
+    /// Role of the messages the model learns to write.
+    pub assistant_role: String,
+}
+
+impl Default for ChatTemplate {
+    /// Creates the default template: `<|role|>` headers on their own line, and messages ending
+    /// with the EOT token, so that generation stops at the end of the answer.
+    ///
+    /// # Returns
+    ///
+    /// New `ChatTemplate`.
+    fn default() -> Self {
+        ChatTemplate {
+            conversation_start: String::new(),
+            message_start: "<|{role}|>\n".to_string(),
+            message_end: "<|endoftext|>".to_string(),
+            assistant_role: "assistant".to_string(),
+        }
+    }
+}
+
+impl ChatTemplate {
+    /// Reads a template from a JSON object, e.g. `{"message_start": "### {role}:\n"}`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the JSON file. Its keys are the names of the fields, the missing ones
+    ///   keep their default value.
+    ///
+    /// # Returns
+    ///
+    /// The template, or an error if the file cannot be read or has unknown keys.
+    pub fn from_json(path: &Path) -> Result<Self> {
+        let invalid = |reason: String| Error::InvalidInput(format!("{}: {}", path.display(), reason));
+        let data = fs::read_to_string(path)?;
+        let value: serde_json::Value =
+            serde_json::from_str(&data).map_err(|err| invalid(err.to_string()))?;
+        let Some(object) = value.as_object() else {
+            return Err(invalid("expected a JSON object".to_string()));
+        };
+
+        let mut template = ChatTemplate::default();
+        for (key, value) in object {
+            let field = match key.as_str() {
+                "conversation_start" => &mut template.conversation_start,
+                "message_start" => &mut template.message_start,
+                "message_end" => &mut template.message_end,
+                "assistant_role" => &mut template.assistant_role,
+                _ => return Err(invalid(format!("unknown key \"{}\"", key))),
+            };
+            let Some(text) = value.as_str() else {
+                return Err(invalid(format!("\"{}\" is not a string", key)));
+            };
+            *field = text.to_string();
+        }
+        Ok(template)
+    }
+
+    /// Encodes a conversation, marking the tokens the model learns to predict.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokenizer` - Tokenizer with merges.
+    /// * `messages` - The messages of the conversation.
+    ///
+    /// # Returns
+    ///
+    /// The tokens of the conversation, and for each of them whether it is a target of the loss:
+    /// the contents and `message_end` of the assistant messages.
+    pub fn encode(&self, tokenizer: &Tokenizer, messages: &[Message]) -> (Vec<u32>, Vec<bool>) {
+        let mut tokens = tokenizer.encode(&self.conversation_start);
+        let mut trained = vec![false; tokens.len()];
+        for message in messages {
+            let is_assistant = message.role == self.assistant_role;
+            let mut push = |piece: Vec<u32>, learn: bool| {
+                trained.extend(std::iter::repeat_n(learn, piece.len()));
+                tokens.extend(piece);
+            };
+            push(self.encode_message_start(tokenizer, &message.role), false);
+            push(tokenizer.encode_ordinary(&message.content), is_assistant);
+            push(tokenizer.encode(&self.message_end), is_assistant);
+        }
+        (tokens, trained)
+    }
+
+    /// Encodes a conversation as a prompt for the model to write the next assistant message.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokenizer` - Tokenizer with merges.
+    /// * `messages` - The messages so far, usually ending with a user message.
+    ///
+    /// # Returns
+    ///
+    /// The tokens of the messages followed by the `message_start` of the assistant.
+    pub fn encode_prompt(&self, tokenizer: &Tokenizer, messages: &[Message]) -> Vec<u32> {
+        let (mut tokens, _) = self.encode(tokenizer, messages);
+        tokens.extend(self.encode_message_start(tokenizer, &self.assistant_role));
+        tokens
+    }
+
+    /// Encodes the `message_start` of a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokenizer` - Tokenizer with merges.
+    /// * `role` - Role of the message, encoded as plain text so that it cannot produce EOT.
+    ///
+    /// # Returns
+    ///
+    /// The tokens of `message_start` with the role in place of `{role}`.
+    fn encode_message_start(&self, tokenizer: &Tokenizer, role: &str) -> Vec<u32> {
+        let Some(eot_token) = tokenizer.eot_token() else {
+            return tokenizer.encode_ordinary(&self.message_start.replace("{role}", role));
+        };
+        let mut tokens = Vec::new();
+        for (i, part) in self.message_start.split(EOT_TEXT).enumerate() {
+            if i > 0 {
+                tokens.push(eot_token);
+            }
+            tokens.extend(tokenizer.encode_ordinary(&part.replace("{role}", role)));
+        }
+        tokens
+    }
+}
+
+/// Reads the conversations of JSONL files.
+///
+/// # Arguments
+///
+/// * `path` - A JSONL file, or a directory of them read in name order. Each line is an object with
+///   a `messages` array of `{"role": ..., "content": ...}` objects, like the chat datasets of
+///   OpenAI and HuggingFace.
+///
+/// # Returns
+///
+/// The messages of each conversation, or an error if a file cannot be read or parsed.
+pub fn read_conversations(path: &Path) -> Result<Vec<Vec<Message>>> {
+    let mut files = Vec::new();
+    collect_files(path, &mut files)?;
+    let mut conversations = Vec::new();
+    for file in &files {
+        read_conversations_file(file, &mut conversations)?;
+    }
+    Ok(conversations)
+}
+
+/// Reads the conversations of a JSONL file, skipping the blank lines.
+///
+/// # Arguments
+///
+/// * `path` - Path to the file.
+/// * `conversations` - The conversations read so far.
+fn read_conversations_file(path: &Path, conversations: &mut Vec<Vec<Message>>) -> Result<()> {
+    let data = fs::read(path)?;
+    let text = String::from_utf8_lossy(&data);
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid =
+            |reason: &str| Error::InvalidInput(format!("line {} of {}: {}", i + 1, path.display(), reason));
+        let value: serde_json::Value =
+            serde_json::from_str(line).map_err(|err| invalid(&err.to_string()))?;
+        let Some(items) = value.get("messages").and_then(|messages| messages.as_array()) else {
+            return Err(invalid("no array field \"messages\""));
+        };
+        let mut messages = Vec::with_capacity(items.len());
+        for item in items {
+            let field = |name: &str| item.get(name).and_then(|field| field.as_str());
+            let (Some(role), Some(content)) = (field("role"), field("content")) else {
+                return Err(invalid("a message has no string \"role\" and \"content\""));
+            };
+            messages.push(Message {
+                role: role.to_string(),
+                content: content.to_string(),
+            });
+        }
+        conversations.push(messages);
+    }
+    Ok(())
+}
+
+/// Reads conversations in batches for supervised fine-tuning, with the loss on the assistant
+/// messages only.
+///
+/// The conversations are packed into rows of T tokens, several per row when they fit, and
+/// padded. The targets of the tokens that are not learned are `IGNORE_INDEX`, so they do not
+/// count in `GPT2::mean_loss` nor in the gradients, and `doc_starts` keeps each conversation
+/// from attending to the other ones of its row.
+pub struct SftLoader {
+    /// Batch size
+    pub B: usize,
+
+    /// Sequence length
+    pub T: usize,
+
+    /// Input tokens of all the rows, padded to a whole number of batches
+    row_inputs: Vec<i32>,
+
+    /// Target tokens of all the rows
+    row_targets: Vec<i32>,
+
+    /// Start of the conversation of each input token, in its row
+    row_doc_starts: Vec<i32>,
+
+    /// Seed of the shuffling, `None` to read the batches in order
+    shuffle_seed: Option<u64>,
+
+    /// Order of the batches in the current epoch
+    batch_order: Vec<usize>,
+
+    /// Number of complete passes over the conversations
+    pub epoch: usize,
+
+    /// Position of the next batch in the order of the epoch
+    pub batch_index: usize,
+
+    /// Whether `next_batch` goes on with the next epoch after the last batch of the current one
+    pub wrap: bool,
+
+    /// Whether the last batch of the epoch was read without `wrap`
+    exhausted: bool,
+
+    // ----------------------------------------------------------------------------
+    // Output memory
+    // ----------------------------------------------------------------------------
+    /// Pointer to input tokens
+    pub inputs: SendPtr<i32>,
+
+    /// Pointer to target tokens, `IGNORE_INDEX` where there is no loss
+    pub targets: SendPtr<i32>,
+
+    /// Pointer to the start of the conversation of each input token, in its row
+    pub doc_starts: SendPtr<i32>,
+
+    // ----------------------------------------------------------------------------
+    // Convenience variables
+    // ----------------------------------------------------------------------------
+    /// Number of conversations packed
+    pub num_conversations: usize,
+
+    /// Number of conversations longer than T tokens, cut after their first T tokens
+    pub num_truncated: usize,
+
+    /// Number of targets with a loss in an epoch
+    pub num_loss_tokens: usize,
+
+    /// Number of batches in an epoch
+    pub num_batches: usize,
+}
+
+impl SftLoader {
+    /// Creates a new SftLoader from the conversations of JSONL files, reading its batches in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - A JSONL file or a directory of them, see `read_conversations`.
+    /// * `tokenizer` - Tokenizer with merges.
+    /// * `template` - How to lay out the messages.
+    /// * `B` - Batch size.
+    /// * `T` - Sequence length.
+    ///
+    /// # Returns
+    ///
+    /// A new `SftLoader` instance, or an error if the files cannot be read or have no assistant
+    /// message, or if B or T is 0.
+    pub fn new(
+        path: &Path,
+        tokenizer: &Tokenizer,
+        template: &ChatTemplate,
+        B: usize,
+        T: usize,
+    ) -> Result<Self> {
+        let conversations = read_conversations(path)?;
+        Self::from_conversations(&conversations, tokenizer, template, B, T)
+    }
+
+    /// Creates a new SftLoader from conversations, reading its batches in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `conversations` - The messages of each conversation.
+    /// * `tokenizer` - Tokenizer with merges.
+    /// * `template` - How to lay out the messages.
+    /// * `B` - Batch size.
+    /// * `T` - Sequence length.
+    ///
+    /// # Returns
+    ///
+    /// A new `SftLoader` instance, or an error if the tokenizer cannot encode, B or T is 0, or no
+    /// conversation has an assistant message.
+    ///
+    /// # Note
+    ///
+    /// The conversations are packed in order: each one goes to the current row if it fits, and
+    /// starts a new row otherwise. The rest of the rows and of the last batch is padding with
+    /// the EOT token and no loss. Conversations with nothing to learn in their first T tokens
+    /// are skipped.
+    pub fn from_conversations(
+        conversations: &[Vec<Message>],
+        tokenizer: &Tokenizer,
+        template: &ChatTemplate,
+        B: usize,
+        T: usize,
+    ) -> Result<Self> {
+        if !tokenizer.can_encode() {
+            return Err(Error::InvalidInput(
+                "the tokenizer has no merges, re-run `python train_gpt2.py` to write them".to_string(),
+            ));
+        }
+        if B == 0 || T == 0 {
+            return Err(Error::InvalidInput(format!(
+                "the batches need at least one row of one token, got B = {} and T = {}",
+                B, T
+            )));
+        }
+        let pad_token = tokenizer.eot_token().unwrap_or(0) as i32;
+
+        // Conversations are independent, encode them in parallel
+        let encoded: Vec<(Vec<u32>, Vec<bool>)> = conversations
+            .par_iter()
+            .map(|messages| template.encode(tokenizer, messages))
+            .collect();
+
+        let mut loader = SftLoader {
+            B,
+            T,
+            row_inputs: Vec::new(),
+            row_targets: Vec::new(),
+            row_doc_starts: Vec::new(),
+            shuffle_seed: None,
+            batch_order: Vec::new(),
+            epoch: 0,
+            batch_index: 0,
+            wrap: true,
+            exhausted: false,
+            inputs: SendPtr::new(std::ptr::null_mut()),
+            targets: SendPtr::new(std::ptr::null_mut()),
+            doc_starts: SendPtr::new(std::ptr::null_mut()),
+            num_conversations: 0,
+            num_truncated: 0,
+            num_loss_tokens: 0,
+            num_batches: 0,
+        };
+
+        // Fill of the last row, a full row starts a new one
+        let mut used = T;
+        for (tokens, trained) in &encoded {
+            let length = tokens.len().min(T);
+            // The input at i predicts the token at i + 1 of the same conversation
+            let targets: Vec<i32> = (0..length)
+                .map(|i| {
+                    if i + 1 < length && trained[i + 1] {
+                        tokens[i + 1] as i32
+                    } else {
+                        IGNORE_INDEX
+                    }
+                })
+                .collect();
+            let num_loss_tokens = targets.iter().filter(|&&target| target != IGNORE_INDEX).count();
+            if num_loss_tokens == 0 {
+                continue;
+            }
+
+            if used + length > T {
+                loader.push_padding_row(pad_token);
+                used = 0;
+            }
+            let offset = loader.row_inputs.len() - T + used;
+            for i in 0..length {
+                loader.row_inputs[offset + i] = tokens[i] as i32;
+                loader.row_targets[offset + i] = targets[i];
+                loader.row_doc_starts[offset + i] = used as i32;
+            }
+            used += length;
+            loader.num_conversations += 1;
+            loader.num_truncated += (tokens.len() > T) as usize;
+            loader.num_loss_tokens += num_loss_tokens;
+        }
+        if loader.num_conversations == 0 {
+            return Err(Error::InvalidInput(format!(
+                "none of the {} conversations has a \"{}\" message to learn",
+                conversations.len(),
+                template.assistant_role
+            )));
+        }
+        while !loader.row_inputs.len().is_multiple_of(B * T) {
+            loader.push_padding_row(pad_token);
+        }
+        loader.num_batches = loader.row_inputs.len() / (B * T);
+        loader.reset();
+
+        Ok(loader)
+    }
+
+    /// Shuffles the order of the batches, with a new permutation every epoch, and starts over
+    /// from the first epoch.
+    ///
+    /// # Arguments
+    ///
+    /// * `seed` - Seed of the permutations. The same seed gives the same batches in the same order.
+    pub fn shuffle(&mut self, seed: u64) {
+        self.shuffle_seed = Some(seed);
+        self.reset();
+    }
+
+    /// Fraction of the batches of the current epoch already read, in [0, 1).
+    pub fn epoch_progress(&self) -> f64 {
+        self.batch_index as f64 / self.num_batches as f64
+    }
+
+    /// Resets the SftLoader to start from the beginning of the first epoch.
+    pub fn reset(&mut self) {
+        self.seek(0, 0);
+    }
+
+    /// Moves the SftLoader to a position saved from its counters, e.g. to resume training.
+    ///
+    /// # Arguments
+    ///
+    /// * `epoch` - Number of complete passes over the conversations.
+    /// * `batch_index` - Position of the next batch in the order of the epoch.
+    ///
+    /// # Returns
+    ///
+    /// An error if the position is outside of the dataset, e.g. it was saved with other data.
+    pub fn resume(&mut self, epoch: usize, batch_index: usize) -> Result<()> {
+        if batch_index >= self.num_batches {
+            return Err(Error::InvalidInput(format!(
+                "no batch {} in the {} batches of the conversations",
+                batch_index, self.num_batches
+            )));
+        }
+        self.seek(epoch, batch_index);
+        Ok(())
+    }
+
+    /// Points `inputs`, `targets` and `doc_starts` to the next batch.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a batch was loaded, `false` without `wrap` once the last batch of the epoch was
+    /// read before.
+    pub fn next_batch(&mut self) -> bool {
+        if self.exhausted && !self.wrap {
+            return false;
+        }
+        let offset = self.batch_order[self.batch_index] * self.B * self.T;
+        self.inputs = SendPtr::new(self.row_inputs[offset..].as_mut_ptr());
+        self.targets = SendPtr::new(self.row_targets[offset..].as_mut_ptr());
+        self.doc_starts = SendPtr::new(self.row_doc_starts[offset..].as_mut_ptr());
+
+        self.batch_index += 1;
+        if self.batch_index == self.num_batches {
+            self.seek(self.epoch + 1, 0);
+            self.exhausted = !self.wrap;
+        }
+        true
+    }
+
+    /// Moves the SftLoader to a position inside of the dataset.
+    fn seek(&mut self, epoch: usize, batch_index: usize) {
+        self.batch_order = (0..self.num_batches).collect();
+        if let Some(seed) = self.shuffle_seed {
+            let mut state = permutation_state(seed, epoch, 0);
+            random::shuffle(&mut self.batch_order, &mut state);
+        }
+        self.epoch = epoch;
+        self.batch_index = batch_index;
+        self.exhausted = false;
+    }
+
+    /// Appends a row of padding: EOT inputs without targets, each its own document.
+    fn push_padding_row(&mut self, pad_token: i32) {
+        self.row_inputs.extend(std::iter::repeat_n(pad_token, self.T));
+        self.row_targets.extend(std::iter::repeat_n(IGNORE_INDEX, self.T));
+        self.row_doc_starts.extend(0..self.T as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOT: u32 = 256;
+
+    /// A tokenizer encoding every byte as its own token.
+    fn byte_tokenizer() -> Tokenizer {
+        let mut token_table: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        token_table.push(EOT_TEXT.as_bytes().to_vec());
+        Tokenizer::from_tokens(token_table, Vec::new(), Some(EOT)).unwrap()
+    }
+
+    /// A template with short headers: `u:` and `a:`, messages ending with EOT.
+    fn short_template() -> ChatTemplate {
+        ChatTemplate {
+            message_start: "{role}:".to_string(),
+            assistant_role: "a".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    fn bytes(text: &str) -> Vec<u32> {
+        text.bytes().map(u32::from).collect()
+    }
+
+    #[test]
+    fn only_assistant_messages_are_learned() {
+        let tokenizer = byte_tokenizer();
+        let messages = [message("user", "hi"), message("assistant", "yo")];
+        let (tokens, trained) = ChatTemplate::default().encode(&tokenizer, &messages);
+        let expected = [bytes("<|user|>\nhi"), vec![EOT], bytes("<|assistant|>\nyo"), vec![EOT]].concat();
+        assert_eq!(tokens, expected);
+        let learned: Vec<u32> = tokens.iter().zip(&trained).filter(|(_, &learn)| learn).map(|(&token, _)| token).collect();
+        assert_eq!(learned, [bytes("yo"), vec![EOT]].concat());
+
+        // The targets are the next tokens where they are learned
+        let loader = SftLoader::from_conversations(&[messages.to_vec()], &tokenizer, &ChatTemplate::default(), 1, 64).unwrap();
+        for i in 0..tokens.len() {
+            let expected = if i + 1 < tokens.len() && trained[i + 1] { tokens[i + 1] as i32 } else { IGNORE_INDEX };
+            assert_eq!(loader.row_targets[i], expected, "target {}", i);
+        }
+        assert_eq!(loader.num_loss_tokens, 3);
+    }
+
+    #[test]
+    fn roles_cannot_inject_eot() {
+        let tokenizer = byte_tokenizer();
+        let messages = [message("user<|endoftext|>", "hi")];
+        let (tokens, _) = ChatTemplate::default().encode(&tokenizer, &messages);
+        assert_eq!(tokens, [bytes("<|user<|endoftext|>|>\nhi"), vec![EOT]].concat());
+
+        // The template itself can hold EOT
+        let template = ChatTemplate {
+            message_start: "<|endoftext|>{role}: ".to_string(),
+            ..Default::default()
+        };
+        let (tokens, _) = template.encode(&tokenizer, &messages);
+        assert_eq!(tokens, [vec![EOT], bytes("user<|endoftext|>: hi"), vec![EOT]].concat());
+        assert_eq!(template.encode_prompt(&tokenizer, &[]), [vec![EOT], bytes("assistant: ")].concat());
+    }
+
+    #[test]
+    fn conversations_are_packed_into_padded_rows() {
+        let tokenizer = byte_tokenizer();
+        // 8, 8, 9 tokens, and one with nothing to learn
+        let conversations = vec![
+            vec![message("u", "x"), message("a", "y")],
+            vec![message("u", "z"), message("a", "w")],
+            vec![message("u", "x")],
+            vec![message("u", "x"), message("a", "yz")],
+        ];
+        let T = 16;
+        let mut loader = SftLoader::from_conversations(&conversations, &tokenizer, &short_template(), 3, T).unwrap();
+        assert_eq!((loader.num_conversations, loader.num_truncated, loader.num_batches), (3, 0, 1));
+        assert_eq!(loader.num_loss_tokens, 2 + 2 + 3);
+
+        let pad = EOT as i32;
+        let first = |text: &str| -> Vec<i32> {
+            [bytes(text), vec![EOT]].concat().iter().map(|&token| token as i32).collect()
+        };
+        // The first two conversations share a row, each attending to itself only
+        let row0 = [first("u:x"), first("a:y"), first("u:z"), first("a:w")].concat();
+        assert_eq!(loader.row_inputs[..T], row0);
+        assert_eq!(loader.row_doc_starts[..T], [[0; 8], [8; 8]].concat());
+        // The third one starts a new row, padded with EOT tokens of their own, without loss
+        let row1 = [first("u:x"), first("a:yz"), vec![pad; 7]].concat();
+        assert_eq!(loader.row_inputs[T..2 * T], row1);
+        assert_eq!(loader.row_doc_starts[T..2 * T], [vec![0; 9], (9..16).collect()].concat());
+        assert!(loader.row_targets[T + 8..2 * T].iter().all(|&target| target == IGNORE_INDEX));
+        assert_eq!(loader.row_targets[T + 5..T + 8], [b'y' as i32, b'z' as i32, pad]);
+        // A padding row completes the batch
+        assert_eq!(loader.row_inputs[2 * T..], [pad; 16]);
+        assert!(loader.row_targets[2 * T..].iter().all(|&target| target == IGNORE_INDEX));
+        assert_eq!(loader.row_doc_starts[2 * T..], (0..16).collect::<Vec<_>>());
+
+        assert!(loader.next_batch());
+        assert_eq!(loader.inputs.ptr, loader.row_inputs.as_mut_ptr());
+
+        // A conversation longer than T is cut
+        let loader = SftLoader::from_conversations(&conversations[..1], &tokenizer, &short_template(), 1, 7).unwrap();
+        assert_eq!((loader.num_truncated, loader.num_loss_tokens), (1, 1));
+        assert_eq!(loader.row_targets[5..7], [b'y' as i32, IGNORE_INDEX]);
+    }
+
+    #[test]
+    fn empty_batches_are_rejected() {
+        let tokenizer = byte_tokenizer();
+        let conversations = vec![vec![message("u", "x"), message("a", "y")]];
+        for (B, T) in [(0, 16), (1, 0), (0, 0)] {
+            let result = SftLoader::from_conversations(&conversations, &tokenizer, &short_template(), B, T);
+            assert!(matches!(result, Err(Error::InvalidInput(_))));
+        }
+        let result = SftLoader::from_conversations(&conversations[..0], &tokenizer, &short_template(), 1, 16);
+        assert!(matches!(result, Err(Error::InvalidInput(_))));
+    }
+}
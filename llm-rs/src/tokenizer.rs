@@ -1,11 +1,31 @@
-use std::fs::File;
-use std::io::Read;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
 use std::path::Path;
-use std::io::Write;
+use std::sync::OnceLock;
+
+use fancy_regex::Regex;
+
+use crate::error::{Error, Result};
+
+/// Pre-tokenization pattern of GPT-2, as used by tiktoken. BPE never merges across its matches.
+const GPT2_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
 
 pub struct Tokenizer {
     vocab_size: u32,
-    token_table: Vec<String>,
+    /// Bytes of each token, which are not always valid UTF-8 on their own.
+    token_table: Vec<Vec<u8>>,
+    /// Token of each byte sequence that can be produced by encoding, i.e. every token but EOT.
+    encoder: HashMap<Vec<u8>, u32>,
+    /// The merges as pairs of tokens, from the first to the last.
+    merges: Vec<(u32, u32)>,
+    /// Priority of each merge (lower merges first), keyed by the bytes it produces.
+    merge_ranks: HashMap<Vec<u8>, u32>,
+    /// The `<|endoftext|>` token, if the vocabulary has one.
+    eot_token: Option<u32>,
     pub init_ok: bool,
 }
 
@@ -18,69 +38,457 @@ impl Tokenizer {
     ///
     /// # Returns
     ///
-    /// A new `Tokenizer` instance.
-    pub fn new(filename: &Path) -> Self {
+    /// A new `Tokenizer` instance, or an error if the file cannot be read or is invalid.
+    /// The tokenizer file was added on April 14 2024, re-run `python train_gpt2.py` to write it.
+    ///
+    /// # Note
+    ///
+    /// Version 2 files can only decode. Version 3 files also store the BPE merges, as
+    /// `header[4]` pairs of `u32` token ids after the token table, and can encode.
+    pub fn new(filename: &Path) -> Result<Self> {
         let mut tokenizer = Tokenizer {
             vocab_size: 0,
             token_table: Vec::new(),
+            encoder: HashMap::new(),
+            merges: Vec::new(),
+            merge_ranks: HashMap::new(),
+            eot_token: None,
             init_ok: false,
         };
 
-        let mut file = match File::open(filename) {
-            Ok(file) => file,
-            Err(_) => {
-                panic!(
-                    "---\n
-                    WARNING: Failed to open the tokenizer file\n
-                    The Tokenizer is a new feature added April 14 2024.\n
-                    Re-run `python train_gpt2.py` to write it\n
-                    ---"
-                );
-            }
-        };
+        let mut data = Vec::new();
+        File::open(filename)?.read_to_end(&mut data)?;
 
-        let mut header = [0; 256];
-        file.read_exact(unsafe {
-            std::slice::from_raw_parts_mut(
-                header.as_mut_ptr() as *mut u8,
-                header.len() * std::mem::size_of::<u32>(),
-            )
-        })
-        .expect("Failed to read header");
+        let mut header = [0u32; 256];
+        let header_bytes = mem::size_of_val(&header);
+        if data.len() < header_bytes {
+            return Err(Error::Truncated {
+                expected: header_bytes as u64,
+                actual: data.len() as u64,
+            });
+        }
+        for (value, chunk) in header.iter_mut().zip(data.chunks_exact(mem::size_of::<u32>())) {
+            *value = u32::from_ne_bytes(chunk.try_into().unwrap());
+        }
 
         // Check magic number and version
         if header[0] != 20240328 {
-            panic!("Bad magic tokenizer file");
+            return Err(Error::BadMagic {
+                expected: 20240328,
+                found: header[0] as i32,
+            });
         }
-        if header[1] != 2 {
-            panic!("Bad version in tokenizer file")
+        if header[1] != 2 && header[1] != 3 {
+            return Err(Error::BadVersion(header[1] as i32));
         }
 
         tokenizer.vocab_size = header[2];
+        if header[3] >= header[2] {
+            return Err(Error::InconsistentHeader(format!(
+                "EOT token {} is out of the vocabulary of {} tokens",
+                header[3], header[2]
+            )));
+        }
+        tokenizer.eot_token = Some(header[3]);
 
+        let mut offset = header_bytes;
         for _ in 0..tokenizer.vocab_size {
-            let mut length = [0];
-            file.read_exact(&mut length)
-                .expect("Failed to read token length");
-
-            assert!(length[0] > 0); // Every token should be at least one character
-            let mut token_bytes = vec![0u8; length[0] as usize];
-            file.read_exact(&mut token_bytes)
-                .expect("Failed to read token bytes");
-            let token = match String::from_utf8(token_bytes) {
-                Ok(token) => token,
-                Err(_) => String::new(),
+            let Some(&length) = data.get(offset) else {
+                return Err(Error::Truncated {
+                    expected: offset as u64 + 1,
+                    actual: data.len() as u64,
+                });
             };
+            // Every token should be at least one character
+            if length == 0 {
+                return Err(Error::InconsistentHeader(format!(
+                    "token {} has a length of zero",
+                    tokenizer.token_table.len()
+                )));
+            }
+            let end = offset + 1 + length as usize;
+            let Some(token_bytes) = data.get(offset + 1..end) else {
+                return Err(Error::Truncated {
+                    expected: end as u64,
+                    actual: data.len() as u64,
+                });
+            };
+            tokenizer.token_table.push(token_bytes.to_vec());
+            offset = end;
+        }
 
-            tokenizer.token_table.push(token);
+        if header[1] == 3 {
+            let num_merges = header[4] as usize;
+            let end = offset + num_merges * 2 * mem::size_of::<u32>();
+            let Some(merge_bytes) = data.get(offset..end) else {
+                return Err(Error::Truncated {
+                    expected: end as u64,
+                    actual: data.len() as u64,
+                });
+            };
+            let merges = merge_bytes
+                .chunks_exact(2 * mem::size_of::<u32>())
+                .map(|pair| {
+                    (
+                        u32::from_ne_bytes(pair[..4].try_into().unwrap()),
+                        u32::from_ne_bytes(pair[4..].try_into().unwrap()),
+                    )
+                })
+                .collect();
+            tokenizer.set_merges(merges)?;
         }
 
         tokenizer.init_ok = true;
 
-        tokenizer
+        Ok(tokenizer)
     }
 
-    /// Decodes a token ID into its corresponding string.
+    /// Creates a tokenizer from the `vocab.json` and `merges.txt` files of a HuggingFace GPT-2
+    /// tokenizer, which store the bytes of the tokens as printable characters.
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab_path` - Path to `vocab.json`, mapping each token to its id.
+    /// * `merges_path` - Path to `merges.txt`, listing the merges from the first to the last.
+    ///
+    /// # Returns
+    ///
+    /// A new `Tokenizer` instance able to encode, or an error if the files cannot be read or are
+    /// invalid.
+    pub fn from_vocab_merges(vocab_path: &Path, merges_path: &Path) -> Result<Self> {
+        let vocab: HashMap<String, u32> = serde_json::from_slice(&fs::read(vocab_path)?)
+            .map_err(|err| Error::InconsistentHeader(format!("invalid vocab.json: {}", err)))?;
+        let merges_text = fs::read_to_string(merges_path)?;
+
+        let byte_decoder: HashMap<char, u8> = bytes_to_unicode()
+            .iter()
+            .enumerate()
+            .map(|(byte, &c)| (c, byte as u8))
+            .collect();
+        let to_bytes = |text: &str| -> Result<Vec<u8>> {
+            text.chars()
+                .map(|c| {
+                    byte_decoder.get(&c).copied().ok_or_else(|| {
+                        Error::InconsistentHeader(format!("unexpected character {:?} in {:?}", c, text))
+                    })
+                })
+                .collect()
+        };
+
+        // The ids must cover the whole vocabulary
+        let vocab_size = vocab.len() as u32;
+        let mut token_bytes_table = vec![None; vocab.len()];
+        let eot_token = vocab.get("<|endoftext|>").copied();
+        for (token, &id) in &vocab {
+            let Some(entry) = token_bytes_table.get_mut(id as usize) else {
+                return Err(Error::InconsistentHeader(format!(
+                    "token id {} is out of the vocabulary of {} tokens",
+                    id, vocab_size
+                )));
+            };
+            *entry = Some(if Some(id) == eot_token {
+                token.as_bytes().to_vec()
+            } else {
+                to_bytes(token)?
+            });
+        }
+        let token_bytes_table: Vec<Vec<u8>> = token_bytes_table
+            .into_iter()
+            .enumerate()
+            .map(|(id, bytes)| {
+                bytes.ok_or_else(|| Error::InconsistentHeader(format!("token id {} is missing", id)))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut merges = Vec::new();
+        for line in merges_text.lines() {
+            if line.starts_with("#version") || line.is_empty() {
+                continue;
+            }
+            let pair = line.split_once(' ').and_then(|(left, right)| {
+                Some((*vocab.get(left)?, *vocab.get(right)?))
+            });
+            let Some(pair) = pair else {
+                return Err(Error::InconsistentHeader(format!("invalid merge {:?}", line)));
+            };
+            merges.push(pair);
+        }
+
+        Tokenizer::from_tokens(token_bytes_table, merges, eot_token)
+    }
+
+    /// Creates a tokenizer from its tokens and merges, e.g. the ones found by a `BpeTrainer`.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_table` - Bytes of each token. Every byte must have its own token.
+    /// * `merges` - The merges as pairs of tokens, from the first to the last. The bytes of each
+    ///   pair must be a token.
+    /// * `eot_token` - The `<|endoftext|>` token, if any. It is never produced by the merges.
+    ///
+    /// # Returns
+    ///
+    /// A new `Tokenizer` instance able to encode, or an error if the merges are inconsistent.
+    pub fn from_tokens(
+        token_table: Vec<Vec<u8>>,
+        merges: Vec<(u32, u32)>,
+        eot_token: Option<u32>,
+    ) -> Result<Self> {
+        let mut tokenizer = Tokenizer {
+            vocab_size: token_table.len() as u32,
+            token_table,
+            encoder: HashMap::new(),
+            merges: Vec::new(),
+            merge_ranks: HashMap::new(),
+            eot_token,
+            init_ok: false,
+        };
+        tokenizer.set_merges(merges)?;
+        tokenizer.init_ok = true;
+
+        Ok(tokenizer)
+    }
+
+    /// Writes the tokenizer to a version 3 file, with its merges, for `Tokenizer::new`.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path to the tokenizer file.
+    ///
+    /// # Returns
+    ///
+    /// An error if the file cannot be written, or if the tokenizer has no EOT token or a token
+    /// longer than 255 bytes, which the format cannot store.
+    pub fn save(&self, filename: &Path) -> Result<()> {
+        let Some(eot_token) = self.eot_token else {
+            return Err(Error::InconsistentHeader(
+                "the tokenizer file needs an EOT token".to_string(),
+            ));
+        };
+        if let Some(id) = self.token_table.iter().position(|token| token.len() > 255) {
+            return Err(Error::InconsistentHeader(format!(
+                "token {} is longer than 255 bytes",
+                id
+            )));
+        }
+
+        let mut header = [0u32; 256];
+        header[0] = 20240328;
+        header[1] = 3;
+        header[2] = self.vocab_size;
+        header[3] = eot_token;
+        header[4] = self.merges.len() as u32;
+
+        let mut file = BufWriter::new(File::create(filename)?);
+        for value in header {
+            file.write_all(&value.to_ne_bytes())?;
+        }
+        for token in &self.token_table {
+            file.write_all(&[token.len() as u8])?;
+            file.write_all(token)?;
+        }
+        for &(left, right) in &self.merges {
+            file.write_all(&left.to_ne_bytes())?;
+            file.write_all(&right.to_ne_bytes())?;
+        }
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes the tokenizer to the `vocab.json` and `merges.txt` files of a HuggingFace GPT-2
+    /// tokenizer, for `Tokenizer::from_vocab_merges` or other libraries.
+    ///
+    /// # Arguments
+    ///
+    /// * `vocab_path` - Path to `vocab.json`.
+    /// * `merges_path` - Path to `merges.txt`.
+    ///
+    /// # Returns
+    ///
+    /// An error if the files cannot be written.
+    pub fn save_vocab_merges(&self, vocab_path: &Path, merges_path: &Path) -> Result<()> {
+        let byte_encoder = bytes_to_unicode();
+        let to_text = |token: u32| -> String {
+            let bytes = &self.token_table[token as usize];
+            if Some(token) == self.eot_token {
+                String::from_utf8_lossy(bytes).into_owned()
+            } else {
+                bytes.iter().map(|&byte| byte_encoder[byte as usize]).collect()
+            }
+        };
+
+        let vocab: serde_json::Map<String, serde_json::Value> = (0..self.vocab_size)
+            .map(|token| (to_text(token), token.into()))
+            .collect();
+        let mut vocab_file = BufWriter::new(File::create(vocab_path)?);
+        serde_json::to_writer(&mut vocab_file, &vocab).map_err(io::Error::from)?;
+        vocab_file.flush()?;
+
+        let mut merges_file = BufWriter::new(File::create(merges_path)?);
+        writeln!(merges_file, "#version: 0.2")?;
+        for &(left, right) in &self.merges {
+            writeln!(merges_file, "{} {}", to_text(left), to_text(right))?;
+        }
+        merges_file.flush()?;
+
+        Ok(())
+    }
+
+    /// Sets up the encoder and the merge ranks, checking that every byte and every merge result
+    /// is a token.
+    ///
+    /// # Arguments
+    ///
+    /// * `merges` - The merges as pairs of tokens, from the first to the last.
+    fn set_merges(&mut self, merges: Vec<(u32, u32)>) -> Result<()> {
+        for (id, bytes) in self.token_table.iter().enumerate() {
+            if Some(id as u32) == self.eot_token {
+                continue;
+            }
+            if let Some(other) = self.encoder.insert(bytes.clone(), id as u32) {
+                return Err(Error::InconsistentHeader(format!(
+                    "tokens {} and {} have the same bytes",
+                    other, id
+                )));
+            }
+        }
+        if let Some(byte) = (0..=255u8).find(|&byte| !self.encoder.contains_key(&[byte][..])) {
+            return Err(Error::InconsistentHeader(format!(
+                "byte {:#04x} has no token",
+                byte
+            )));
+        }
+        for (rank, &(left, right)) in merges.iter().enumerate() {
+            let (Some(left), Some(right)) = (
+                self.token_table.get(left as usize),
+                self.token_table.get(right as usize),
+            ) else {
+                return Err(Error::InconsistentHeader(format!(
+                    "merge {} of tokens {} and {} is out of the vocabulary",
+                    rank, left, right
+                )));
+            };
+            let bytes = [left.as_slice(), right.as_slice()].concat();
+            if !self.encoder.contains_key(&bytes) {
+                return Err(Error::InconsistentHeader(format!(
+                    "merge {} produces {:?}, which is not a token",
+                    rank,
+                    String::from_utf8_lossy(&bytes)
+                )));
+            }
+            self.merge_ranks.entry(bytes).or_insert(rank as u32);
+        }
+        self.merges = merges;
+        Ok(())
+    }
+
+    /// Whether the tokenizer knows the BPE merges, which `encode` needs.
+    pub fn can_encode(&self) -> bool {
+        self.init_ok && !self.encoder.is_empty()
+    }
+
+    /// The `<|endoftext|>` token, if the vocabulary has one.
+    pub fn eot_token(&self) -> Option<u32> {
+        self.eot_token
+    }
+
+    /// Number of tokens of the vocabulary.
+    pub fn vocab_size(&self) -> u32 {
+        self.vocab_size
+    }
+
+    /// Encodes a text into tokens, like tiktoken with all the special tokens allowed:
+    /// `<|endoftext|>` becomes the EOT token.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to encode.
+    ///
+    /// # Returns
+    ///
+    /// The token ids of the text.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        let special = self
+            .eot_token
+            .and_then(|eot| Some((std::str::from_utf8(&self.token_table[eot as usize]).ok()?, eot)));
+        match special {
+            Some((eot_text, eot)) if !eot_text.is_empty() => {
+                for (i, part) in text.split(eot_text).enumerate() {
+                    if i > 0 {
+                        tokens.push(eot);
+                    }
+                    self.encode_ordinary_into(part, &mut tokens);
+                }
+            }
+            _ => self.encode_ordinary_into(text, &mut tokens),
+        }
+        tokens
+    }
+
+    /// Encodes a text into tokens, like tiktoken's `encode_ordinary`: special tokens are treated
+    /// as plain text.
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text to encode.
+    ///
+    /// # Returns
+    ///
+    /// The token ids of the text.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        self.encode_ordinary_into(text, &mut tokens);
+        tokens
+    }
+
+    /// Encodes a text without special tokens, appending the tokens to `tokens`.
+    fn encode_ordinary_into(&self, text: &str, tokens: &mut Vec<u32>) {
+        assert!(
+            self.can_encode(),
+            "the tokenizer has no merges, re-run `python train_gpt2.py` to write them"
+        );
+        for piece in pre_tokenize(text) {
+            self.encode_piece(piece.as_bytes(), tokens);
+        }
+    }
+
+    /// Applies the merges to a piece of the pre-tokenization, appending its tokens to `tokens`.
+    ///
+    /// # Note
+    ///
+    /// Like tiktoken, the merge producing the lowest ranked bytes goes first, the leftmost one on
+    /// ties, and a piece that is a token as a whole is that token.
+    fn encode_piece(&self, piece: &[u8], tokens: &mut Vec<u32>) {
+        if let Some(&token) = self.encoder.get(piece) {
+            tokens.push(token);
+            return;
+        }
+        // Start and end of every part, single bytes at first
+        let mut bounds: Vec<usize> = (0..=piece.len()).collect();
+        loop {
+            let mut best: Option<(u32, usize)> = None;
+            for i in 0..bounds.len() - 2 {
+                if let Some(&rank) = self.merge_ranks.get(&piece[bounds[i]..bounds[i + 2]]) {
+                    if best.is_none_or(|(best_rank, _)| rank < best_rank) {
+                        best = Some((rank, i));
+                    }
+                }
+            }
+            match best {
+                Some((_, i)) => {
+                    bounds.remove(i + 1);
+                }
+                None => break,
+            }
+        }
+        for part in bounds.windows(2) {
+            tokens.push(self.encoder[&piece[part[0]..part[1]]]);
+        }
+    }
+
+    /// Decodes a token ID into its bytes.
     ///
     /// # Arguments
     ///
@@ -88,40 +496,359 @@ impl Tokenizer {
     ///
     /// # Returns
     ///
-    /// The corresponding string for the token ID.
-    pub fn decode(&mut self, token_id: u32) -> &str {
+    /// The bytes of the token. A character can be split over several tokens, so they are not
+    /// always valid UTF-8: use a `StreamDecoder` to turn a sequence of tokens into text.
+    pub fn decode_bytes(&self, token_id: u32) -> &[u8] {
         if !self.init_ok {
-            ""
+            &[]
         } else if let Some(value) = self.token_table.get(token_id as usize) {
             value
         } else {
             println!("invalid token id {}!", token_id);
-            ""
+            &[]
         }
     }
 
+    /// Decodes a token ID into its corresponding string.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The token ID to decode.
+    ///
+    /// # Returns
+    ///
+    /// The corresponding string for the token ID, with U+FFFD in place of the bytes that are not
+    /// valid UTF-8 on their own.
+    pub fn decode(&self, token_id: u32) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.decode_bytes(token_id))
+    }
+
     /// Frees the resources allocated by the Tokenizer.
     pub fn free(&mut self) {
-        if self.init_ok != false {
+        if self.init_ok {
             self.token_table.clear();
+            self.encoder.clear();
+            self.merges.clear();
+            self.merge_ranks.clear();
             self.init_ok = false;
         }
     }
 }
 
-/// Safely prints a string if it contains valid ASCII characters.
+/// Turns the tokens of a sequence into text as they come, buffering the characters split over
+/// several tokens until their last byte arrives.
+pub struct StreamDecoder<'a> {
+    /// The tokenizer giving the bytes of the tokens.
+    tokenizer: &'a Tokenizer,
+
+    /// Replace the invalid bytes by U+FFFD instead of returning an error.
+    pub lossy: bool,
+
+    /// Bytes of an incomplete character at the end of the tokens so far.
+    pending: Vec<u8>,
+}
+
+impl<'a> StreamDecoder<'a> {
+    /// Creates a decoder at the start of a sequence.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokenizer` - The tokenizer giving the bytes of the tokens.
+    /// * `lossy` - Replace the invalid bytes by U+FFFD instead of returning an error.
+    ///
+    /// # Returns
+    ///
+    /// A new `StreamDecoder` with nothing buffered.
+    pub fn new(tokenizer: &'a Tokenizer, lossy: bool) -> Self {
+        StreamDecoder {
+            tokenizer,
+            lossy,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Decodes the next token of the sequence.
+    ///
+    /// # Arguments
+    ///
+    /// * `token_id` - The token ID to decode.
+    ///
+    /// # Returns
+    ///
+    /// The text completed by this token, empty if it ends in the middle of a character. Unless
+    /// the decoder is lossy, bytes that cannot be part of valid UTF-8 are an error, and the
+    /// token is dropped along with the incomplete character before it, if any. The text of the
+    /// characters that token completed before its invalid bytes is lost as well, the next token
+    /// is decoded from a clean state.
+    pub fn push(&mut self, token_id: u32) -> Result<String> {
+        self.pending.extend_from_slice(self.tokenizer.decode_bytes(token_id));
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    return Ok(text);
+                }
+                Err(err) => {
+                    let (valid, rest) = self.pending.split_at(err.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    let Some(invalid_len) = err.error_len() else {
+                        // The rest may still become a character
+                        self.pending = rest.to_vec();
+                        return Ok(text);
+                    };
+                    if !self.lossy {
+                        let invalid = rest[..invalid_len].to_vec();
+                        self.pending.clear();
+                        return Err(Error::InvalidUtf8(invalid));
+                    }
+                    self.pending = rest[invalid_len..].to_vec();
+                    text.push(char::REPLACEMENT_CHARACTER);
+                }
+            }
+        }
+    }
+
+    /// Ends the sequence.
+    ///
+    /// # Returns
+    ///
+    /// U+FFFD if the sequence ends in the middle of a character and the decoder is lossy (an
+    /// error if it is not), an empty string otherwise.
+    pub fn finish(&mut self) -> Result<String> {
+        if self.pending.is_empty() {
+            return Ok(String::new());
+        }
+        let incomplete = std::mem::take(&mut self.pending);
+        if self.lossy {
+            Ok(char::REPLACEMENT_CHARACTER.to_string())
+        } else {
+            Err(Error::InvalidUtf8(incomplete))
+        }
+    }
+}
+
+/// Safely prints a piece of text, without its control characters other than whitespace.
 ///
 /// # Arguments
 ///
 /// * `piece` - The string slice to print.
 pub fn safe_print(piece: &str, lock: &mut std::io::StdoutLock<'_>) {
-    if piece.is_empty() {
-        return;
-    } 
-    let bytes = piece.as_bytes();
-    if !(bytes[0].is_ascii_graphic() || bytes[0].is_ascii_whitespace()) {
-        return; // weird byte, don't print it
-    }
-    // Print the string if it is valid
-    lock.write(piece.as_bytes()).unwrap();
+    // Weird bytes, don't print them
+    let printable: String = piece
+        .chars()
+        .filter(|c| !c.is_control() || c.is_whitespace())
+        .collect();
+    lock.write_all(printable.as_bytes()).unwrap();
+}
+
+/// Splits a text into the pieces BPE merges within, with the GPT-2 pattern.
+///
+/// # Arguments
+///
+/// * `text` - The text to split, without special tokens.
+pub(crate) fn pre_tokenize(text: &str) -> impl Iterator<Item = &str> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| Regex::new(GPT2_PATTERN).unwrap());
+    pattern
+        .find_iter(text)
+        .map(|piece| piece.expect("the GPT-2 pattern failed to match").as_str())
+}
+
+/// The printable character standing for each byte in the `vocab.json` and `merges.txt` files of
+/// GPT-2: the printable Latin-1 characters stand for themselves, the other bytes are shifted
+/// past 255.
+fn bytes_to_unicode() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut shifted = 0;
+    for byte in 0..=255u8 {
+        let printable = matches!(byte, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        chars[byte as usize] = if printable {
+            byte as char
+        } else {
+            shifted += 1;
+            char::from_u32(255 + shifted).unwrap()
+        };
+    }
+    chars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Texts exercising the pre-tokenization: contractions, runs of whitespace, non-ASCII text,
+    /// emoji and the special token.
+    const TEXTS: &[&str] = &[
+        "it's",
+        "I'm sure they'll say we'd done it, don't you think? IT'S",
+        "a  world\n\n  indented\tline\r\n   ",
+        "café, naïve, Ελληνικά, 你好, こんにちは",
+        "😀👍🏽 emoji and 🇫🇷 flags",
+        "first<|endoftext|>second<|endoftext|>",
+        "",
+    ];
+
+    /// A small tokenizer whose ids can be worked out by hand: the 256 bytes, then the merges
+    /// `it`, `'s`, ` w`, ` wo`, `\n\n`, `é`, the first two bytes of the emoji and `or`, and EOT.
+    fn small_tokenizer() -> Tokenizer {
+        let mut token_table: Vec<Vec<u8>> = (0..=255u8).map(|byte| vec![byte]).collect();
+        let merges = vec![
+            (b'i' as u32, b't' as u32),
+            (b'\'' as u32, b's' as u32),
+            (b' ' as u32, b'w' as u32),
+            (258, b'o' as u32),
+            (b'\n' as u32, b'\n' as u32),
+            (0xC3, 0xA9),
+            (0xF0, 0x9F),
+            (b'o' as u32, b'r' as u32),
+        ];
+        for &(left, right) in &merges {
+            let bytes = [token_table[left as usize].as_slice(), &token_table[right as usize]].concat();
+            token_table.push(bytes);
+        }
+        token_table.push(b"<|endoftext|>".to_vec());
+        Tokenizer::from_tokens(token_table, merges, Some(264)).unwrap()
+    }
+
+    /// The GPT-2 tokenizer written by `python train_gpt2.py`, with its merges. The tests using it
+    /// are ignored unless run with `--include-ignored`, and then fail if it is missing.
+    fn gpt2_tokenizer() -> Tokenizer {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../gpt2_tokenizer.bin");
+        match Tokenizer::new(&path) {
+            Ok(tokenizer) if tokenizer.can_encode() => tokenizer,
+            _ => panic!("no version 3 {}, run `python train_gpt2.py`", path.display()),
+        }
+    }
+
+    /// Concatenates the bytes of the tokens.
+    fn decode_all(tokenizer: &Tokenizer, tokens: &[u32]) -> Vec<u8> {
+        tokens.iter().flat_map(|&token| tokenizer.decode_bytes(token).to_vec()).collect()
+    }
+
+    fn bytes(text: &str) -> Vec<u32> {
+        text.bytes().map(u32::from).collect()
+    }
+
+    #[test]
+    fn encode_splits_like_tiktoken() {
+        let tokenizer = small_tokenizer();
+        // Contractions are pieces of their own, lowercase only
+        assert_eq!(tokenizer.encode("it's"), vec![256, 257]);
+        assert_eq!(tokenizer.encode("IT'S"), bytes("IT'S"));
+        // The lowest ranked merge goes first: ` wo` before `or`
+        assert_eq!(tokenizer.encode(" world"), [vec![259], bytes("rld")].concat());
+        // The last space of a run goes with the next word, a run ending the text stays whole
+        assert_eq!(tokenizer.encode("a  world"), [bytes("a "), vec![259], bytes("rld")].concat());
+        assert_eq!(tokenizer.encode("a\n\n"), vec![b'a' as u32, 260]);
+        assert_eq!(tokenizer.encode("a\n\nb"), bytes("a\n\nb"));
+        // Merges apply to the bytes of the UTF-8 encoding
+        assert_eq!(tokenizer.encode("café"), [bytes("caf"), vec![261]].concat());
+        assert_eq!(tokenizer.encode("😀"), vec![262, 0x98, 0x80]);
+    }
+
+    #[test]
+    fn encode_special_token() {
+        let tokenizer = small_tokenizer();
+        assert_eq!(tokenizer.encode("<|endoftext|>"), vec![264]);
+        assert_eq!(tokenizer.encode("a<|endoftext|>b"), vec![b'a' as u32, 264, b'b' as u32]);
+        assert_eq!(tokenizer.encode_ordinary("<|endoftext|>"), bytes("<|endoftext|>"));
+    }
+
+    /// Pushes the tokens one by one, returning the text of each.
+    fn push_all(decoder: &mut StreamDecoder, tokens: &[u32]) -> Vec<String> {
+        tokens.iter().map(|&token| decoder.push(token).unwrap()).collect()
+    }
+
+    #[test]
+    fn stream_decoder_waits_for_complete_characters() {
+        let tokenizer = small_tokenizer();
+        for lossy in [false, true] {
+            let mut decoder = StreamDecoder::new(&tokenizer, lossy);
+            // The emoji is split into 262 (its first two bytes), 0x98 and 0x80
+            let tokens = [vec![b'a' as u32], tokenizer.encode("😀"), tokenizer.encode("café")].concat();
+            assert_eq!(push_all(&mut decoder, &tokens), ["a", "", "", "😀", "c", "a", "f", "é"]);
+            assert_eq!(decoder.finish().unwrap(), "");
+        }
+    }
+
+    #[test]
+    fn stream_decoder_finish_flushes_an_incomplete_character() {
+        let tokenizer = small_tokenizer();
+
+        let mut lossy = StreamDecoder::new(&tokenizer, true);
+        assert_eq!(push_all(&mut lossy, &[b'a' as u32, 262]), ["a", ""]);
+        assert_eq!(lossy.finish().unwrap(), "\u{FFFD}");
+        // The decoder starts a new sequence
+        assert_eq!(push_all(&mut lossy, &[b'b' as u32]), ["b"]);
+        assert_eq!(lossy.finish().unwrap(), "");
+
+        let mut strict = StreamDecoder::new(&tokenizer, false);
+        assert_eq!(push_all(&mut strict, &[262, 0x98]), ["", ""]);
+        assert!(matches!(strict.finish(), Err(Error::InvalidUtf8(bytes)) if bytes == [0xF0, 0x9F, 0x98]));
+        assert_eq!(push_all(&mut strict, &[b'b' as u32]), ["b"]);
+    }
+
+    #[test]
+    fn stream_decoder_invalid_bytes() {
+        let tokenizer = small_tokenizer();
+
+        // A stray continuation byte, and an emoji cut short by `é` (261)
+        let mut lossy = StreamDecoder::new(&tokenizer, true);
+        assert_eq!(push_all(&mut lossy, &[0x80, b'a' as u32, 262, 261]), ["\u{FFFD}", "a", "", "\u{FFFD}é"]);
+        assert_eq!(lossy.finish().unwrap(), "");
+
+        let mut strict = StreamDecoder::new(&tokenizer, false);
+        assert!(matches!(strict.push(0x80), Err(Error::InvalidUtf8(bytes)) if bytes == [0x80]));
+        assert_eq!(push_all(&mut strict, &[b'a' as u32, 262]), ["a", ""]);
+        // The `é` completed by the failing token is dropped along with the incomplete emoji
+        assert!(matches!(strict.push(261), Err(Error::InvalidUtf8(bytes)) if bytes == [0xF0, 0x9F]));
+        assert_eq!(push_all(&mut strict, &[b'b' as u32]), ["b"]);
+        assert_eq!(strict.finish().unwrap(), "");
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let tokenizer = small_tokenizer();
+        for text in TEXTS {
+            let tokens = tokenizer.encode(text);
+            assert_eq!(decode_all(&tokenizer, &tokens), text.as_bytes(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs gpt2_tokenizer.bin from `python train_gpt2.py`"]
+    fn encode_decode_round_trip_gpt2() {
+        let tokenizer = gpt2_tokenizer();
+        for text in TEXTS {
+            let tokens = tokenizer.encode(text);
+            assert_eq!(decode_all(&tokenizer, &tokens), text.as_bytes(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs gpt2_tokenizer.bin from `python train_gpt2.py`"]
+    fn encode_matches_tiktoken_gpt2() {
+        let tokenizer = gpt2_tokenizer();
+        // tiktoken.get_encoding("gpt2").encode(text, allowed_special="all")
+        let expected: &[(&str, &[u32])] = &[
+            ("Hello world", &[15496, 995]),
+            ("Hello, world!", &[15496, 11, 995, 0]),
+            ("it's", &[270, 338]),
+            ("I'm", &[40, 1101]),
+            ("don't", &[9099, 470]),
+            ("Hello  world", &[15496, 220, 995]),
+            ("Hello\n\nworld", &[15496, 198, 198, 6894]),
+            ("Hello world\n\n", &[15496, 995, 628]),
+            ("é", &[2634]),
+            ("你好", &[19526, 254, 25001, 121]),
+            ("😀", &[47249, 222]),
+            ("<|endoftext|>", &[50256]),
+            ("Hello<|endoftext|>world", &[15496, 50256, 6894]),
+        ];
+        for &(text, tokens) in expected {
+            assert_eq!(tokenizer.encode(text), tokens, "{:?}", text);
+        }
+    }
 }
@@ -0,0 +1,518 @@
+use core::slice;
+use std::alloc::{self, Layout};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::gpt2::GPT2;
+use crate::send_ptr::SendPtr;
+
+/// Everything besides the weights needed to resume a training run where it stopped.
+///
+/// The weights themselves go through `GPT2::save`, the state file holds the step counter,
+/// the sampling RNG, the position of the training `DataLoader` and the AdamW buffers. When the
+/// model trains low-rank adapters, those are the buffers of the adapters, saved with
+/// `LoraAdapters::save`. `save_checkpoint` writes both files so that an interrupted run always
+/// finds the weights and state of the same step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainState {
+    /// Number of optimization steps already done, i.e. the index of the next step.
+    pub step: usize,
+
+    /// State of the RNG used for sampling.
+    pub rng_state: u64,
+
+    /// Number of complete passes of the training `DataLoader` over its dataset.
+    pub train_epoch: u64,
+
+    /// Position of the current shard of the training `DataLoader` in the order of the epoch.
+    pub train_shard: u64,
+
+    /// Position of the next batch of the training `DataLoader` in the order of the shard.
+    pub train_sample: u64,
+}
+
+impl TrainState {
+    /// Writes the training state, including the AdamW buffers of `model`, to a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path of the state file to write.
+    /// * `model` - The model being trained.
+    pub fn save(&self, filename: &Path, model: &GPT2) -> Result<()> {
+        write_atomically(filename, |state_file| self.write(state_file, model))
+    }
+
+    /// Writes the header and the AdamW buffers of the training state.
+    ///
+    /// # Arguments
+    ///
+    /// * `state_file` - Where to write the state.
+    /// * `model` - The model being trained.
+    fn write(&self, state_file: &mut impl Write, model: &GPT2) -> Result<()> {
+        let mut state_header = [0i32; 256];
+        state_header[0] = 20240527; // magic
+        state_header[1] = 2; // version
+        write_u64(&mut state_header, 10, self.step as u64);
+        write_u64(&mut state_header, 12, model.num_parameters as u64);
+        write_u64(&mut state_header, 14, num_lora_parameters(model) as u64);
+        write_u64(&mut state_header, 20, self.rng_state);
+        write_u64(&mut state_header, 30, self.train_epoch);
+        write_u64(&mut state_header, 32, self.train_shard);
+        write_u64(&mut state_header, 34, self.train_sample);
+
+        for value in state_header {
+            state_file.write_all(&value.to_le_bytes())?;
+        }
+
+        // AdamW buffers are lazily allocated, before the first update they are all zeros
+        let (buffers, num_trained) = match &model.lora {
+            Some(lora) => ([lora.m_memory, lora.v_memory], lora.num_parameters),
+            None => ([model.m_memory, model.v_memory], model.num_parameters),
+        };
+        for buffer in buffers {
+            if buffer.ptr.is_null() {
+                let zeros = 0.0f32.to_le_bytes();
+                for _ in 0..num_trained {
+                    state_file.write_all(&zeros)?;
+                }
+            } else {
+                let values = unsafe { slice::from_raw_parts(buffer.ptr, num_trained) };
+                for value in values {
+                    state_file.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a checkpoint to resume from: the weights, or the adapters when the model has any,
+    /// and the training state.
+    ///
+    /// # Arguments
+    ///
+    /// * `weights_path` - Path of the weights file, or of the adapter file with `lora`.
+    /// * `state_path` - Path of the state file.
+    /// * `model` - The model being trained.
+    ///
+    /// # Note
+    ///
+    /// Both files are first written completely next to their targets, with a `.tmp` extension,
+    /// then renamed over them. The previous checkpoint is only replaced once the state file is on
+    /// disk, and `recover_checkpoint` finishes the renames after an interruption.
+    pub fn save_checkpoint(&self, weights_path: &Path, state_path: &Path, model: &GPT2) -> Result<()> {
+        let weights_temp_path = temp_path(weights_path);
+        let state_temp_path = temp_path(state_path);
+        write_synced(&weights_temp_path, |weights_file| match &model.lora {
+            Some(lora) => lora.write(weights_file),
+            None => model.write_checkpoint(weights_file, 3),
+        })?;
+        if let Err(err) = write_synced(&state_temp_path, |state_file| self.write(state_file, model)) {
+            let _ = fs::remove_file(&weights_temp_path);
+            return Err(err);
+        }
+        fs::rename(&weights_temp_path, weights_path)?;
+        fs::rename(&state_temp_path, state_path)?;
+        Ok(())
+    }
+
+    /// Reads a training state file and restores the AdamW buffers of `model` from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - Path of the state file to read.
+    /// * `model` - The model being trained, already loaded from the weights saved with the state,
+    ///   and with the adapters saved with it if any.
+    ///
+    /// # Returns
+    ///
+    /// The step, RNG and data position to resume from.
+    pub fn load(filename: &Path, model: &mut GPT2) -> Result<Self> {
+        let mut state_file = File::open(filename)?;
+        let file_size = state_file.metadata()?.len();
+
+        let mut state_header = [0i32; 256];
+        let header_bytes = mem::size_of_val(&state_header) as u64;
+        let num_trained = match &model.lora {
+            Some(lora) => lora.num_parameters,
+            None => model.num_parameters,
+        };
+        let expected_size = header_bytes + (2 * num_trained * mem::size_of::<f32>()) as u64;
+        if file_size < header_bytes {
+            return Err(Error::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
+        state_file.read_exact(unsafe {
+            slice::from_raw_parts_mut(state_header.as_mut_ptr() as *mut u8, header_bytes as usize)
+        })?;
+
+        // Check magic number and version
+        if state_header[0] != 20240527 {
+            return Err(Error::BadMagic {
+                expected: 20240527,
+                found: state_header[0],
+            });
+        }
+        if state_header[1] != 2 {
+            return Err(Error::BadVersion(state_header[1]));
+        }
+        let num_parameters = read_u64(&state_header, 12);
+        if num_parameters != model.num_parameters as u64 {
+            return Err(Error::InconsistentHeader(format!(
+                "state has {} parameters but the model has {}",
+                num_parameters, model.num_parameters
+            )));
+        }
+        let num_lora_parameters = read_u64(&state_header, 14);
+        if num_lora_parameters != self::num_lora_parameters(model) as u64 {
+            return Err(Error::InconsistentHeader(format!(
+                "state has {} adapter parameters but the model has {}",
+                num_lora_parameters,
+                self::num_lora_parameters(model)
+            )));
+        }
+        if file_size < expected_size {
+            return Err(Error::Truncated {
+                expected: expected_size,
+                actual: file_size,
+            });
+        }
+
+        // Restore the AdamW buffers, allocating them like `GPT2::update` would
+        let buffers = match &mut model.lora {
+            Some(lora) => [&mut lora.m_memory, &mut lora.v_memory],
+            None => [&mut model.m_memory, &mut model.v_memory],
+        };
+        unsafe {
+            for buffer in buffers {
+                if buffer.ptr.is_null() {
+                    let layout = Layout::array::<f32>(num_trained).unwrap();
+                    *buffer = SendPtr::new(alloc::alloc_zeroed(layout) as *mut f32);
+                }
+                state_file.read_exact(slice::from_raw_parts_mut(
+                    buffer.ptr as *mut u8,
+                    num_trained * mem::size_of::<f32>(),
+                ))?;
+            }
+        }
+
+        Ok(TrainState {
+            step: read_u64(&state_header, 10) as usize,
+            rng_state: read_u64(&state_header, 20),
+            train_epoch: read_u64(&state_header, 30),
+            train_shard: read_u64(&state_header, 32),
+            train_sample: read_u64(&state_header, 34),
+        })
+    }
+}
+
+/// Completes or discards a checkpoint that `TrainState::save_checkpoint` did not finish writing,
+/// so that the weights and state files are from the same step.
+///
+/// # Arguments
+///
+/// * `weights_path` - Path of the weights file, or of the adapter file with `lora`.
+/// * `state_path` - Path of the state file.
+///
+/// # Returns
+///
+/// An error if the files cannot be renamed or removed.
+///
+/// # Note
+///
+/// The temporary state file is written after the temporary weights file: when it is there and
+/// complete, the new checkpoint is complete and replaces the previous one, otherwise the previous
+/// one is kept and the temporary files are removed.
+pub fn recover_checkpoint(weights_path: &Path, state_path: &Path) -> Result<()> {
+    let weights_temp_path = temp_path(weights_path);
+    let state_temp_path = temp_path(state_path);
+    if is_complete_state(&state_temp_path) {
+        if weights_temp_path.exists() {
+            fs::rename(&weights_temp_path, weights_path)?;
+        }
+        fs::rename(&state_temp_path, state_path)?;
+    } else {
+        for temp_path in [weights_temp_path, state_temp_path] {
+            if temp_path.exists() {
+                fs::remove_file(&temp_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a state file holds the whole header and the AdamW buffers its header announces.
+fn is_complete_state(path: &Path) -> bool {
+    let Ok(mut state_file) = File::open(path) else {
+        return false;
+    };
+    let mut state_header = [0i32; 256];
+    let header_bytes = mem::size_of_val(&state_header);
+    let read = state_file.read_exact(unsafe {
+        slice::from_raw_parts_mut(state_header.as_mut_ptr() as *mut u8, header_bytes)
+    });
+    if read.is_err() || state_header[0] != 20240527 || state_header[1] != 2 {
+        return false;
+    }
+    // The adapters are trained instead of the weights when there are any
+    let num_trained = match read_u64(&state_header, 14) {
+        0 => read_u64(&state_header, 12),
+        num_lora_parameters => num_lora_parameters,
+    };
+    let expected_size = header_bytes as u64 + 2 * num_trained * mem::size_of::<f32>() as u64;
+    state_file.metadata().is_ok_and(|metadata| metadata.len() == expected_size)
+}
+
+/// Writes a file without ever leaving it partially written: the content goes to a `.tmp` file
+/// next to it, which is synced to disk and renamed over it.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file to write.
+/// * `write` - Writes the content of the file.
+///
+/// # Returns
+///
+/// An error if the file cannot be written, in which case `path` is left untouched.
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let temp_path = temp_path(path);
+    write_synced(&temp_path, write)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Writes a file like `write_atomically`, for writers that create the file from its path.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file to write.
+/// * `write` - Writes the file at the path it is given.
+///
+/// # Returns
+///
+/// An error if the file cannot be written, in which case `path` is left untouched.
+pub(crate) fn write_path_atomically(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let temp_path = temp_path(path);
+    let written = write(&temp_path).and_then(|()| Ok(File::open(&temp_path)?.sync_all()?));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Writes a file and syncs it to disk, removing it if it cannot be written completely.
+///
+/// # Arguments
+///
+/// * `path` - Path of the file to write.
+/// * `write` - Writes the content of the file.
+fn write_synced(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    let written = File::create(path).map_err(Error::from).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    });
+    if written.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+/// The temporary file a file is written to before replacing it, with `.tmp` appended to its name.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Number of parameters of the adapters of a model, 0 without adapters.
+fn num_lora_parameters(model: &GPT2) -> usize {
+    model.lora.as_ref().map_or(0, |lora| lora.num_parameters)
+}
+
+/// Stores a `u64` in two consecutive header slots, low half first.
+fn write_u64(header: &mut [i32; 256], index: usize, value: u64) {
+    header[index] = value as u32 as i32;
+    header[index + 1] = (value >> 32) as u32 as i32;
+}
+
+/// Reads a `u64` stored by `write_u64`.
+fn read_u64(header: &[i32; 256], index: usize) -> u64 {
+    (header[index] as u32 as u64) | ((header[index + 1] as u32 as u64) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataloader::{write_tokens, DataLoader};
+    use crate::gpt2::GPT2Config;
+    use crate::random::random_u32;
+
+    /// A new empty directory for the files of a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("llm-rs-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The tiny model with a smaller vocabulary, so that the tests run fast.
+    fn tiny_model() -> GPT2 {
+        GPT2::from_config(GPT2Config::tiny().with_vocab_size(1000), 42).unwrap()
+    }
+
+    /// Content of the state file at `step`.
+    fn state_bytes(step: usize, model: &GPT2) -> Vec<u8> {
+        let state = TrainState {
+            step,
+            rng_state: 1,
+            train_epoch: 0,
+            train_shard: 0,
+            train_sample: 0,
+        };
+        let mut bytes = Vec::new();
+        state.write(&mut bytes, model).unwrap();
+        bytes
+    }
+
+    /// AdamW buffers of a model, copied out of its memory.
+    fn adamw_buffers(model: &GPT2) -> Vec<f32> {
+        let n = model.num_parameters;
+        unsafe {
+            [slice::from_raw_parts(model.m_memory.ptr, n), slice::from_raw_parts(model.v_memory.ptr, n)].concat()
+        }
+    }
+
+    /// Parameters of a model, copied out of its memory.
+    fn params(model: &GPT2) -> Vec<f32> {
+        unsafe { slice::from_raw_parts(model.params_memory.ptr, model.num_parameters).to_vec() }
+    }
+
+    /// Runs training steps like the training loop does, returning the loss of the last one.
+    fn train(model: &mut GPT2, loader: &mut DataLoader, steps: std::ops::Range<usize>) -> f32 {
+        for step in steps {
+            loader.next_batch().unwrap();
+            model.forward(loader.inputs, loader.targets, loader.B, loader.T);
+            unsafe {
+                model.zero_grad();
+                model.backward();
+                model.update(1e-3, 0.9, 0.999, 1e-8, 0.0, step + 1).unwrap();
+            }
+        }
+        model.mean_loss
+    }
+
+    #[test]
+    fn recover_checkpoint_keeps_a_consistent_pair() {
+        let dir = test_dir("recover-test");
+        let weights = dir.join("weights.bin");
+        let state = dir.join("state.bin");
+        let read = |path: &Path| fs::read(path).unwrap();
+        let mut model = tiny_model();
+        let (state_1, state_2, state_3) = (state_bytes(1, &model), state_bytes(2, &model), state_bytes(3, &model));
+
+        // Interrupted before the state was written: the previous checkpoint stays
+        fs::write(&weights, "weights 1").unwrap();
+        fs::write(&state, &state_1).unwrap();
+        fs::write(temp_path(&weights), "weights 2").unwrap();
+        recover_checkpoint(&weights, &state).unwrap();
+        assert_eq!((read(&weights), read(&state)), (b"weights 1".to_vec(), state_1.clone()));
+        assert!(!temp_path(&weights).exists());
+
+        // Interrupted while writing the state: the previous checkpoint stays as well
+        fs::write(temp_path(&weights), "weights 2").unwrap();
+        fs::write(temp_path(&state), &state_2[..state_2.len() - 1]).unwrap();
+        recover_checkpoint(&weights, &state).unwrap();
+        assert_eq!((read(&weights), read(&state)), (b"weights 1".to_vec(), state_1));
+        assert!(!temp_path(&weights).exists() && !temp_path(&state).exists());
+
+        // Interrupted before the renames: the new checkpoint replaces it
+        fs::write(temp_path(&weights), "weights 2").unwrap();
+        fs::write(temp_path(&state), &state_2).unwrap();
+        recover_checkpoint(&weights, &state).unwrap();
+        assert_eq!((read(&weights), read(&state)), (b"weights 2".to_vec(), state_2));
+
+        // Interrupted between the renames: the state follows the weights
+        fs::write(&weights, "weights 3").unwrap();
+        fs::write(temp_path(&state), &state_3).unwrap();
+        recover_checkpoint(&weights, &state).unwrap();
+        assert_eq!((read(&weights), read(&state)), (b"weights 3".to_vec(), state_3));
+        assert!(!temp_path(&state).exists());
+
+        // A complete write leaves no temporary file behind
+        write_atomically(&weights, |file| Ok(file.write_all(b"weights 4")?)).unwrap();
+        assert_eq!(read(&weights), b"weights 4");
+        assert!(!temp_path(&weights).exists());
+
+        unsafe { model.free() };
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resumed_training_matches_an_uninterrupted_run() {
+        let dir = test_dir("resume-test");
+        let (B, T) = (2, 8);
+        let mut rng_state = 7;
+        let tokens: Vec<u32> = (0..10 * B * T).map(|_| random_u32(&mut rng_state) % 1000).collect();
+        let tokens_path = dir.join("tokens.bin");
+        write_tokens(&tokens_path, &tokens).unwrap();
+        let shuffled_loader = || {
+            let mut loader = DataLoader::new(&tokens_path, B, T).unwrap();
+            loader.shuffle(42);
+            loader
+        };
+        // More steps than batches, so that the run goes on with the next epoch
+        let (num_steps, checkpoint_step) = (12, 7);
+
+        let mut model = tiny_model();
+        let mut loader = shuffled_loader();
+        let loss = train(&mut model, &mut loader, 0..num_steps);
+        let (expected_params, expected_buffers) = (params(&model), adamw_buffers(&model));
+        unsafe { model.free() };
+        loader.free();
+
+        // Stop at the checkpoint and start over from the files
+        let weights_path = dir.join("weights.bin");
+        let state_path = dir.join("state.bin");
+        let mut model = tiny_model();
+        let mut loader = shuffled_loader();
+        train(&mut model, &mut loader, 0..checkpoint_step);
+        let state = TrainState {
+            step: checkpoint_step,
+            rng_state: 1,
+            train_epoch: loader.epoch as u64,
+            train_shard: loader.shard_index as u64,
+            train_sample: loader.sample_index as u64,
+        };
+        state.save_checkpoint(&weights_path, &state_path, &model).unwrap();
+        unsafe { model.free() };
+        loader.free();
+
+        let mut model = GPT2::new(&weights_path).unwrap();
+        let mut loader = shuffled_loader();
+        let state = TrainState::load(&state_path, &mut model).unwrap();
+        assert_eq!(state.step, checkpoint_step);
+        loader
+            .resume(state.train_epoch as usize, state.train_shard as usize, state.train_sample as usize)
+            .unwrap();
+        let resumed_loss = train(&mut model, &mut loader, state.step..num_steps);
+
+        assert_eq!(resumed_loss.to_bits(), loss.to_bits());
+        assert!(params(&model).iter().zip(&expected_params).all(|(a, e)| a.to_bits() == e.to_bits()));
+        assert!(adamw_buffers(&model).iter().zip(&expected_buffers).all(|(a, e)| a.to_bits() == e.to_bits()));
+        unsafe { model.free() };
+        loader.free();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -356,13 +356,33 @@ def write_state(model, x, y, logits, loss, filename):
         write_tensors(grads, model.config.n_layer, file, "float32")
     print(f"wrote {filename}")
 
+def bpe_merge_pair(ranks, token, rank):
+    # the two tokens merged into `token`: apply the merges ranked below it to its bytes
+    parts = [bytes([b]) for b in token]
+    while True:
+        best = None
+        for i in range(len(parts) - 1):
+            r = ranks.get(parts[i] + parts[i + 1])
+            if r is not None and r < rank and (best is None or r < best[0]):
+                best = (r, i)
+        if best is None:
+            break
+        i = best[1]
+        parts = parts[:i] + [parts[i] + parts[i + 1]] + parts[i + 2:]
+    assert len(parts) == 2, f"token {token} is not the result of a merge"
+    return ranks[parts[0]], ranks[parts[1]]
+
 def write_tokenizer(enc, filename):
     n = enc.max_token_value + 1
+    # the merges, from the first to the last, as pairs of token ids
+    ranks = enc._mergeable_ranks
+    merges = [bpe_merge_pair(ranks, token, rank) for token, rank in sorted(ranks.items(), key=lambda x: x[1]) if len(token) > 1]
     header = torch.zeros(256, dtype=torch.int32)
     header[0] = 20240328 # magic
-    header[1] = 2 # tokenizer version = 2 (1 -> 2: includes EOT token)
+    header[1] = 3 # tokenizer version = 3 (1 -> 2: includes EOT token, 2 -> 3: includes merges)
     header[2] = n # number of tokens
     header[3] = enc.eot_token # EOT token
+    header[4] = len(merges) # number of merges
     with open(filename, "wb") as file:
         file.write(header.numpy().tobytes())
         for i in range(n):
@@ -371,6 +391,8 @@ def write_tokenizer(enc, filename):
             assert length < 256, f"Token length exceeds 255: {length}"
             file.write(struct.pack("<B", length))  # Write the length as a 1-byte unsigned integer
             file.write(b)  # Write the actual bytes
+        for left, right in merges:
+            file.write(struct.pack("<II", left, right))
     print(f"wrote {filename}")
 
 def print0(*args, **kwargs):